@@ -0,0 +1,333 @@
+use crate::protocol::tracker::PeerAddress;
+use log::debug;
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How often the unchoked peers are chosen again (https://wiki.theory.org/BitTorrentSpecification#Choking_and_Optimistic_Unchoking).
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves on to another peer every third round, i.e. every 30 seconds.
+const OPTIMISTIC_UNCHOKE_ROUNDS: usize = 3;
+
+/// Peers we are interested in that did not send a block for this long are snubbing us,
+/// they only get the optimistic unchoke until they send something again.
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Number of peers unchoked for their rate, next to the optimistic unchoke.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// Choking state of a single peer connection, shared by the connection and the [`Choker`].
+/// The connection reports the transferred bytes and interest, and applies the choker's decision.
+pub struct ChokedPeer {
+    id: u64,
+    address: PeerAddress,
+    /// Whether the peer is interested in our pieces.
+    interested: AtomicBool,
+    unchoked: AtomicBool,
+    /// Bytes received from and sent to the peer since the last round.
+    downloaded: AtomicU64,
+    uploaded: AtomicU64,
+    /// While we are interested in the peer: when we became interested or received its last block.
+    waiting_since: Mutex<Option<Instant>>,
+    changed: Notify,
+}
+
+impl ChokedPeer {
+    fn new(id: u64, address: PeerAddress) -> Self {
+        Self {
+            id,
+            address,
+            interested: AtomicBool::new(false),
+            unchoked: AtomicBool::new(false),
+            downloaded: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            waiting_since: Mutex::new(None),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn address(&self) -> PeerAddress {
+        self.address
+    }
+
+    /// Whether the peer should be unchoked.
+    pub fn is_unchoked(&self) -> bool {
+        self.unchoked.load(Ordering::Relaxed)
+    }
+
+    /// Completes once the choker changed its decision about the peer.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    pub fn set_interested(&self, interested: bool) {
+        self.interested.store(interested, Ordering::Relaxed);
+    }
+
+    /// Records whether we are interested in the peer, only then can it snub us.
+    pub fn set_am_interested(&self, interested: bool, now: Instant) {
+        *self.waiting_since.lock().unwrap() = interested.then_some(now);
+    }
+
+    /// Records the bytes of a block received from the peer.
+    pub fn add_downloaded(&self, bytes: usize, now: Instant) {
+        self.downloaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(waiting_since) = self.waiting_since.lock().unwrap().as_mut() {
+            *waiting_since = now;
+        }
+    }
+
+    /// Records the bytes of a block sent to the peer.
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn is_snubbing(&self, now: Instant) -> bool {
+        self.waiting_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| now.saturating_duration_since(since) >= SNUB_TIMEOUT)
+    }
+
+    fn set_unchoked(&self, unchoked: bool) {
+        if self.unchoked.swap(unchoked, Ordering::Relaxed) != unchoked {
+            self.changed.notify_one();
+        }
+    }
+}
+
+/// State of a peer at the start of a choking round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Candidate {
+    id: u64,
+    interested: bool,
+    snubbing: bool,
+    /// Bytes transferred in the last round: downloaded from the peer, or uploaded to it when seeding.
+    rate: u64,
+}
+
+/// The interested peers with the highest rate fill the `slots`, snubbing peers are left out.
+fn regular_unchokes(candidates: &[Candidate], slots: usize) -> Vec<u64> {
+    let mut eligible: Vec<_> = candidates
+        .iter()
+        .filter(|candidate| candidate.interested && !candidate.snubbing)
+        .collect();
+    eligible.sort_by_key(|candidate| std::cmp::Reverse(candidate.rate));
+    eligible
+        .into_iter()
+        .take(slots)
+        .map(|candidate| candidate.id)
+        .collect()
+}
+
+/// Keeps the `current` optimistic unchoke until it's time to `rotate`, then picks a random interested
+/// peer which is not unchoked anyway, so new peers get the chance to show their rate.
+fn optimistic_unchoke(
+    candidates: &[Candidate],
+    regular: &[u64],
+    current: Option<u64>,
+    rotate: bool,
+) -> Option<u64> {
+    let eligible: Vec<u64> = candidates
+        .iter()
+        .filter(|candidate| candidate.interested && !regular.contains(&candidate.id))
+        .map(|candidate| candidate.id)
+        .collect();
+    if let Some(current) = current.filter(|current| eligible.contains(current)) {
+        if !rotate || eligible.len() == 1 {
+            return Some(current);
+        }
+    }
+    let others: Vec<u64> = eligible
+        .iter()
+        .copied()
+        .filter(|id| Some(*id) != current)
+        .collect();
+    others.choose(&mut rand::thread_rng()).copied()
+}
+
+/// Rounds of the choker, the optimistic unchoke rotates every [`OPTIMISTIC_UNCHOKE_ROUNDS`] of them.
+#[derive(Default)]
+struct Rounds {
+    count: usize,
+    optimistic: Option<u64>,
+}
+
+/// Tit-for-tat choking of the peers of a download (https://www.bittorrent.org/beps/bep_0003.html#choking-and-optimistic-unchoking):
+/// every [`CHOKE_INTERVAL`] the interested peers we download from the fastest (that we upload to the fastest
+/// when seeding) are unchoked, plus one optimistically unchoked peer.
+pub struct Choker {
+    slots: usize,
+    peers: Mutex<HashMap<u64, Arc<ChokedPeer>>>,
+    next_id: AtomicU64,
+    rounds: Mutex<Rounds>,
+}
+
+impl Choker {
+    /// Unchokes up to `slots` peers for their rate.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            peers: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            rounds: Mutex::new(Rounds::default()),
+        }
+    }
+
+    /// Adds the peer of a new connection, it's choked until the choker decides otherwise.
+    pub fn register(&self, address: PeerAddress) -> Arc<ChokedPeer> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let peer = Arc::new(ChokedPeer::new(id, address));
+        self.peers.lock().unwrap().insert(id, peer.clone());
+        peer
+    }
+
+    /// Removes the peer of a closed connection.
+    pub fn unregister(&self, peer: &ChokedPeer) {
+        self.peers.lock().unwrap().remove(&peer.id);
+    }
+
+    /// Unchokes a peer which became interested right away if a slot is free, instead of in the next round.
+    pub fn interested(&self, peer: &ChokedPeer) {
+        let optimistic = self.rounds.lock().unwrap().optimistic;
+        let unchoked = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|other| other.is_unchoked() && Some(other.id) != optimistic)
+            .count();
+        if unchoked < self.slots {
+            peer.set_unchoked(true);
+        }
+    }
+
+    /// Chooses the unchoked peers based on the bytes transferred since the previous round.
+    pub fn rechoke(&self, seeding: bool, now: Instant) {
+        let peers: Vec<_> = self.peers.lock().unwrap().values().cloned().collect();
+        let candidates: Vec<_> = peers
+            .iter()
+            .map(|peer| {
+                let downloaded = peer.downloaded.swap(0, Ordering::Relaxed);
+                let uploaded = peer.uploaded.swap(0, Ordering::Relaxed);
+                Candidate {
+                    id: peer.id,
+                    interested: peer.interested.load(Ordering::Relaxed),
+                    snubbing: peer.is_snubbing(now),
+                    rate: if seeding { uploaded } else { downloaded },
+                }
+            })
+            .collect();
+        let regular = regular_unchokes(&candidates, self.slots);
+        let mut rounds = self.rounds.lock().unwrap();
+        let rotate = rounds.count.is_multiple_of(OPTIMISTIC_UNCHOKE_ROUNDS);
+        rounds.optimistic = optimistic_unchoke(&candidates, &regular, rounds.optimistic, rotate);
+        rounds.count += 1;
+        for peer in &peers {
+            let unchoked = regular.contains(&peer.id) || rounds.optimistic == Some(peer.id);
+            if unchoked != peer.is_unchoked() {
+                debug!(
+                    "[{0}] {1}",
+                    peer.address,
+                    if unchoked { "unchoked" } else { "choked" }
+                );
+            }
+            peer.set_unchoked(unchoked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(id: u64, interested: bool, snubbing: bool, rate: u64) -> Candidate {
+        Candidate {
+            id,
+            interested,
+            snubbing,
+            rate,
+        }
+    }
+
+    #[test]
+    fn unchoke_fastest_interested_peers() {
+        let candidates = [
+            candidate(0, true, false, 10),
+            candidate(1, false, false, 100),
+            candidate(2, true, true, 90),
+            candidate(3, true, false, 50),
+            candidate(4, true, false, 20),
+        ];
+        assert_eq!(regular_unchokes(&candidates, 2), vec![3, 4]);
+        assert_eq!(regular_unchokes(&candidates, 5), vec![3, 4, 0]);
+    }
+
+    #[test]
+    fn rotate_optimistic_unchoke() {
+        let candidates = [
+            candidate(0, true, false, 10),
+            candidate(1, true, true, 0),
+            candidate(2, false, false, 0),
+        ];
+        // snubbing peers still get the optimistic unchoke
+        assert_eq!(optimistic_unchoke(&candidates, &[0], None, false), Some(1));
+        assert_eq!(
+            optimistic_unchoke(&candidates, &[0], Some(1), true),
+            Some(1)
+        );
+        assert_eq!(
+            optimistic_unchoke(&candidates, &[], Some(1), false),
+            Some(1)
+        );
+        assert_eq!(optimistic_unchoke(&candidates, &[], Some(1), true), Some(0));
+        assert_eq!(
+            optimistic_unchoke(&candidates, &[0, 1], Some(1), false),
+            None
+        );
+    }
+
+    #[test]
+    fn choke_snubbing_peers() {
+        let choker = Choker::new(1);
+        let start = Instant::now();
+        let address = PeerAddress::from("127.0.0.1:1".parse::<std::net::SocketAddr>().unwrap());
+        let fast = choker.register(address);
+        let slow = choker.register(address);
+        for peer in [&fast, &slow] {
+            peer.set_interested(true);
+            peer.set_am_interested(true, start);
+        }
+        choker.interested(&fast);
+        assert!(fast.is_unchoked());
+        choker.interested(&slow);
+        assert!(!slow.is_unchoked());
+
+        // uploads decide while seeding
+        fast.add_downloaded(100, start);
+        slow.add_uploaded(100);
+        choker.rechoke(true, start);
+        assert!(slow.is_unchoked());
+
+        // the snubbing peer loses its slot to the fast one, it can only be unchoked optimistically
+        fast.add_downloaded(100, start + SNUB_TIMEOUT);
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        assert!(fast.is_unchoked());
+        assert!(slow.is_unchoked());
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        assert!(fast.is_unchoked());
+        assert!(slow.is_unchoked());
+
+        choker.unregister(&fast);
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        assert!(slow.is_unchoked());
+        slow.set_interested(false);
+        choker.rechoke(false, start + SNUB_TIMEOUT);
+        assert!(!slow.is_unchoked());
+    }
+}
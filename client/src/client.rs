@@ -1,17 +1,37 @@
+use crate::choker::{Choker, DEFAULT_UPLOAD_SLOTS};
+use crate::download::{
+    download_from_peer, run_choker, DownloadState, DownloadedPiece, PeerExchange, PieceQueue,
+    PieceStore,
+};
+use crate::listener::{IncomingConnection, PeerListener};
+use crate::protocol::dht::DhtNode;
+use crate::protocol::lsd::LocalServiceDiscovery;
+use crate::protocol::magnet::MagnetLink;
 use crate::protocol::meta_info_file::{Sha1HashBytes, TorrentFile};
 use crate::protocol::peer_wire::PeerConnection;
-use crate::protocol::tracker::{AnnounceResponse, PeerAddress, TrackerUrl};
-use crate::protocol::{meta_info_file, peer_wire, tracker};
+use crate::protocol::tracker::{PeerAddress, ScrapeStats, TrackerTiers};
+use crate::protocol::{
+    dht, lsd, magnet, meta_info_file, metadata, peer_wire, pex, tracker, udp_tracker,
+};
+use crate::resume::resume_path;
+use crate::storage::{FileSystemBackend, StorageBackend};
+use crate::tracker_session::{Announcer, TrackerEvent, TrackerSession, TransferStats};
 use log::debug;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::pin;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::io;
-use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio::task::{JoinError, JoinSet};
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+use tokio::time::Instant;
 
 /// Hard coded peer ID prefix specific to this client.
 const PEER_ID_PREFIX: &str = "-RT0100-";
@@ -27,6 +47,8 @@ pub enum Error {
     HttpResponseParse(#[from] serde_bencode::Error),
     #[error("tracker error")]
     Tracker(#[from] tracker::Error),
+    #[error("UDP tracker error")]
+    UdpTracker(#[from] udp_tracker::Error),
     #[error("protocol error")]
     Protocol(#[from] peer_wire::Error),
     #[error("async error")]
@@ -35,24 +57,105 @@ pub enum Error {
     IO(#[from] io::Error),
     #[error("peer connection timeout: {0:?}")]
     PeerConnectionTimeout(Duration),
+    #[error("block request timeout: {0:?}")]
+    BlockRequestTimeout(Duration),
+    #[error("peer requested a block of {0} bytes")]
+    RequestTooLong(u32),
+    #[error("piece #{0} does not match its hash")]
+    InvalidPieceHash(usize),
+    #[error("piece length must be a power of two of at least 16 KiB: {0}")]
+    InvalidPieceLength(usize),
+    #[error("torrent has no data")]
+    EmptyTorrent,
+    #[error("file name is not valid UTF-8: {0:?}")]
+    InvalidFileName(std::path::PathBuf),
+    #[error("invalid resume data")]
+    InvalidResumeData,
+    #[error("all peers disconnected, {0} pieces are still missing")]
+    DownloadIncomplete(usize),
+    #[error("failed to parse magnet URI")]
+    MagnetParse(#[from] magnet::Error),
+    #[error("metadata exchange error")]
+    Metadata(#[from] metadata::Error),
+    #[error("no peer could provide the torrent metadata")]
+    MetadataUnavailable,
+    #[error("tracker did not respond within {0:?}")]
+    TrackerTimeout(Duration),
+    #[error("torrent has no trackers")]
+    NoTrackers,
+    #[error("DHT error")]
+    Dht(#[from] dht::Error),
+    #[error("local service discovery error")]
+    Lsd(#[from] lsd::Error),
+    #[error("peer exchange error")]
+    Pex(#[from] pex::Error),
 }
 
+/// Interval of the DHT lookups for new peers during a download.
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Maximum number of peers saved in the resume data of a download.
+const MAX_RESUME_PEERS: usize = 100;
+
+/// Interval of saving the resume data while pieces are downloaded, so little progress is lost
+/// if the client is killed.
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Configuration for [`BitTorrentClient`].
 pub struct BitTorrentClientConfig {
     timeouts: BitTorrentClientConfigTimeouts,
+    /// Number of peers unchoked for their rate in each download, one more is unchoked optimistically.
+    upload_slots: usize,
+    /// Where the data of torrents is stored.
+    storage: Arc<dyn StorageBackend>,
 }
 
 /// Low-level networking timeout configuration.
 pub struct BitTorrentClientConfigTimeouts {
     stream_connection_timeout: Duration,
     handshake_io_timeout: Duration,
+    block_request_timeout: Duration,
+    metadata_fetch_timeout: Duration,
+}
+
+impl Default for BitTorrentClientConfig {
+    fn default() -> Self {
+        Self {
+            timeouts: BitTorrentClientConfigTimeouts {
+                stream_connection_timeout: Duration::from_secs(30),
+                handshake_io_timeout: Duration::from_secs(30),
+                block_request_timeout: Duration::from_secs(30),
+                metadata_fetch_timeout: Duration::from_secs(60),
+            },
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            storage: Arc::new(FileSystemBackend),
+        }
+    }
+}
+
+impl BitTorrentClientConfig {
+    /// Sets how many peers of a download are unchoked for their rate (4 by default), a peer chosen at random
+    /// is unchoked in addition to them (https://www.bittorrent.org/beps/bep_0003.html#choking-and-optimistic-unchoking).
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        self.upload_slots = upload_slots;
+        self
+    }
+
+    /// Sets where the data of torrents is stored, in files by default (see [`FileSystemBackend`]).
+    pub fn with_storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = storage;
+        self
+    }
 }
 
 /// BitTorrent client implementation
 pub struct BitTorrentClient {
-    http_client: reqwest::Client,
+    announcer: Announcer,
     peer_id: String,
     config: Arc<BitTorrentClientConfig>,
+    dht: Option<Arc<DhtNode>>,
+    lsd: Option<Arc<LocalServiceDiscovery>>,
+    listener: Option<Arc<PeerListener>>,
 }
 
 impl Default for BitTorrentClient {
@@ -62,15 +165,14 @@ impl Default for BitTorrentClient {
             .take(20 - PEER_ID_PREFIX.len())
             .map(char::from)
             .collect();
+        let peer_id = format!("{0}{1}", PEER_ID_PREFIX, peer_id);
         Self {
-            http_client: reqwest::Client::new(),
-            peer_id: format!("{0}{1}", PEER_ID_PREFIX, peer_id),
-            config: Arc::new(BitTorrentClientConfig {
-                timeouts: BitTorrentClientConfigTimeouts {
-                    stream_connection_timeout: Duration::from_secs(30),
-                    handshake_io_timeout: Duration::from_secs(30),
-                },
-            }),
+            announcer: Announcer::new(peer_id.clone()),
+            peer_id,
+            config: Arc::new(BitTorrentClientConfig::default()),
+            dht: None,
+            lsd: None,
+            listener: None,
         }
     }
 }
@@ -85,10 +187,61 @@ impl BitTorrentClient {
         self
     }
 
+    /// Reports tracker warnings and failures to `events` (see [`TrackerEvent`]).
+    pub fn with_tracker_events(mut self, events: mpsc::UnboundedSender<TrackerEvent>) -> Self {
+        self.announcer = self.announcer.with_events(events);
+        self
+    }
+
+    /// Sets how long a single HTTP tracker may take to respond before the next one is tried
+    /// (30 seconds by default, https://www.bittorrent.org/beps/bep_0012.html).
+    /// UDP trackers are bounded by [`Self::with_udp_tracker_retransmissions`] instead.
+    pub fn with_tracker_timeout(mut self, timeout: Duration) -> Self {
+        self.announcer = self.announcer.with_timeout(timeout);
+        self
+    }
+
+    /// Sets how often a request to a UDP tracker is retransmitted before the next tracker is tried,
+    /// the n-th retransmission waits `15 * 2 ^ n` seconds (2 by default, https://www.bittorrent.org/beps/bep_0015.html#time-outs).
+    pub fn with_udp_tracker_retransmissions(mut self, retransmissions: u32) -> Self {
+        self.announcer = self.announcer.with_udp_tracker(
+            udp_tracker::UdpTrackerClient::new().with_max_retransmissions(retransmissions),
+        );
+        self
+    }
+
+    /// Looks up peers in the mainline DHT as well, next to the trackers (see [`DhtNode`]).
+    /// The node should be bootstrapped already, with a DHT torrents without (working) trackers
+    /// can be downloaded too.
+    pub fn with_dht(mut self, dht: Arc<DhtNode>) -> Self {
+        self.dht = Some(dht);
+        self
+    }
+
+    /// Announces downloads to the local network and connects to the peers found there as well
+    /// (see [`LocalServiceDiscovery`]).
+    pub fn with_lsd(mut self, lsd: Arc<LocalServiceDiscovery>) -> Self {
+        self.lsd = Some(lsd);
+        self
+    }
+
+    /// Accepts connections of peers during downloads, its port is announced to trackers and the DHT
+    /// (see [`PeerListener`]). The same listener can be shared by multiple clients.
+    pub fn with_listener(mut self, listener: Arc<PeerListener>) -> Self {
+        self.announcer = self.announcer.with_port(listener.port());
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Whether peers can be found without trackers, private torrents only use their trackers.
+    fn has_trackerless_sources(&self, private: bool) -> bool {
+        !private && (self.dht.is_some() || self.lsd.is_some())
+    }
+
     /// Initiates a new TCP connection to the given address and applies a connectivity timeout based on `config`.
     async fn tcp_stream_with_timeout(
         config: Arc<BitTorrentClientConfig>,
-        address: String,
+        address: SocketAddr,
     ) -> Result<TcpStream, Error> {
         tokio::time::timeout(
             config.timeouts.stream_connection_timeout,
@@ -99,77 +252,617 @@ impl BitTorrentClient {
         .map_err(Error::IO)
     }
 
-    /// Initializes a connection to a torrent peer and performs handshake.
+    /// Initializes a connection to a torrent peer and performs handshake, `extensions` are advertised
+    /// in the extended handshake.
     async fn init_peer_connection(
         config: Arc<BitTorrentClientConfig>,
         peer_id: String,
         peer_address: PeerAddress,
         info_hash: Sha1HashBytes,
+        extensions: &[&str],
     ) -> Result<PeerConnection<TcpStream>, Error> {
         let mut peer_connection = PeerConnection::new(
-            Self::tcp_stream_with_timeout(config.clone(), peer_address.to_string()).await?,
+            Self::tcp_stream_with_timeout(config.clone(), peer_address.socket_addr()).await?,
             config.timeouts.handshake_io_timeout,
         );
+        for extension in extensions {
+            peer_connection.register_extension(extension);
+        }
         peer_connection.handshake(peer_id, info_hash).await?;
 
         Ok(peer_connection)
     }
 
-    /// Starts the download of a torrent file.
-    /// Currently this method only establishes connection with all peers, performs handshake
-    /// and closes connection if there was no error.
-    pub async fn download(&self, torrent_file_path: &str) -> Result<(), Error> {
-        // read and parse torrent file
+    /// Downloads the content of a torrent file to `output_path`.
+    /// For single-file torrents `output_path` is the downloaded file, for multi-file torrents
+    /// it is the directory the files are downloaded into.
+    /// Pieces are requested from all peers parallel, each piece is verified against its hash
+    /// before it is written to disk. Returns once the whole file is downloaded and verified.
+    /// When the download stops, its progress is saved in a resume file next to `output_path`
+    /// (`output_path` with `.resume` appended), so a restarted download continues where it stopped.
+    /// Without (valid) resume data the existing data is verified before the download starts.
+    /// The torrent is announced to its trackers during the whole download (see [`TrackerSession`]).
+    pub async fn download(&self, torrent_file_path: &str, output_path: &str) -> Result<(), Error> {
         let torrent_file = meta_info_file::parse(torrent_file_path).await?;
+        self.download_torrent_file(torrent_file, output_path).await
+    }
+
+    /// Downloads the content of a parsed torrent file to `output_path` (see [`Self::download`]),
+    /// e.g. of a torrent loaded with [`TorrentFile::from_bytes`] or [`TorrentFile::from_reader`].
+    pub async fn download_torrent_file(
+        &self,
+        torrent_file: TorrentFile,
+        output_path: &str,
+    ) -> Result<(), Error> {
+        self.share_torrent_file(torrent_file, output_path, None::<std::future::Pending<()>>)
+            .await
+    }
+
+    /// Shares the content of a torrent file at `data_path` with other peers until `stop` completes
+    /// (e.g. `tokio::signal::ctrl_c()`), see [`Self::download`] for the meaning of `data_path`.
+    /// The existing data is verified first unless resume data is available, missing or corrupt pieces
+    /// are downloaded while the verified ones are uploaded already, so this also downloads a torrent and stays in the swarm afterwards.
+    /// Uploads are only requested by the peers we connect to, unless a [`PeerListener`] is set.
+    pub async fn seed(
+        &self,
+        torrent_file_path: &str,
+        data_path: &str,
+        stop: impl Future<Output = ()>,
+    ) -> Result<(), Error> {
+        let torrent_file = meta_info_file::parse(torrent_file_path).await?;
+        self.share_torrent_file(torrent_file, data_path, Some(stop))
+            .await
+    }
+
+    /// Downloads a torrent file, then seeds it until `seed_until` completes if it's set.
+    async fn share_torrent_file(
+        &self,
+        torrent_file: TorrentFile,
+        output_path: &str,
+        seed_until: Option<impl Future<Output = ()>>,
+    ) -> Result<(), Error> {
         debug!("Torrent file: {:?}", torrent_file.name);
+        let tiers = TrackerTiers::new(torrent_file.trackers());
+        if tiers.is_empty() && !self.has_trackerless_sources(torrent_file.private) {
+            return Err(Error::NoTrackers);
+        }
+        let (info_hash, private) = (torrent_file.info_hash, torrent_file.private);
+        let storage = self
+            .config
+            .storage
+            .open(Arc::new(torrent_file), Path::new(output_path))
+            .await?;
+        let store = PieceStore::new(storage).with_resume_path(resume_path(Path::new(output_path)));
+        // only verified data can be uploaded
+        let mut peers = store.restore(seed_until.is_some()).await?;
+
+        // get peers from the first responsive tracker and the DHT
+        let stats = Arc::new(TransferStats::new(store.left() as u64));
+        let (peers_tx, peers_rx) = mpsc::channel(4);
+        let (sources, source_peers) = self
+            .start_peer_sources(tiers, info_hash, private, stats, peers_tx)
+            .await?;
+        peers.extend(source_peers);
+        debug!("{0} peers found!", peers.len());
 
-        // get peers from tracker's announce URL
-        let response = self.announce(&torrent_file).await?;
-        let peers = response.peers().map_err(Error::Tracker)?;
+        let result = self
+            .download_torrent(store, peers, peers_rx, &sources, seed_until)
+            .await;
+        sources.stop().await;
+        result
+    }
+
+    /// Downloads the content of a torrent given by a magnet URI to `output_path` (see [`Self::download`]).
+    /// The torrent's info dictionary is fetched from peers first (https://www.bittorrent.org/beps/bep_0009.html).
+    pub async fn download_magnet(&self, magnet_uri: &str, output_path: &str) -> Result<(), Error> {
+        let magnet: MagnetLink = magnet_uri.parse()?;
+        debug!("Magnet link: {:?}", magnet);
 
+        // the size of the torrent is unknown until the metadata is fetched
+        let stats = Arc::new(TransferStats::new(0));
+        let (peers_tx, peers_rx) = mpsc::channel(4);
+        // each tracker of the magnet link is a tier on its own
+        let trackers = magnet
+            .trackers
+            .iter()
+            .map(|url| vec![url.clone()])
+            .collect();
+        let (sources, source_peers) = self
+            .start_peer_sources(
+                TrackerTiers::new(trackers),
+                magnet.info_hash,
+                false,
+                stats.clone(),
+                peers_tx,
+            )
+            .await?;
+        let mut peers = magnet.peers.clone();
+        peers.extend(source_peers);
+        let mut unique_peers = HashSet::new();
+        peers.retain(|peer| unique_peers.insert(*peer));
         debug!("{0} peers found!", peers.len());
 
-        // start to connect to all peers parallel, do handshake then disconnect
+        let result = self
+            .download_magnet_torrent(&magnet, peers, peers_rx, output_path, &sources)
+            .await;
+        sources.stop().await;
+        result
+    }
+
+    /// Asks a tracker for the number of seeders, leechers and completed downloads of the given torrents
+    /// without announcing (https://wiki.theory.org/BitTorrentSpecification#Tracker_.27scrape.27_Convention).
+    /// `tracker_url` is the announce URL of an HTTP or UDP tracker, torrents unknown to the tracker
+    /// are missing from the result.
+    pub async fn scrape(
+        &self,
+        tracker_url: &str,
+        info_hashes: &[Sha1HashBytes],
+    ) -> Result<HashMap<Sha1HashBytes, ScrapeStats>, Error> {
+        self.announcer.scrape(tracker_url, info_hashes).await
+    }
+
+    /// Starts announcing the torrent to its trackers and the local network, and looking it up
+    /// in the DHT periodically. Peers found later on are sent to `peers_tx`, returns the peers found initially.
+    /// Tracker errors are only fatal without other sources, `private` torrents use their trackers only.
+    async fn start_peer_sources(
+        &self,
+        tiers: TrackerTiers,
+        info_hash: Sha1HashBytes,
+        private: bool,
+        stats: Arc<TransferStats>,
+        peers_tx: mpsc::Sender<Vec<PeerAddress>>,
+    ) -> Result<(PeerSources, Vec<PeerAddress>), Error> {
+        let mut peers = vec![];
+        let mut sources = PeerSources {
+            stats: stats.clone(),
+            session: None,
+            dht: None,
+            lsd: None,
+        };
+        if let Some(dht) = self.dht.as_ref().filter(|_| !private) {
+            let port = self.listener.as_ref().map(|listener| listener.port());
+            match dht_lookup(dht, info_hash, port).await {
+                Ok(dht_peers) => peers.extend(dht_peers),
+                Err(error) => debug!("DHT lookup failed: {:?}", error),
+            }
+            let lookups = tokio::spawn(lookup_peers_periodically(
+                dht.clone(),
+                info_hash,
+                port,
+                peers_tx.clone(),
+            ));
+            sources.dht = Some((dht.clone(), lookups));
+        }
+        if let Some(lsd) = self.lsd.as_ref().filter(|_| !private) {
+            if let Err(error) = lsd.add_torrent(info_hash, peers_tx.clone()).await {
+                debug!("LSD announce failed: {:?}", error);
+            }
+            sources.lsd = Some((lsd.clone(), info_hash));
+        }
+        if !tiers.is_empty() {
+            match TrackerSession::start(self.announcer.clone(), tiers, info_hash, stats, peers_tx)
+                .await
+            {
+                Ok((session, tracker_peers)) => {
+                    peers.extend(tracker_peers);
+                    sources.session = Some(session);
+                }
+                Err(error) if self.has_trackerless_sources(private) => {
+                    debug!(
+                        "Trackers failed, relying on other peer sources: {:?}",
+                        error
+                    )
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Ok((sources, peers))
+    }
+
+    /// Fetches the metadata of a magnet link, then downloads its content (see [`Self::download_torrent`]).
+    async fn download_magnet_torrent(
+        &self,
+        magnet: &MagnetLink,
+        mut peers: Vec<PeerAddress>,
+        new_peers: mpsc::Receiver<Vec<PeerAddress>>,
+        output_path: &str,
+        sources: &PeerSources,
+    ) -> Result<(), Error> {
+        let info = self.fetch_metadata(magnet.info_hash, &peers).await?;
+        let announce = magnet.trackers.first().cloned().unwrap_or_default();
+        let torrent_file = TorrentFile::from_info_bytes(info.as_slice(), announce)?;
+        debug!("Torrent file: {:?}", torrent_file.name);
+        let storage = self
+            .config
+            .storage
+            .open(Arc::new(torrent_file), Path::new(output_path))
+            .await?;
+        let store = PieceStore::new(storage).with_resume_path(resume_path(Path::new(output_path)));
+        peers.extend(store.restore(false).await?);
+        sources.stats.set_left(store.left() as u64);
+
+        self.download_torrent(
+            store,
+            peers,
+            new_peers,
+            sources,
+            None::<std::future::Pending<()>>,
+        )
+        .await
+    }
+
+    /// Fetches the info dictionary of a torrent from all peers parallel, the first verified one is returned.
+    async fn fetch_metadata(
+        &self,
+        info_hash: Sha1HashBytes,
+        peers: &[PeerAddress],
+    ) -> Result<Vec<u8>, Error> {
         let mut handlers = JoinSet::new();
-        for peer in peers {
+        for peer in peers.iter().copied() {
             let peer_id = self.peer_id.clone();
             let config = self.config.clone();
             handlers.spawn(async move {
-                let peer_connection =
-                    Self::init_peer_connection(config, peer_id, peer, torrent_file.info_hash)
-                        .await?;
-                peer_connection.stream().lock().await.shutdown().await?;
-                Ok::<(), Error>(())
+                let peer_connection = Self::init_peer_connection(
+                    config.clone(),
+                    peer_id,
+                    peer,
+                    info_hash,
+                    &[metadata::UT_METADATA],
+                )
+                .await?;
+                let timeout = config.timeouts.metadata_fetch_timeout;
+                tokio::time::timeout(
+                    timeout,
+                    metadata::fetch_metadata(&peer_connection, info_hash),
+                )
+                .await
+                .map_err(|_| Error::PeerConnectionTimeout(timeout))?
+                .map_err(Error::Metadata)
             });
         }
 
-        // wait for all peer connections to finish
         while let Some(res) = handlers.join_next().await {
-            let result: Result<(), Error> = res.map_err(Error::Async)?;
-            if result.is_err() {
-                debug!("Peer connection error: {:?}", result.unwrap_err());
+            match res.map_err(Error::Async)? {
+                Ok(info) => return Ok(info),
+                Err(error) => debug!("Metadata exchange error: {:?}", error),
             }
         }
 
-        Ok(())
+        Err(Error::MetadataUnavailable)
     }
 
-    /// Get all details of the torrent from the tracker parsed from .torrent file.
-    async fn announce(&self, torrent: &TorrentFile) -> Result<AnnounceResponse, Error> {
-        let url = TrackerUrl::new(torrent.announce.clone(), self.peer_id.clone())
-            .with_compact(true)
-            .with_info_hash(torrent.info_hash)
-            .to_string();
-        debug!("Announce URL: {:?}", url);
-        let response = self
-            .http_client
-            .get(url)
-            .send()
-            .await
-            .map_err(Error::HttpClient)?;
-        let response_body = response.bytes().await.map_err(Error::HttpClient)?;
-        let resp: AnnounceResponse = serde_bencode::from_bytes(response_body.as_ref())?;
+    /// Downloads the pieces missing from `store` from `peers` (see [`Self::download`]).
+    /// Peers received from `new_peers` are connected as well, the download fails once all peers
+    /// disconnected and `new_peers` is closed. `sources` are told when the download completed.
+    /// With `seed_until` the pieces of `store` are shared with all peers until it completes,
+    /// regardless of the download's progress. The progress is saved as resume data regularly and in the end.
+    async fn download_torrent(
+        &self,
+        store: PieceStore,
+        peers: Vec<PeerAddress>,
+        mut new_peers: mpsc::Receiver<Vec<PeerAddress>>,
+        sources: &PeerSources,
+        seed_until: Option<impl Future<Output = ()>>,
+    ) -> Result<(), Error> {
+        let seeding = seed_until.is_some();
+        let torrent_file = store.torrent();
+        let have = store.bitfield();
+        let stats = sources.stats.clone();
+        let info_hash = torrent_file.info_hash;
+        let pieces_count = torrent_file.pieces_count();
+        // private torrents must not exchange peers (https://www.bittorrent.org/beps/bep_0027.html)
+        let (pex_tx, mut pex_rx) = mpsc::channel(16);
+        let pex = (!torrent_file.private).then(|| PeerExchange::new(pex_tx));
+        // metadata is only fetched from peers (see `fetch_metadata`), never served, so `ut_metadata`
+        // isn't advertised to peers of the download
+        let extensions: &[&str] = if pex.is_some() { &[pex::UT_PEX] } else { &[] };
+        let state = Arc::new(DownloadState {
+            queue: PieceQueue::new(torrent_file, &have),
+            store,
+            pex,
+            stats: stats.clone(),
+            choker: Choker::new(self.config.upload_slots),
+            block_request_timeout: self.config.timeouts.block_request_timeout,
+        });
+        tokio::spawn(run_choker(Arc::downgrade(&state)));
+
+        // peers connecting to us are accepted during the download
+        let (incoming_tx, mut incoming_rx) = mpsc::channel::<IncomingConnection>(16);
+        let _registration = self
+            .listener
+            .as_ref()
+            .map(|listener| listener.register(info_hash, incoming_tx));
+
+        // start to download pieces from all peers parallel
+        let (completed_tx, mut completed_rx) = mpsc::channel::<DownloadedPiece>(16);
+        let mut known_peers = HashSet::new();
+        let mut handlers = JoinSet::new();
+        let spawn_peers = |peers: Vec<PeerAddress>,
+                           handlers: &mut JoinSet<Result<(), Error>>,
+                           known_peers: &mut HashSet<PeerAddress>,
+                           completed_tx: &mpsc::Sender<DownloadedPiece>| {
+            for peer in peers {
+                if !known_peers.insert(peer) {
+                    continue;
+                }
+                let peer_id = self.peer_id.clone();
+                let config = self.config.clone();
+                let state = state.clone();
+                let completed_tx = completed_tx.clone();
+                handlers.spawn(async move {
+                    let peer_connection =
+                        Self::init_peer_connection(config, peer_id, peer, info_hash, extensions)
+                            .await?;
+                    download_from_peer(peer_connection, state, completed_tx, true).await
+                });
+            }
+        };
+        let spawn_incoming =
+            |mut connection: IncomingConnection,
+             handlers: &mut JoinSet<Result<(), Error>>,
+             completed_tx: &mpsc::Sender<DownloadedPiece>| {
+                let peer_id = self.peer_id.clone();
+                let state = state.clone();
+                let completed_tx = completed_tx.clone();
+                handlers.spawn(async move {
+                    for extension in extensions {
+                        connection.register_extension(extension);
+                    }
+                    connection.respond_handshake(peer_id, info_hash).await?;
+                    download_from_peer(connection, state, completed_tx, false).await
+                });
+            };
+        spawn_peers(peers, &mut handlers, &mut known_peers, &completed_tx);
+        // keep a sender while new peers can arrive, so the download only fails once no peers are left,
+        // seeds keep waiting for peers until they are stopped
+        let weak_completed_tx = completed_tx.downgrade();
+        let _seeding_tx = seeding.then(|| completed_tx.clone());
+        let mut completed_tx = Some(completed_tx);
+        let mut stop = pin!(async {
+            match seed_until {
+                Some(stop) => stop.await,
+                None => std::future::pending().await,
+            }
+        });
+
+        // write verified pieces to disk until all of them are done
+        let mut downloaded = have.count();
+        let mut saved = downloaded;
+        let mut save_resume =
+            tokio::time::interval_at(Instant::now() + RESUME_SAVE_INTERVAL, RESUME_SAVE_INTERVAL);
+        let mut result = Ok(());
+        while downloaded < pieces_count || seeding {
+            tokio::select! {
+                _ = &mut stop => break,
+                piece = completed_rx.recv() => {
+                    let Some(piece) = piece else {
+                        result = Err(Error::DownloadIncomplete(pieces_count - downloaded));
+                        break;
+                    };
+                    if let Err(error) = state.store.write_piece(&piece).await {
+                        result = Err(error);
+                        break;
+                    }
+                    stats.add_downloaded(piece.data.len() as u64);
+                    downloaded += 1;
+                    debug!("{0}/{1} pieces downloaded", downloaded, pieces_count);
+                    if downloaded == pieces_count {
+                        if let Err(error) = state.store.sync_all().await {
+                            result = Err(error);
+                            break;
+                        }
+                        sources.completed().await;
+                    }
+                }
+                _ = save_resume.tick(), if downloaded > saved => {
+                    save_progress(&state.store, &known_peers).await;
+                    saved = downloaded;
+                }
+                Some(res) = handlers.join_next() => {
+                    // peer failures are logged only, the download continues with the remaining peers
+                    match res {
+                        Ok(Err(error)) => debug!("Peer connection error: {:?}", error),
+                        Err(error) => debug!("Peer task error: {:?}", error),
+                        Ok(Ok(())) => {}
+                    }
+                }
+                peers = new_peers.recv(), if completed_tx.is_some() => match peers {
+                    Some(peers) => {
+                        if let Some(completed_tx) = &completed_tx {
+                            spawn_peers(peers, &mut handlers, &mut known_peers, completed_tx);
+                        }
+                    }
+                    None => completed_tx = None,
+                },
+                // peers learned via peer exchange or connecting to us don't keep a failed download alive
+                Some(peers) = pex_rx.recv() => {
+                    if let Some(completed_tx) = weak_completed_tx.upgrade() {
+                        spawn_peers(peers, &mut handlers, &mut known_peers, &completed_tx);
+                    }
+                }
+                Some(connection) = incoming_rx.recv() => {
+                    if let Some(completed_tx) = weak_completed_tx.upgrade() {
+                        spawn_incoming(connection, &mut handlers, &completed_tx);
+                    }
+                }
+            }
+        }
+        save_progress(&state.store, &known_peers).await;
+        drop(completed_rx);
+        handlers.abort_all();
+
+        result
+    }
+}
+
+/// Flushes the pieces written to `store` and saves its resume data, failures are logged only.
+async fn save_progress(store: &PieceStore, known_peers: &HashSet<PeerAddress>) {
+    let peers: Vec<_> = known_peers.iter().copied().take(MAX_RESUME_PEERS).collect();
+    let saved = async {
+        store.sync_all().await?;
+        store.save_resume(&peers).await
+    };
+    if let Err(error) = saved.await {
+        debug!("Failed to save resume data: {:?}", error);
+    }
+}
+
+/// Peer discovery of a single download: the tracker session, the periodic DHT lookups
+/// and the local service discovery of the torrent.
+struct PeerSources {
+    /// Transfer statistics of the torrent announced to the trackers.
+    stats: Arc<TransferStats>,
+    session: Option<TrackerSession>,
+    dht: Option<(Arc<DhtNode>, JoinHandle<()>)>,
+    lsd: Option<(Arc<LocalServiceDiscovery>, Sha1HashBytes)>,
+}
+
+impl PeerSources {
+    /// Tells the trackers that the download completed.
+    async fn completed(&self) {
+        if let Some(session) = &self.session {
+            session.completed().await;
+        }
+    }
+
+    /// Stops all peer sources.
+    async fn stop(self) {
+        if let Some((lsd, info_hash)) = self.lsd {
+            lsd.remove_torrent(&info_hash);
+        }
+        if let Some((dht, lookups)) = self.dht {
+            lookups.abort();
+            if let Err(error) = dht.save_routing_table().await {
+                debug!("Failed to save DHT routing table: {:?}", error);
+            }
+        }
+        if let Some(session) = self.session {
+            session.stop().await;
+        }
+    }
+}
+
+/// Looks up the peers of a torrent in the DHT. If peers can connect to us on `port`,
+/// we are announced as a peer of the torrent as well.
+async fn dht_lookup(
+    dht: &DhtNode,
+    info_hash: Sha1HashBytes,
+    port: Option<u16>,
+) -> Result<Vec<PeerAddress>, dht::Error> {
+    match port {
+        Some(port) => dht.announce(info_hash, port).await,
+        None => dht.get_peers(info_hash).await,
+    }
+}
+
+/// Looks up the peers of a torrent in the DHT every [`DHT_LOOKUP_INTERVAL`] until `peers_tx` is closed
+/// (see [`dht_lookup`]).
+async fn lookup_peers_periodically(
+    dht: Arc<DhtNode>,
+    info_hash: Sha1HashBytes,
+    port: Option<u16>,
+    peers_tx: mpsc::Sender<Vec<PeerAddress>>,
+) {
+    loop {
+        tokio::time::sleep(DHT_LOOKUP_INTERVAL).await;
+        match dht_lookup(&dht, info_hash, port).await {
+            Ok(peers) => {
+                debug!("DHT lookup found {0} peers", peers.len());
+                if peers_tx.send(peers).await.is_err() {
+                    return;
+                }
+            }
+            Err(error) => debug!("DHT lookup failed: {:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{start_http_tracker, TempDir};
+    use sha1::{Digest, Sha1};
+    use tokio::sync::oneshot;
+
+    /// Local stand-in HTTP tracker: seeds (`left=0`) get no peers, downloads get `seed`.
+    /// The first announce of a seed is reported to `seeding`.
+    async fn start_tracker(seed: SocketAddr, seeding: oneshot::Sender<()>) -> String {
+        let mut seeding = Some(seeding);
+        let (url, _) = start_http_tracker(move |params| {
+            let mut body = b"d8:intervali900e5:peers".to_vec();
+            if params.get("left").is_some_and(|left| left == "0") {
+                body.extend_from_slice(b"0:e");
+                if let Some(seeding) = seeding.take() {
+                    let _ = seeding.send(());
+                }
+            } else {
+                body.extend_from_slice(b"6:");
+                body.extend_from_slice(&PeerAddress::from(seed).to_compact());
+                body.push(b'e');
+            }
+            body
+        })
+        .await;
+        url
+    }
+
+    #[tokio::test]
+    async fn download_from_local_seed() {
+        let root = TempDir::new("loopback");
+        let data: Vec<u8> = (0..100_000u32).map(|byte| (byte % 251) as u8).collect();
+        let seed_path = root.join("seed.bin");
+        std::fs::write(&seed_path, &data).unwrap();
+
+        let listener = Arc::new(
+            PeerListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap(),
+        );
+        let (seeding_tx, seeding_rx) = oneshot::channel();
+        let tracker = start_tracker(listener.local_addr(), seeding_tx).await;
+        let torrent_path = root.join("seed.torrent");
+        let mut torrent = format!(
+            "d8:announce{0}:{1}4:infod6:lengthi{2}e4:name8:seed.bin12:piece lengthi16384e6:pieces{3}:",
+            tracker.len(),
+            tracker,
+            data.len(),
+            data.len().div_ceil(16384) * 20
+        )
+        .into_bytes();
+        for piece in data.chunks(16384) {
+            torrent.extend_from_slice(&Sha1::digest(piece));
+        }
+        torrent.extend_from_slice(b"ee");
+        std::fs::write(&torrent_path, torrent).unwrap();
+        let torrent_path = torrent_path.to_str().unwrap().to_string();
+
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let seeder = BitTorrentClient::new().with_listener(listener);
+        let seed = {
+            let torrent_path = torrent_path.clone();
+            let seed_path = seed_path.to_str().unwrap().to_string();
+            tokio::spawn(async move {
+                let stop = async {
+                    let _ = stop_rx.await;
+                };
+                seeder.seed(&torrent_path, &seed_path, stop).await
+            })
+        };
+        // the seed registers the torrent at its listener right after announcing it
+        seeding_rx.await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let download_path = root.join("download.bin");
+        tokio::time::timeout(
+            Duration::from_secs(30),
+            BitTorrentClient::new().download(&torrent_path, download_path.to_str().unwrap()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(std::fs::read(&download_path).unwrap(), data);
 
-        Ok(resp)
+        stop_tx.send(()).unwrap();
+        seed.await.unwrap().unwrap();
     }
 }
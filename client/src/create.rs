@@ -0,0 +1,390 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::{FileEntry, TorrentFile};
+use crate::verify::hash_pieces;
+use log::debug;
+use serde_bytes::ByteBuf;
+use serde_derive::Serialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::io;
+
+/// Smallest piece length, the size of a single block (https://www.bittorrent.org/beps/bep_0052.html#info-dictionary).
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Largest piece length chosen automatically.
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Number of pieces the automatically chosen piece length aims for: more pieces make the torrent file
+/// larger, fewer make a piece's failed verification more costly.
+const TARGET_PIECES_COUNT: usize = 1500;
+
+/// Bencoded form of a created torrent file, see [`TorrentBuilder`].
+#[derive(Serialize)]
+struct RawTorrent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announce: Option<String>,
+    #[serde(rename = "announce-list", skip_serializing_if = "Vec::is_empty")]
+    announce_list: Vec<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(rename = "created by", skip_serializing_if = "Option::is_none")]
+    created_by: Option<String>,
+    #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
+    creation_date: Option<u64>,
+    info: RawInfo,
+    /// Web seeds (https://www.bittorrent.org/beps/bep_0019.html).
+    #[serde(rename = "url-list", skip_serializing_if = "Vec::is_empty")]
+    url_list: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct RawInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<RawFile>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
+    name: String,
+    #[serde(rename = "piece length")]
+    piece_length: usize,
+    pieces: ByteBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+    /// Makes the info hash unique to a tracker, so the same content can be shared on multiple private trackers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RawFile {
+    length: usize,
+    path: Vec<String>,
+}
+
+/// Creates torrent files (https://www.bittorrent.org/beps/bep_0003.html#metainfo-files) of a file
+/// or a directory: a directory becomes a multi-file torrent of all files below it, in the order
+/// of their paths. The result can be parsed with [`crate::protocol::meta_info_file::parse`].
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<SystemTime>,
+    private: bool,
+    source: Option<String>,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    /// Creates a torrent of the file or directory at `path`, the torrent is named after it.
+    /// Symbolic links inside a directory are skipped.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            announce: None,
+            announce_list: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+            source: None,
+            web_seeds: vec![],
+        }
+    }
+
+    /// Sets the piece length, a power of two of at least 16 KiB. By default it's chosen by the size
+    /// of the content, so the torrent has about 1500 pieces.
+    pub fn with_piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Sets the tracker URL, the first tracker of `announce-list` is used if it's not set.
+    pub fn with_announce(mut self, announce: String) -> Self {
+        self.announce = Some(announce);
+        self
+    }
+
+    /// Sets the tiers of tracker URLs (https://www.bittorrent.org/beps/bep_0012.html).
+    pub fn with_announce_list(mut self, announce_list: Vec<Vec<String>>) -> Self {
+        self.announce_list = announce_list;
+        self
+    }
+
+    pub fn with_comment(mut self, comment: String) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    /// Sets the name and version of the program creating the torrent.
+    pub fn with_created_by(mut self, created_by: String) -> Self {
+        self.created_by = Some(created_by);
+        self
+    }
+
+    /// Sets the creation date, torrents have none by default, so the same content always results
+    /// in the same torrent file.
+    pub fn with_creation_date(mut self, creation_date: SystemTime) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    /// Marks the torrent private, its peers are only obtained from its trackers (https://www.bittorrent.org/beps/bep_0027.html).
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Sets the `source` of the info dictionary, which changes the info hash.
+    pub fn with_source(mut self, source: String) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Sets the HTTP URLs the content can be downloaded from as well (https://www.bittorrent.org/beps/bep_0019.html).
+    pub fn with_web_seeds(mut self, web_seeds: Vec<String>) -> Self {
+        self.web_seeds = web_seeds;
+        self
+    }
+
+    /// Reads and hashes the content, then returns the bencoded torrent file. Pieces are hashed on all
+    /// available cores parallel, this blocks the calling thread until all of them are hashed
+    /// (use `tokio::task::spawn_blocking` in async code).
+    pub fn build(&self) -> Result<Vec<u8>, Error> {
+        let name = file_name(&std::fs::canonicalize(&self.path)?)?;
+        let multi_file = std::fs::metadata(&self.path)?.is_dir();
+        let mut files = vec![];
+        if multi_file {
+            collect_files(&self.path, &mut vec![], &mut files)?;
+        } else {
+            files.push((vec![], std::fs::metadata(&self.path)?.len() as usize));
+        }
+        let length: usize = files.iter().map(|(_, length)| length).sum();
+        if length == 0 {
+            return Err(Error::EmptyTorrent);
+        }
+        let piece_length = match self.piece_length {
+            Some(piece_length)
+                if piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH =>
+            {
+                piece_length
+            }
+            Some(piece_length) => return Err(Error::InvalidPieceLength(piece_length)),
+            None => (length / TARGET_PIECES_COUNT)
+                .next_power_of_two()
+                .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH),
+        };
+
+        // the layout of the torrent to hash its pieces, the hashes are unknown yet
+        let mut offset = 0;
+        let entries = files
+            .iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path: path.iter().collect(),
+                    length: *length,
+                    offset,
+                };
+                offset += length;
+                entry
+            })
+            .collect();
+        let layout = TorrentFile::new(
+            String::new(),
+            [0; 20],
+            vec![[0; 20]; length.div_ceil(piece_length)],
+            piece_length as isize,
+            length as isize,
+            name.clone(),
+            entries,
+            multi_file,
+        );
+        let opened = layout
+            .file_paths(&self.path)
+            .iter()
+            .map(|path| File::open(path).map(Some))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut pieces = Vec::with_capacity(layout.pieces_count() * 20);
+        for hash in hash_pieces(&layout, &opened)? {
+            // files only become shorter if they are modified while they are hashed
+            let hash = hash.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            pieces.extend_from_slice(&hash);
+        }
+
+        let raw = RawTorrent {
+            announce: self
+                .announce
+                .clone()
+                .or_else(|| self.announce_list.iter().flatten().next().cloned()),
+            announce_list: self.announce_list.clone(),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            creation_date: self.creation_date.map(|date| {
+                date.duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs())
+            }),
+            info: RawInfo {
+                files: multi_file.then(|| {
+                    files
+                        .into_iter()
+                        .map(|(path, length)| RawFile { length, path })
+                        .collect()
+                }),
+                length: (!multi_file).then_some(length),
+                name,
+                piece_length,
+                pieces: ByteBuf::from(pieces),
+                private: self.private.then_some(1),
+                source: self.source.clone(),
+            },
+            url_list: self.web_seeds.clone(),
+        };
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+
+    /// Creates the torrent (see [`Self::build`]) and saves it at `torrent_path`.
+    pub fn write(&self, torrent_path: &Path) -> Result<(), Error> {
+        std::fs::write(torrent_path, self.build()?)?;
+        Ok(())
+    }
+}
+
+/// The last component of `path` as a string, torrents can only contain UTF-8 names.
+fn file_name(path: &Path) -> Result<String, Error> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .ok_or_else(|| Error::InvalidFileName(path.to_path_buf()))
+}
+
+/// Adds all files below `directory` to `files` in the order of their paths: the path components
+/// relative to the torrent's root (`prefix` is the path of `directory`) and the length of each file.
+/// Symbolic links are skipped, they could point into the directory again or add files twice.
+fn collect_files(
+    directory: &Path,
+    prefix: &mut Vec<String>,
+    files: &mut Vec<(Vec<String>, usize)>,
+) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            debug!("Skipping symbolic link {:?}", path);
+            continue;
+        }
+        prefix.push(file_name(&path)?);
+        if metadata.is_dir() {
+            collect_files(&path, prefix, files)?;
+        } else {
+            files.push((prefix.clone(), metadata.len() as usize));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::meta_info_file;
+    use crate::test_util::TempDir;
+    use crate::verify::verify;
+
+    /// Parses the torrent `data` written to `path`.
+    async fn parse(path: &Path, data: &[u8]) -> TorrentFile {
+        std::fs::write(path, data).unwrap();
+        meta_info_file::parse(path.to_str().unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn create_multi_file_torrent() {
+        let dir = TempDir::new("create-torrent");
+        let root = dir.join("content");
+        std::fs::create_dir_all(root.join("a")).unwrap();
+        let data: Vec<u8> = (0..40000).map(|byte| byte as u8).collect();
+        std::fs::write(root.join("b.bin"), &data).unwrap();
+        std::fs::write(root.join("a").join("x.txt"), b"hello").unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&root, root.join("a").join("loop")).unwrap();
+            std::os::unix::fs::symlink(root.join("b.bin"), root.join("c.bin")).unwrap();
+        }
+
+        let torrent_path = dir.join("content.torrent");
+        TorrentBuilder::new(&root)
+            .with_piece_length(16384)
+            .with_announce_list(vec![
+                vec!["http://tracker/announce".to_string()],
+                vec!["udp://backup:6969".to_string()],
+            ])
+            .with_comment("test".to_string())
+            .with_created_by("RT0100".to_string())
+            .with_creation_date(SystemTime::UNIX_EPOCH)
+            .with_private(true)
+            .with_source("tracker".to_string())
+            .with_web_seeds(vec!["http://seed/".to_string()])
+            .write(&torrent_path)
+            .unwrap();
+
+        let torrent = meta_info_file::parse(torrent_path.to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(torrent.name, "content");
+        assert!(torrent.multi_file && torrent.private);
+        assert_eq!(torrent.announce, "http://tracker/announce");
+        assert_eq!(torrent.trackers().len(), 2);
+        let paths: Vec<_> = torrent.files.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("a/x.txt"),
+                PathBuf::from("b.bin"),
+                PathBuf::from("empty")
+            ]
+        );
+        assert_eq!(torrent.pieces_count(), 3);
+        assert!(verify(&torrent, &root).unwrap().is_valid());
+
+        // the source is part of the info hash
+        let other = TorrentBuilder::new(&root)
+            .with_piece_length(16384)
+            .with_private(true)
+            .build()
+            .unwrap();
+        let other = parse(&dir.join("other.torrent"), &other).await;
+        assert_eq!(other.piece_hashes, torrent.piece_hashes);
+        assert_ne!(other.info_hash, torrent.info_hash);
+    }
+
+    #[tokio::test]
+    async fn choose_piece_length_of_single_file() {
+        let dir = TempDir::new("create-single-file");
+        let path = dir.join("single-file.iso");
+        std::fs::write(&path, vec![7; 100_000]).unwrap();
+        let data = TorrentBuilder::new(&path).build().unwrap();
+        let torrent = parse(&dir.join("single-file.torrent"), &data).await;
+        assert!(!torrent.multi_file);
+        assert_eq!(torrent.piece_length, 16384);
+        assert_eq!(torrent.length, 100_000);
+        assert!(verify(&torrent, &path).unwrap().is_valid());
+
+        assert!(matches!(
+            TorrentBuilder::new(&path).with_piece_length(20000).build(),
+            Err(Error::InvalidPieceLength(20000))
+        ));
+        let empty = dir.join("empty");
+        std::fs::create_dir_all(&empty).unwrap();
+        assert!(matches!(
+            TorrentBuilder::new(&empty).build(),
+            Err(Error::EmptyTorrent)
+        ));
+    }
+}
@@ -0,0 +1,1038 @@
+use crate::choker::{ChokedPeer, Choker, CHOKE_INTERVAL};
+use crate::client::Error;
+use crate::picker::PiecePicker;
+use crate::protocol::meta_info_file::{Sha1HashBytes, TorrentFile};
+use crate::protocol::peer_wire::{Bitfield, PeerConnection, PeerMessage};
+use crate::protocol::pex::{PexMessage, PexState, FLAG_REACHABLE, UT_PEX};
+use crate::protocol::tracker::PeerAddress;
+use crate::protocol::transport::Transport;
+use crate::resume::{self, ResumeData};
+use crate::storage::Storage;
+use crate::tracker_session::TransferStats;
+use bytes::{Bytes, BytesMut};
+use log::debug;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+
+/// Size of a single block requested from peers, 16 KiB is the de facto standard (https://wiki.theory.org/BitTorrentSpecification#request:_.3Clen.3D0013.3E.3Cid.3D6.3E.3Cindex.3E.3Cbegin.3E.3Clength.3E).
+pub const BLOCK_SIZE: usize = 16384;
+
+/// Longest block request served, peers asking for more are disconnected. Requests are 16 KiB
+/// in practice, 128 KiB is the limit most clients accept.
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// Maximum number of block requests sent to a peer without receiving the blocks.
+const MAX_PIPELINED_REQUESTS: usize = 5;
+
+/// How often an idle peer (choked or without useful pieces) checks again for work.
+const IDLE_PEER_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A piece that still needs to be downloaded.
+#[derive(Debug, Clone)]
+pub struct PieceWork {
+    pub index: usize,
+    pub length: usize,
+    pub hash: Sha1HashBytes,
+}
+
+/// A downloaded piece which is already verified against its hash.
+#[derive(Debug)]
+pub struct DownloadedPiece {
+    pub index: usize,
+    pub data: Bytes,
+}
+
+/// A block received in endgame mode, every connection downloading the same piece takes it over.
+#[derive(Debug, Clone)]
+struct SharedBlock {
+    index: usize,
+    begin: usize,
+    data: Bytes,
+}
+
+/// Pieces waiting to be downloaded, shared between all peer connections. The [`PiecePicker`] decides
+/// which piece a peer downloads next, based on the pieces of all connected peers.
+/// In endgame mode pieces are downloaded from multiple peers, received blocks are shared between them,
+/// so the requests of the same block can be cancelled at the other peers.
+pub struct PieceQueue {
+    pieces: Vec<PieceWork>,
+    state: Mutex<QueueState>,
+    endgame_blocks: broadcast::Sender<SharedBlock>,
+}
+
+struct QueueState {
+    picker: PiecePicker,
+    /// Pieces put back with some of their blocks downloaded, so another peer can finish them.
+    partial: HashMap<usize, PieceProgress>,
+}
+
+impl PieceQueue {
+    /// Constructs a queue containing the pieces of `torrent` missing from `have`.
+    pub fn new(torrent: &TorrentFile, have: &Bitfield) -> Self {
+        let pieces = torrent
+            .piece_hashes
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| PieceWork {
+                index,
+                length: torrent.piece_size(index),
+                hash: *hash,
+            })
+            .collect();
+        Self {
+            pieces,
+            state: Mutex::new(QueueState {
+                picker: PiecePicker::new(have),
+                partial: HashMap::new(),
+            }),
+            endgame_blocks: broadcast::channel(64).0,
+        }
+    }
+
+    /// Counts the pieces of a peer for rarest first.
+    fn add_peer(&self, peer_pieces: &Bitfield) {
+        self.state.lock().unwrap().picker.add_peer(peer_pieces);
+    }
+
+    fn remove_peer(&self, peer_pieces: &Bitfield) {
+        self.state.lock().unwrap().picker.remove_peer(peer_pieces);
+    }
+
+    fn add_piece(&self, index: usize) {
+        self.state.lock().unwrap().picker.add_piece(index);
+    }
+
+    /// Takes the piece the peer should download next, partially downloaded pieces continue where they stopped.
+    /// Once all pieces are taken, pieces downloaded by other peers are taken again (endgame mode).
+    fn take(&self, peer_pieces: &Bitfield) -> Option<PieceProgress> {
+        let mut state = self.state.lock().unwrap();
+        let mut rng = rand::thread_rng();
+        let index = match state.picker.pick(peer_pieces, &mut rng) {
+            Some(index) => index,
+            None => {
+                let index = state.picker.pick_endgame(peer_pieces, &mut rng)?;
+                debug!("endgame: piece #{0} is downloaded from another peer", index);
+                return Some(PieceProgress::new(self.pieces[index].clone()));
+            }
+        };
+        let progress = state
+            .partial
+            .remove(&index)
+            .unwrap_or_else(|| PieceProgress::new(self.pieces[index].clone()));
+        Some(progress)
+    }
+
+    /// Puts back a piece that could not be downloaded, so other peers can pick it up.
+    /// Its downloaded blocks are kept, the pending requests are dropped.
+    fn put_back(&self, mut progress: PieceProgress) {
+        let mut state = self.state.lock().unwrap();
+        let index = progress.work.index;
+        let partial = progress.downloaded > 0;
+        if state.picker.put_back(index, partial) && partial {
+            progress.reset_requests();
+            state.partial.insert(index, progress);
+        }
+    }
+
+    /// Marks a piece as downloaded and verified, returns `false` if another peer completed it first.
+    fn done(&self, index: usize) -> bool {
+        self.state.lock().unwrap().picker.done(index)
+    }
+
+    /// Hands over a received block to the other peers downloading the piece in endgame mode.
+    fn share_block(&self, index: usize, begin: usize, data: Bytes) {
+        if self.state.lock().unwrap().picker.is_endgame() {
+            let _ = self.endgame_blocks.send(SharedBlock { index, begin, data });
+        }
+    }
+
+    fn subscribe_blocks(&self) -> broadcast::Receiver<SharedBlock> {
+        self.endgame_blocks.subscribe()
+    }
+}
+
+/// Peers of a download shared for peer exchange (https://www.bittorrent.org/beps/bep_0011.html):
+/// every connection advertises the connected peers and hands over the peers it learns about.
+pub struct PeerExchange {
+    connected: Mutex<HashSet<PeerAddress>>,
+    discovered: mpsc::Sender<Vec<PeerAddress>>,
+}
+
+impl PeerExchange {
+    /// Peers learned from other peers are sent to `discovered`.
+    pub fn new(discovered: mpsc::Sender<Vec<PeerAddress>>) -> Self {
+        Self {
+            connected: Mutex::new(HashSet::new()),
+            discovered,
+        }
+    }
+
+    fn connected(&self) -> HashSet<PeerAddress> {
+        self.connected.lock().unwrap().clone()
+    }
+
+    /// Hands over discovered peers, they are dropped if the previous ones are not taken yet.
+    fn discover(&self, peers: Vec<PeerAddress>) {
+        if !peers.is_empty() && self.discovered.try_send(peers).is_err() {
+            debug!("Dropped peers received via peer exchange");
+        }
+    }
+}
+
+/// Verified pieces of a download: they are written to the torrent's storage, and read back
+/// to serve the block requests of other peers.
+pub struct PieceStore {
+    storage: Arc<dyn Storage>,
+    have: Mutex<Bitfield>,
+    /// Announces the index of every new piece to the peer connections.
+    new_pieces: broadcast::Sender<usize>,
+    /// Where the resume data of the download is kept, if anywhere.
+    resume_path: Option<PathBuf>,
+}
+
+impl PieceStore {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let have = Bitfield::new(storage.torrent().pieces_count());
+        Self {
+            storage,
+            have: Mutex::new(have),
+            new_pieces: broadcast::channel(64).0,
+            resume_path: None,
+        }
+    }
+
+    /// Keeps the resume data of the download in `resume_path` (see [`Self::restore`]).
+    pub fn with_resume_path(mut self, resume_path: PathBuf) -> Self {
+        self.resume_path = Some(resume_path);
+        self
+    }
+
+    pub fn torrent(&self) -> &TorrentFile {
+        self.storage.torrent()
+    }
+
+    /// Pieces we have.
+    pub fn bitfield(&self) -> Bitfield {
+        self.have.lock().unwrap().clone()
+    }
+
+    /// Whether we have all pieces, i.e. we are a seed.
+    pub fn is_complete(&self) -> bool {
+        self.have.lock().unwrap().count() == self.torrent().pieces_count()
+    }
+
+    /// Number of bytes of the pieces we don't have.
+    pub fn left(&self) -> usize {
+        let have = self.have.lock().unwrap();
+        let torrent = self.torrent();
+        (0..torrent.pieces_count())
+            .filter(|index| !have.has(*index))
+            .map(|index| torrent.piece_size(index))
+            .sum()
+    }
+
+    /// Verifies the data already present in the storage against the piece hashes, pieces matching
+    /// their hash are available to other peers from now on. Returns the number of valid pieces.
+    pub async fn check_pieces(&self) -> Result<usize, Error> {
+        let mut valid = 0;
+        for index in 0..self.torrent().pieces_count() {
+            if self.storage.verify_piece(index).await? {
+                self.have.lock().unwrap().set(index);
+                valid += 1;
+            }
+        }
+        Ok(valid)
+    }
+
+    /// Restores the pieces we have from the resume data saved by [`Self::save_resume`]. If it is missing
+    /// or stale, i.e. the files changed since it was saved, the existing data is verified instead
+    /// (see [`Self::check_pieces`]). Storages without resume data (see [`Storage::file_stats`]) are only
+    /// verified with `verify`. Returns the peers of the resume data.
+    pub async fn restore(&self, verify: bool) -> Result<Vec<PeerAddress>, Error> {
+        let files = match &self.resume_path {
+            Some(_) => self.storage.file_stats().await?,
+            None => None,
+        };
+        if let (Some(path), Some(files)) = (&self.resume_path, files) {
+            match resume::load(path).await {
+                Ok(Some(resume)) => match resume.pieces(self.torrent(), &files) {
+                    Some(pieces) => {
+                        debug!("{0} pieces restored from {1:?}", pieces.count(), path);
+                        *self.have.lock().unwrap() = pieces;
+                        return Ok(resume.peers);
+                    }
+                    None => debug!("Resume data {:?} is stale", path),
+                },
+                Ok(None) => {}
+                Err(error) => debug!("Invalid resume data {:?}: {:?}", path, error),
+            }
+        } else if !verify {
+            return Ok(vec![]);
+        }
+        let valid = self.check_pieces().await?;
+        debug!("{0} pieces of existing data are valid", valid);
+        Ok(vec![])
+    }
+
+    /// Saves the pieces we have, the state of the files and `peers` as resume data, the data
+    /// must be flushed already (see [`Self::sync_all`]).
+    pub async fn save_resume(&self, peers: &[PeerAddress]) -> Result<(), Error> {
+        let Some(path) = &self.resume_path else {
+            return Ok(());
+        };
+        let Some(files) = self.storage.file_stats().await? else {
+            return Ok(());
+        };
+        let resume = ResumeData {
+            info_hash: self.torrent().info_hash,
+            pieces: self.bitfield().as_bytes().to_vec(),
+            files,
+            peers: peers.to_vec(),
+        };
+        resume::save(path, &resume).await
+    }
+
+    /// Writes a verified piece to the storage, then tells all peers that we have it.
+    pub async fn write_piece(&self, piece: &DownloadedPiece) -> Result<(), Error> {
+        self.storage
+            .write_block(piece.index, 0, piece.data.clone())
+            .await?;
+        self.have.lock().unwrap().set(piece.index);
+        let _ = self.new_pieces.send(piece.index);
+        Ok(())
+    }
+
+    /// Reads a block of a piece we have, returns `None` if we don't have the piece or the block
+    /// is out of its bounds.
+    pub async fn read_block(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> Result<Option<Bytes>, Error> {
+        let torrent = self.torrent();
+        let in_bounds = index < torrent.pieces_count()
+            && begin
+                .checked_add(length)
+                .is_some_and(|end| end <= torrent.piece_size(index));
+        if length == 0 || !in_bounds || !self.have.lock().unwrap().has(index) {
+            return Ok(None);
+        }
+        Ok(Some(self.storage.read_block(index, begin, length).await?))
+    }
+
+    /// Flushes all written data to the storage.
+    pub async fn sync_all(&self) -> Result<(), Error> {
+        self.storage.flush().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<usize> {
+        self.new_pieces.subscribe()
+    }
+}
+
+/// State of a download shared by all of its peer connections.
+pub struct DownloadState {
+    pub queue: PieceQueue,
+    pub store: PieceStore,
+    /// Peer exchange, unless the torrent is private.
+    pub pex: Option<PeerExchange>,
+    /// Uploaded bytes are counted here for tracker announces.
+    pub stats: Arc<TransferStats>,
+    /// Decides which peers we upload to, see [`run_choker`].
+    pub choker: Choker,
+    pub block_request_timeout: Duration,
+}
+
+/// Runs the rounds of the download's choker every [`CHOKE_INTERVAL`] until the download is dropped.
+pub async fn run_choker(state: Weak<DownloadState>) {
+    let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state
+            .choker
+            .rechoke(state.store.is_complete(), std::time::Instant::now());
+    }
+}
+
+/// State of the piece currently downloaded from a peer.
+struct PieceProgress {
+    work: PieceWork,
+    data: BytesMut,
+    /// Blocks received, and blocks requested from the current peer but not received yet.
+    received: Vec<bool>,
+    requested: Vec<bool>,
+    downloaded: usize,
+    backlog: usize,
+}
+
+impl PieceProgress {
+    fn new(work: PieceWork) -> Self {
+        let data = BytesMut::zeroed(work.length);
+        let blocks = work.length.div_ceil(BLOCK_SIZE);
+        Self {
+            work,
+            data,
+            received: vec![false; blocks],
+            requested: vec![false; blocks],
+            downloaded: 0,
+            backlog: 0,
+        }
+    }
+
+    /// Offset and length of the next block to request, the block counts as requested from then on.
+    fn next_request(&mut self) -> Option<(usize, usize)> {
+        let block = (0..self.received.len())
+            .find(|block| !self.received[*block] && !self.requested[*block])?;
+        self.requested[block] = true;
+        self.backlog += 1;
+        let begin = block * BLOCK_SIZE;
+        Some((begin, BLOCK_SIZE.min(self.work.length - begin)))
+    }
+
+    /// Whether the block at `begin` is requested from the current peer and not received yet.
+    fn is_requested(&self, begin: usize) -> bool {
+        begin.is_multiple_of(BLOCK_SIZE)
+            && self
+                .requested
+                .get(begin / BLOCK_SIZE)
+                .is_some_and(|requested| *requested)
+    }
+
+    /// Offsets and lengths of the blocks requested from the current peer and not received yet.
+    fn pending_requests(&self) -> Vec<(usize, usize)> {
+        (0..self.requested.len())
+            .filter(|block| self.requested[*block])
+            .map(|block| {
+                let begin = block * BLOCK_SIZE;
+                (begin, BLOCK_SIZE.min(self.work.length - begin))
+            })
+            .collect()
+    }
+
+    /// Stores a received block, returns `false` if it's not a block of the piece or received already.
+    fn receive(&mut self, begin: usize, block: &[u8]) -> bool {
+        let index = begin / BLOCK_SIZE;
+        let expected_length = BLOCK_SIZE.min(self.work.length.saturating_sub(begin));
+        if !begin.is_multiple_of(BLOCK_SIZE)
+            || index >= self.received.len()
+            || block.len() != expected_length
+            || self.received[index]
+        {
+            return false;
+        }
+        self.data[begin..begin + block.len()].copy_from_slice(block);
+        self.received[index] = true;
+        self.downloaded += block.len();
+        if std::mem::take(&mut self.requested[index]) {
+            self.backlog -= 1;
+        }
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        self.downloaded == self.work.length
+    }
+
+    /// Forgets the requests sent to the current peer.
+    fn reset_requests(&mut self) {
+        self.requested.fill(false);
+        self.backlog = 0;
+    }
+
+    /// Checks the downloaded data against the expected SHA-1 hash of the piece.
+    fn is_valid(&self) -> bool {
+        let hash: Sha1HashBytes = Sha1::digest(&self.data).into();
+        hash == self.work.hash
+    }
+}
+
+/// Exchanges pieces with a single peer until there is nothing left to download or the connection fails:
+/// verified pieces are sent to `completed`, the piece in progress is put back into the queue on failure,
+/// and the block requests of the peer are served from the pieces we have while the choker unchokes it.
+/// The peer takes part in peer exchange if it supports [`UT_PEX`], it is only advertised to other peers
+/// if we connected to it, as the address of incoming connections is not the one the peer listens on.
+pub async fn download_from_peer<T: Transport>(
+    connection: PeerConnection<T>,
+    state: Arc<DownloadState>,
+    completed: mpsc::Sender<DownloadedPiece>,
+    outgoing: bool,
+) -> Result<(), Error> {
+    let address = PeerAddress::from(connection.peer_addr()?);
+    let advertised = state.pex.as_ref().filter(|_| outgoing);
+    if let Some(pex) = advertised {
+        pex.connected.lock().unwrap().insert(address);
+    }
+    let peer = state.choker.register(address);
+    let mut peer_pieces = Bitfield::new(state.store.torrent().pieces_count());
+    let mut progress = None;
+    let result = download_pieces(
+        &connection,
+        &state,
+        &completed,
+        &peer,
+        &mut peer_pieces,
+        &mut progress,
+    )
+    .await;
+    state.choker.unregister(&peer);
+    state.queue.remove_peer(&peer_pieces);
+    if let Some(progress) = progress {
+        state.queue.put_back(progress);
+    }
+    if let Some(pex) = advertised {
+        pex.connected.lock().unwrap().remove(&address);
+    }
+    result
+}
+
+/// Answers a block request of the peer, requests of pieces we don't have are ignored
+/// and requests longer than [`MAX_REQUEST_LENGTH`] are refused with an error.
+async fn serve_request<T: Transport>(
+    connection: &PeerConnection<T>,
+    state: &DownloadState,
+    peer: &ChokedPeer,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> Result<(), Error> {
+    let Some(block) = state
+        .store
+        .read_block(index as usize, begin as usize, length as usize)
+        .await?
+    else {
+        return Ok(());
+    };
+    state.stats.add_uploaded(block.len() as u64);
+    peer.add_uploaded(block.len());
+    connection
+        .send(PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Sends our connected peers to the remote peer if it's time to.
+async fn send_pex<T: Transport>(
+    connection: &PeerConnection<T>,
+    pex: &PeerExchange,
+    remote: PeerAddress,
+    state: &mut PexState,
+) -> Result<(), Error> {
+    if connection.peer_extension_id(UT_PEX).is_none() {
+        return Ok(());
+    }
+    // we connected to all of our peers, so they accept incoming connections
+    if let Some(message) =
+        state.next_message(&pex.connected(), remote, FLAG_REACHABLE, Instant::now())
+    {
+        debug!(
+            "[{0}] PEX: {1} peers added, {2} dropped",
+            remote,
+            message.added.len(),
+            message.dropped.len()
+        );
+        connection
+            .send_extended(UT_PEX, Bytes::try_from(message)?)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Verifies a completely downloaded piece and hands it over to `completed`.
+/// Returns `false` if the download is over.
+async fn complete_piece(
+    queue: &PieceQueue,
+    completed: &mpsc::Sender<DownloadedPiece>,
+    progress: PieceProgress,
+) -> Result<bool, Error> {
+    let index = progress.work.index;
+    if !progress.is_valid() {
+        // none of the blocks can be trusted, start the piece over
+        queue.put_back(PieceProgress::new(progress.work));
+        return Err(Error::InvalidPieceHash(index));
+    }
+    // in endgame mode another peer may have completed the piece first
+    if !queue.done(index) {
+        return Ok(true);
+    }
+    debug!("piece #{0} downloaded and verified", index);
+    let piece = DownloadedPiece {
+        index,
+        data: progress.data.freeze(),
+    };
+    Ok(completed.send(piece).await.is_ok())
+}
+
+/// Cancels the pending block requests of a piece downloaded from another peer in endgame mode.
+async fn cancel_requests<T: Transport>(
+    connection: &PeerConnection<T>,
+    progress: &PieceProgress,
+) -> Result<(), Error> {
+    for (begin, length) in progress.pending_requests() {
+        connection
+            .send(PeerMessage::Cancel {
+                index: progress.work.index as u32,
+                begin: begin as u32,
+                length: length as u32,
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+async fn download_pieces<T: Transport>(
+    connection: &PeerConnection<T>,
+    state: &DownloadState,
+    completed: &mpsc::Sender<DownloadedPiece>,
+    peer: &ChokedPeer,
+    peer_pieces: &mut Bitfield,
+    progress: &mut Option<PieceProgress>,
+) -> Result<(), Error> {
+    let remote = peer.address();
+    let queue = &state.queue;
+    let pieces_count = state.store.torrent().pieces_count();
+    let block_request_timeout = state.block_request_timeout;
+    let mut choked = true;
+    // our side of the connection state (https://wiki.theory.org/BitTorrentSpecification#Overview)
+    let mut am_choking = true;
+    let mut am_interested = false;
+    let mut pex_state = PexState::new();
+    let mut new_pieces = state.store.subscribe();
+    let mut endgame_blocks = queue.subscribe_blocks();
+    let have = state.store.bitfield();
+    if have.count() > 0 {
+        connection
+            .send(PeerMessage::Bitfield(Bytes::copy_from_slice(
+                have.as_bytes(),
+            )))
+            .await?;
+    }
+    if !state.store.is_complete() {
+        connection.send(PeerMessage::Interested).await?;
+        am_interested = true;
+        peer.set_am_interested(true, Instant::now());
+    }
+
+    loop {
+        if completed.is_closed() {
+            return Ok(());
+        }
+        let seeding = state.store.is_complete();
+        if seeding && peer_pieces.count() == pieces_count {
+            debug!("[{0}] peer is a seed as well, disconnecting", remote);
+            return Ok(());
+        }
+
+        if let Some(pex) = &state.pex {
+            send_pex(connection, pex, remote, &mut pex_state).await?;
+        }
+
+        loop {
+            match new_pieces.try_recv() {
+                Ok(index) => {
+                    connection
+                        .send(PeerMessage::Have {
+                            piece_index: index as u32,
+                        })
+                        .await?;
+                    if progress.as_ref().is_some_and(|p| p.work.index == index) {
+                        cancel_requests(connection, &progress.take().unwrap()).await?;
+                    }
+                }
+                Err(broadcast::error::TryRecvError::Lagged(missed)) => {
+                    debug!("[{0}] {1} have messages skipped", remote, missed)
+                }
+                Err(_) => break,
+            }
+        }
+        if am_interested && seeding {
+            connection.send(PeerMessage::NotInterested).await?;
+            am_interested = false;
+            peer.set_am_interested(false, Instant::now());
+        }
+        if peer.is_unchoked() == am_choking {
+            am_choking = !am_choking;
+            let message = if am_choking {
+                PeerMessage::Choke
+            } else {
+                PeerMessage::Unchoke
+            };
+            connection.send(message).await?;
+        }
+
+        if !choked {
+            if progress.is_none() {
+                *progress = queue.take(peer_pieces);
+            }
+            if let Some(progress) = progress.as_mut() {
+                while progress.backlog < MAX_PIPELINED_REQUESTS {
+                    let Some((begin, length)) = progress.next_request() else {
+                        break;
+                    };
+                    connection
+                        .send(PeerMessage::Request {
+                            index: progress.work.index as u32,
+                            begin: begin as u32,
+                            length: length as u32,
+                        })
+                        .await?;
+                }
+            }
+        }
+
+        let waiting_for_blocks = progress.as_ref().is_some_and(|p| p.backlog > 0);
+        let timeout = if waiting_for_blocks {
+            block_request_timeout
+        } else {
+            IDLE_PEER_RECHECK_INTERVAL
+        };
+        let received = tokio::select! {
+            received = tokio::time::timeout(timeout, connection.recv()) => received,
+            // the choker's decision is applied at the top of the loop
+            _ = peer.changed() => continue,
+            block = endgame_blocks.recv(), if progress.is_some() => {
+                let Ok(block) = block else {
+                    continue;
+                };
+                let Some(current) = progress.as_mut() else {
+                    continue;
+                };
+                if block.index != current.work.index {
+                    continue;
+                }
+                let requested = current.is_requested(block.begin);
+                if !current.receive(block.begin, block.data.as_ref()) {
+                    continue;
+                }
+                if requested {
+                    connection
+                        .send(PeerMessage::Cancel {
+                            index: block.index as u32,
+                            begin: block.begin as u32,
+                            length: block.data.len() as u32,
+                        })
+                        .await?;
+                }
+                if current.is_complete() {
+                    let current = progress.take().unwrap();
+                    if !complete_piece(queue, completed, current).await? {
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+        };
+        let message = match received {
+            Ok(message) => message?,
+            Err(_) if waiting_for_blocks => {
+                return Err(Error::BlockRequestTimeout(block_request_timeout))
+            }
+            Err(_) => continue,
+        };
+
+        match message {
+            PeerMessage::Choke => {
+                choked = true;
+                // peers discard pending requests when choking, start the piece over later
+                if let Some(progress) = progress.take() {
+                    queue.put_back(progress);
+                }
+            }
+            PeerMessage::Unchoke => choked = false,
+            PeerMessage::Interested => {
+                peer.set_interested(true);
+                state.choker.interested(peer);
+            }
+            PeerMessage::NotInterested => peer.set_interested(false),
+            PeerMessage::Request { length, .. } if length > MAX_REQUEST_LENGTH => {
+                return Err(Error::RequestTooLong(length))
+            }
+            // requests of choked peers are discarded, they know to request again once unchoked
+            PeerMessage::Request { .. } if am_choking => {
+                debug!("[{0}] ignoring request while choked", remote)
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => serve_request(connection, state, peer, index, begin, length).await?,
+            PeerMessage::Have { piece_index } => {
+                let index = piece_index as usize;
+                if index < pieces_count && !peer_pieces.has(index) {
+                    peer_pieces.set(index);
+                    queue.add_piece(index);
+                }
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                queue.remove_peer(peer_pieces);
+                *peer_pieces = Bitfield::from_bytes(bitfield.as_ref(), pieces_count);
+                queue.add_peer(peer_pieces);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let Some(current) = progress.as_mut() else {
+                    continue;
+                };
+                if index as usize != current.work.index
+                    || !current.receive(begin as usize, block.as_ref())
+                {
+                    continue;
+                }
+                peer.add_downloaded(block.len(), Instant::now());
+                queue.share_block(index as usize, begin as usize, block);
+
+                if current.is_complete() {
+                    let current = progress.take().unwrap();
+                    if !complete_piece(queue, completed, current).await? {
+                        return Ok(());
+                    }
+                }
+            }
+            PeerMessage::Extended { id, payload } => {
+                let Some(pex) = &state.pex else {
+                    continue;
+                };
+                if connection.local_extension_id(UT_PEX) != Some(id) {
+                    continue;
+                }
+                match PexMessage::try_from(payload) {
+                    Ok(message) => pex.discover(pex_state.receive(message, Instant::now())),
+                    Err(error) => debug!("[{0}] invalid PEX message: {1:?}", remote, error),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::transport::MemoryTransport;
+    use crate::storage::{FileSystemBackend, StorageBackend};
+    use crate::test_util::{single_file_torrent, TempDir};
+    use std::path::Path;
+    use tokio::task::JoinHandle;
+
+    const DATA: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+    /// Single-file torrent of [`DATA`] with two pieces.
+    fn torrent() -> Arc<TorrentFile> {
+        Arc::new(single_file_torrent(DATA, 16))
+    }
+
+    /// Store of [`torrent`] with `data` written to `path`.
+    async fn store(path: &Path, data: &[u8]) -> PieceStore {
+        tokio::fs::write(path, data).await.unwrap();
+        PieceStore::new(FileSystemBackend.open(torrent(), path).await.unwrap())
+    }
+
+    #[test]
+    fn resume_partial_piece() {
+        let mut progress = PieceProgress::new(PieceWork {
+            index: 0,
+            length: 2 * BLOCK_SIZE + 10,
+            hash: [0; 20],
+        });
+        assert_eq!(progress.next_request(), Some((0, BLOCK_SIZE)));
+        assert_eq!(progress.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(!progress.receive(BLOCK_SIZE, &[1; 10]));
+        assert!(progress.receive(BLOCK_SIZE, &[1; BLOCK_SIZE]));
+        assert!(!progress.receive(BLOCK_SIZE, &[1; BLOCK_SIZE]));
+        assert_eq!(progress.backlog, 1);
+
+        // blocks requested from a peer which went away are requested again, received ones are kept
+        progress.reset_requests();
+        assert_eq!(progress.next_request(), Some((0, BLOCK_SIZE)));
+        assert_eq!(progress.next_request(), Some((2 * BLOCK_SIZE, 10)));
+        assert_eq!(progress.next_request(), None);
+        assert!(progress.receive(0, &[0; BLOCK_SIZE]));
+        assert!(progress.receive(2 * BLOCK_SIZE, &[2; 10]));
+        assert!(progress.is_complete());
+        assert_eq!(progress.backlog, 0);
+    }
+
+    #[tokio::test]
+    async fn download_pieces_again_in_endgame() {
+        let dir = TempDir::new("endgame");
+        let store = store(&dir.join("test"), b"").await;
+        let queue = PieceQueue::new(store.torrent(), &store.bitfield());
+        let peer = Bitfield::from_bytes(&[0xff], 2);
+        let mut blocks = queue.subscribe_blocks();
+        let first = queue.take(&peer).unwrap();
+        queue.share_block(first.work.index, 0, Bytes::from_static(b"too early"));
+        let second = queue.take(&peer).unwrap();
+
+        // all pieces are taken, the same ones are downloaded from other peers and their blocks are shared
+        let duplicate = queue.take(&peer).unwrap();
+        assert!([first.work.index, second.work.index].contains(&duplicate.work.index));
+        queue.share_block(duplicate.work.index, 0, Bytes::from_static(&DATA[..16]));
+        let block = blocks.recv().await.unwrap();
+        assert_eq!(
+            (block.index, block.begin, block.data.as_ref()),
+            (duplicate.work.index, 0, &DATA[..16])
+        );
+        assert!(queue.done(duplicate.work.index));
+        assert!(!queue.done(duplicate.work.index));
+    }
+
+    #[tokio::test]
+    async fn check_existing_pieces() {
+        let mut data = DATA.to_vec();
+        data[20] = b'!';
+        let dir = TempDir::new("check-existing-pieces");
+        let store = store(&dir.join("test"), &data).await;
+        assert_eq!(store.check_pieces().await.unwrap(), 1);
+        assert!(!store.is_complete());
+        assert_eq!(store.left(), 16);
+        assert_eq!(
+            store.read_block(0, 4, 8).await.unwrap().unwrap().as_ref(),
+            b"456789ab"
+        );
+        assert!(store.read_block(1, 0, 16).await.unwrap().is_none());
+        assert!(store.read_block(0, 8, 16).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn restore_pieces_from_resume_data() {
+        let dir = TempDir::new("restore-pieces");
+        let path = dir.join("test");
+        let open = || async {
+            let storage = FileSystemBackend.open(torrent(), &path).await.unwrap();
+            PieceStore::new(storage).with_resume_path(resume::resume_path(&path))
+        };
+        let mut data = DATA.to_vec();
+        data[20] = b'!';
+        let store = store(&path, &data)
+            .await
+            .with_resume_path(resume::resume_path(&path));
+        // without resume data the existing data is verified
+        assert!(store.restore(false).await.unwrap().is_empty());
+        assert_eq!(store.left(), 16);
+        let peer = PeerAddress::from("127.0.0.1:6881".parse::<std::net::SocketAddr>().unwrap());
+        store.save_resume(&[peer]).await.unwrap();
+
+        // the fixed piece is not noticed while the file looks unchanged...
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        let modified = file.metadata().unwrap().modified().unwrap();
+        std::io::Seek::seek(&mut &file, std::io::SeekFrom::Start(20)).unwrap();
+        std::io::Write::write_all(&mut &file, &DATA[20..21]).unwrap();
+        file.set_modified(modified).unwrap();
+        let restored = open().await;
+        assert_eq!(restored.restore(false).await.unwrap(), vec![peer]);
+        assert_eq!(restored.left(), 16);
+
+        // ...the stale resume data is ignored once it changed
+        file.set_modified(std::time::SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let rechecked = open().await;
+        assert!(rechecked.restore(false).await.unwrap().is_empty());
+        assert!(rechecked.is_complete());
+    }
+
+    /// Seeds [`DATA`] to the returned remote peer.
+    async fn start_seed(
+        dir: &TempDir,
+        completed_tx: mpsc::Sender<DownloadedPiece>,
+    ) -> (
+        Arc<DownloadState>,
+        JoinHandle<Result<(), Error>>,
+        PeerConnection<MemoryTransport>,
+    ) {
+        let store = store(&dir.join("test"), DATA).await;
+        store.check_pieces().await.unwrap();
+        let have = store.bitfield();
+        let state = Arc::new(DownloadState {
+            queue: PieceQueue::new(store.torrent(), &have),
+            store,
+            pex: None,
+            stats: Arc::new(TransferStats::new(0)),
+            choker: Choker::new(1),
+            block_request_timeout: Duration::from_secs(5),
+        });
+        let (local, remote) = MemoryTransport::pair();
+        let seed = tokio::spawn(download_from_peer(
+            PeerConnection::new(local, Duration::from_secs(5)),
+            state.clone(),
+            completed_tx,
+            false,
+        ));
+        (
+            state,
+            seed,
+            PeerConnection::new(remote, Duration::from_secs(5)),
+        )
+    }
+
+    #[tokio::test]
+    async fn serve_requests_of_unchoked_peers() {
+        let (completed_tx, _completed_rx) = mpsc::channel(1);
+        let dir = TempDir::new("serve-requests");
+        let (state, seed, remote) = start_seed(&dir, completed_tx).await;
+        let request = |length| PeerMessage::Request {
+            index: 1,
+            begin: 0,
+            length,
+        };
+
+        // seeds are not interested, and ignore requests until the peer is unchoked
+        assert_eq!(
+            remote.recv().await.unwrap(),
+            PeerMessage::Bitfield(Bytes::from_static(&[0b1100_0000]))
+        );
+        remote.send(request(16)).await.unwrap();
+        remote.send(PeerMessage::Interested).await.unwrap();
+        assert_eq!(remote.recv().await.unwrap(), PeerMessage::Unchoke);
+        remote.send(request(16)).await.unwrap();
+        assert_eq!(
+            remote.recv().await.unwrap(),
+            PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: Bytes::from_static(&DATA[16..]),
+            }
+        );
+        assert_eq!(state.stats.uploaded(), 16);
+
+        remote.send(request(256 * 1024)).await.unwrap();
+        assert!(matches!(
+            seed.await.unwrap(),
+            Err(Error::RequestTooLong(262144))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reject_long_requests_of_choked_peers() {
+        let (completed_tx, _completed_rx) = mpsc::channel(1);
+        let dir = TempDir::new("reject-long-requests");
+        let (_, seed, remote) = start_seed(&dir, completed_tx).await;
+        remote.recv().await.unwrap();
+        remote
+            .send(PeerMessage::Request {
+                index: 0,
+                begin: 0,
+                length: MAX_REQUEST_LENGTH + 1,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            seed.await.unwrap(),
+            Err(Error::RequestTooLong(length)) if length == MAX_REQUEST_LENGTH + 1
+        ));
+    }
+}
@@ -3,11 +3,32 @@
 ///
 /// Features:
 /// - Read and parse .torrent files
-/// - Communication with torrent tracker to get all bittorrent peers (only http ones for now)
-/// - Connect to all peers parallel through TCP connection and perform handshake as initial steps of download process
+/// - Communication with torrent tracker to get all bittorrent peers over HTTP and UDP, using all tiers of `announce-list`
+/// - Find peers without trackers in the mainline DHT and the local network, and learn about more peers from connected ones (peer exchange)
+/// - Connect to all peers parallel through TCP connection, download and verify pieces, then write them to disk
+/// - Accept incoming peer connections and serve verified pieces to them
 ///
 /// The whole implementation is based on the BitTorrent specification: https://wiki.theory.org/BitTorrentSpecification
+mod choker;
 mod client;
-mod protocol;
+mod create;
+mod download;
+mod listener;
+mod picker;
+pub mod protocol;
+mod resume;
+mod storage;
+#[cfg(test)]
+mod test_util;
+mod tracker_session;
+mod verify;
 
 pub use client::*;
+pub use create::TorrentBuilder;
+pub use listener::PeerListener;
+pub use storage::{
+    FileStats, FileStorage, FileSystemBackend, MemoryBackend, MemoryStorage, Storage,
+    StorageBackend,
+};
+pub use tracker_session::TrackerEvent;
+pub use verify::{verify, FileStatus, FileVerification, VerificationReport};
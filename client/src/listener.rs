@@ -0,0 +1,209 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::Sha1HashBytes;
+use crate::protocol::peer_wire::PeerConnection;
+use log::debug;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+/// I/O timeout of accepted connections, the handshake must arrive within it as well.
+const INCOMING_IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of accepted connections waiting for their handshake, further connections are
+/// closed right away so idle peers can't exhaust our resources.
+const MAX_PENDING_HANDSHAKES: usize = 64;
+
+/// Connection of a peer which connected to us. Its handshake is read already (see [`PeerConnection::accept_handshake`]),
+/// the download of the torrent completes it with [`PeerConnection::respond_handshake`].
+pub(crate) type IncomingConnection = PeerConnection<TcpStream>;
+
+type Torrents = Arc<Mutex<HashMap<Sha1HashBytes, mpsc::Sender<IncomingConnection>>>>;
+
+/// Accepts TCP connections of other peers and hands them over to the download of the torrent
+/// they ask for in their handshake, connections for unknown torrents are closed.
+pub struct PeerListener {
+    local_addr: SocketAddr,
+    torrents: Torrents,
+    acceptor: JoinHandle<()>,
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+/// Registration of a torrent at a [`PeerListener`], the torrent is removed when it is dropped.
+pub(crate) struct ListenerRegistration {
+    torrents: Torrents,
+    info_hash: Sha1HashBytes,
+}
+
+impl Drop for ListenerRegistration {
+    fn drop(&mut self) {
+        self.torrents.lock().unwrap().remove(&self.info_hash);
+    }
+}
+
+impl PeerListener {
+    /// Listens for incoming peer connections on `address`.
+    pub async fn bind(address: SocketAddr) -> Result<Self, Error> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let torrents: Torrents = Arc::new(Mutex::new(HashMap::new()));
+        let acceptor = tokio::spawn(accept_loop(listener, torrents.clone()));
+        debug!("Listening for peers on {0}", local_addr);
+        Ok(Self {
+            local_addr,
+            torrents,
+            acceptor,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Port announced to trackers and other peers.
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Sends connections of peers asking for `info_hash` to `connections` until the registration is dropped.
+    pub(crate) fn register(
+        &self,
+        info_hash: Sha1HashBytes,
+        connections: mpsc::Sender<IncomingConnection>,
+    ) -> ListenerRegistration {
+        self.torrents.lock().unwrap().insert(info_hash, connections);
+        ListenerRegistration {
+            torrents: self.torrents.clone(),
+            info_hash,
+        }
+    }
+}
+
+async fn accept_loop(listener: TcpListener, torrents: Torrents) {
+    let pending_handshakes = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                debug!("Failed to accept peer connection: {:?}", error);
+                continue;
+            }
+        };
+        let Ok(permit) = pending_handshakes.clone().try_acquire_owned() else {
+            debug!(
+                "[{0}] too many pending handshakes, closing connection",
+                address
+            );
+            continue;
+        };
+        let torrents = torrents.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let mut connection = PeerConnection::new(stream, INCOMING_IO_TIMEOUT);
+            let handshake = match connection.accept_handshake().await {
+                Ok(handshake) => handshake,
+                Err(error) => {
+                    debug!("[{0}] invalid incoming handshake: {1:?}", address, error);
+                    return;
+                }
+            };
+            let connections = torrents
+                .lock()
+                .unwrap()
+                .get(&handshake.info_hash())
+                .cloned();
+            match connections {
+                Some(connections) => {
+                    if connections.try_send(connection).is_err() {
+                        debug!("[{0}] dropped incoming connection", address);
+                    }
+                }
+                None => debug!(
+                    "[{0}] incoming connection for unknown torrent {1}",
+                    address,
+                    hex::encode(handshake.info_hash())
+                ),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::peer_wire::PeerMessage;
+
+    async fn connect(
+        listener: &PeerListener,
+        info_hash: Sha1HashBytes,
+    ) -> Result<PeerConnection<TcpStream>, crate::protocol::peer_wire::Error> {
+        let stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+        let mut connection = PeerConnection::new(stream, Duration::from_secs(5));
+        connection
+            .handshake("-RT0100-remotepeer01".to_string(), info_hash)
+            .await?;
+        Ok(connection)
+    }
+
+    #[tokio::test]
+    async fn route_incoming_connections_by_info_hash() {
+        let listener = PeerListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let (connections_tx, mut connections_rx) = mpsc::channel(1);
+        let registration = listener.register([1; 20], connections_tx);
+
+        let local = tokio::spawn(async move {
+            let mut incoming = connections_rx.recv().await.unwrap();
+            incoming
+                .respond_handshake("-RT0100-localpeer001".to_string(), [1; 20])
+                .await
+                .unwrap();
+            incoming.send(PeerMessage::Unchoke).await.unwrap();
+        });
+        let connection = connect(&listener, [1; 20]).await.unwrap();
+        connection.wait_for_extended_handshake().await.unwrap();
+        assert_eq!(connection.recv().await.unwrap(), PeerMessage::Unchoke);
+        local.await.unwrap();
+
+        // unknown torrents, and torrents of finished downloads are refused
+        assert!(connect(&listener, [2; 20]).await.is_err());
+        drop(registration);
+        assert!(connect(&listener, [1; 20]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limit_pending_handshakes() {
+        let listener = PeerListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let (connections_tx, mut connections_rx) = mpsc::channel(1);
+        let _registration = listener.register([1; 20], connections_tx);
+        let mut idle = vec![];
+        for _ in 0..MAX_PENDING_HANDSHAKES {
+            idle.push(TcpStream::connect(listener.local_addr()).await.unwrap());
+        }
+        // connections beyond the limit are closed before their handshake is read
+        assert!(connect(&listener, [1; 20]).await.is_err());
+
+        drop(idle);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let local = tokio::spawn(async move {
+            let mut incoming = connections_rx.recv().await.unwrap();
+            incoming
+                .respond_handshake("-RT0100-localpeer001".to_string(), [1; 20])
+                .await
+                .unwrap();
+        });
+        connect(&listener, [1; 20]).await.unwrap();
+        local.await.unwrap();
+    }
+}
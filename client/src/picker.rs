@@ -0,0 +1,282 @@
+use crate::protocol::peer_wire::Bitfield;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+/// Pieces are picked at random until this many are downloaded, so there is something to share early on
+/// (https://wiki.theory.org/BitTorrentSpecification#Piece_downloading_strategy).
+const RANDOM_FIRST_PIECES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    /// Nothing is downloaded yet.
+    Missing,
+    /// Some blocks are downloaded, but no peer is downloading the rest.
+    Partial,
+    /// Peers are downloading the piece, more than one only in endgame mode.
+    Picked(usize),
+    /// The piece is downloaded and verified.
+    Done,
+}
+
+/// Chooses the piece a peer should download next: partially downloaded pieces are finished first,
+/// then the first few pieces are picked at random, later the rarest piece among the connected peers
+/// (rarest first, ties are broken randomly). Once every piece is picked, the endgame begins:
+/// pieces are picked again for other peers, so the download doesn't wait for the slowest one.
+pub struct PiecePicker {
+    states: Vec<PieceState>,
+    /// Number of connected peers having each piece.
+    availability: Vec<usize>,
+    done: usize,
+}
+
+impl PiecePicker {
+    /// Constructs a picker for the pieces missing from `have`.
+    pub fn new(have: &Bitfield) -> Self {
+        let states: Vec<_> = (0..have.len())
+            .map(|index| {
+                if have.has(index) {
+                    PieceState::Done
+                } else {
+                    PieceState::Missing
+                }
+            })
+            .collect();
+        Self {
+            availability: vec![0; states.len()],
+            done: have.count(),
+            states,
+        }
+    }
+
+    /// Counts the pieces of a peer's bitfield.
+    pub fn add_peer(&mut self, pieces: &Bitfield) {
+        for (index, availability) in self.availability.iter_mut().enumerate() {
+            if pieces.has(index) {
+                *availability += 1;
+            }
+        }
+    }
+
+    /// Stops counting the pieces of a disconnected peer.
+    pub fn remove_peer(&mut self, pieces: &Bitfield) {
+        for (index, availability) in self.availability.iter_mut().enumerate() {
+            if pieces.has(index) {
+                *availability = availability.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Counts a piece a peer announced with a `have` message.
+    pub fn add_piece(&mut self, index: usize) {
+        if let Some(availability) = self.availability.get_mut(index) {
+            *availability += 1;
+        }
+    }
+
+    /// Whether all remaining pieces are picked, i.e. they are downloaded already.
+    pub fn is_endgame(&self) -> bool {
+        !self
+            .states
+            .iter()
+            .any(|state| matches!(state, PieceState::Missing | PieceState::Partial))
+    }
+
+    /// Picks the next piece to download from a peer having `peer_pieces`, the piece isn't picked again
+    /// until it is put back, except in endgame mode (see [`Self::pick_endgame`]).
+    pub fn pick(&mut self, peer_pieces: &Bitfield, rng: &mut impl Rng) -> Option<usize> {
+        let candidates = |state: PieceState| {
+            (0..self.states.len())
+                .filter(|index| self.states[*index] == state && peer_pieces.has(*index))
+                .collect::<Vec<_>>()
+        };
+        let partial = candidates(PieceState::Partial);
+        let index = if !partial.is_empty() {
+            self.rarest(&partial, rng)
+        } else {
+            let missing = candidates(PieceState::Missing);
+            if self.done < RANDOM_FIRST_PIECES {
+                missing.choose(rng).copied()
+            } else {
+                self.rarest(&missing, rng)
+            }
+        }?;
+        self.states[index] = PieceState::Picked(1);
+        Some(index)
+    }
+
+    /// Picks a piece in endgame mode, which is downloaded by other peers already:
+    /// the one with the fewest downloaders is chosen.
+    pub fn pick_endgame(&mut self, peer_pieces: &Bitfield, rng: &mut impl Rng) -> Option<usize> {
+        if !self.is_endgame() {
+            return None;
+        }
+        let candidates: Vec<(usize, usize)> = (0..self.states.len())
+            .filter(|index| peer_pieces.has(*index))
+            .filter_map(|index| match self.states[index] {
+                PieceState::Picked(peers) => Some((index, peers)),
+                _ => None,
+            })
+            .collect();
+        let fewest = candidates.iter().map(|(_, peers)| *peers).min()?;
+        let (index, peers) = *candidates
+            .iter()
+            .filter(|(_, peers)| *peers == fewest)
+            .collect::<Vec<_>>()
+            .choose(rng)?;
+        self.states[*index] = PieceState::Picked(peers + 1);
+        Some(*index)
+    }
+
+    /// The least available piece of `candidates`, a random one of them if multiple are equally rare.
+    fn rarest(&self, candidates: &[usize], rng: &mut impl Rng) -> Option<usize> {
+        let rarity = candidates
+            .iter()
+            .map(|index| self.availability[*index])
+            .min()?;
+        let rarest: Vec<_> = candidates
+            .iter()
+            .copied()
+            .filter(|index| self.availability[*index] == rarity)
+            .collect();
+        rarest.choose(rng).copied()
+    }
+
+    /// Makes a picked piece available to other peers again, `partial` if some of its blocks are downloaded.
+    /// Returns `false` if other peers are still downloading the piece, or it's done already.
+    pub fn put_back(&mut self, index: usize, partial: bool) -> bool {
+        match self.states[index] {
+            PieceState::Picked(peers) if peers > 1 => {
+                self.states[index] = PieceState::Picked(peers - 1);
+                false
+            }
+            PieceState::Picked(_) => {
+                self.states[index] = if partial {
+                    PieceState::Partial
+                } else {
+                    PieceState::Missing
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Marks a picked piece as downloaded and verified, returns `false` if it was done already
+    /// (i.e. it was downloaded from multiple peers in endgame mode).
+    pub fn done(&mut self, index: usize) -> bool {
+        if self.states[index] == PieceState::Done {
+            return false;
+        }
+        self.states[index] = PieceState::Done;
+        self.done += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for index in pieces {
+            bitfield.set(*index);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn pick_rarest_piece_after_random_first_pieces() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(&bitfield(10, &[0, 1, 2, 3]));
+        picker.add_peer(&bitfield(10, &[4, 5, 6, 7, 8]));
+        picker.add_peer(&bitfield(10, &[4, 5, 6, 7]));
+        picker.add_peer(&bitfield(10, &[5, 6, 7]));
+        picker.add_piece(6);
+
+        // pieces unknown to the other peers are the rarest
+        let peer = bitfield(10, &[4, 5, 6, 7, 8, 9]);
+        assert_eq!(picker.pick(&peer, &mut rng), Some(9));
+        assert_eq!(picker.pick(&peer, &mut rng), Some(8));
+        assert_eq!(picker.pick(&peer, &mut rng), Some(4));
+        let mut equally_rare = [
+            picker.pick(&peer, &mut rng).unwrap(),
+            picker.pick(&peer, &mut rng).unwrap(),
+        ];
+        equally_rare.sort();
+        assert_eq!(equally_rare, [5, 7]);
+        assert_eq!(picker.pick(&peer, &mut rng), Some(6));
+        assert_eq!(picker.pick(&peer, &mut rng), None);
+        assert!(picker.is_endgame());
+
+        // disconnected peers don't count anymore
+        picker.remove_peer(&bitfield(10, &[4, 5, 6, 7, 8]));
+        picker.put_back(8, false);
+        picker.put_back(4, false);
+        assert_eq!(picker.pick(&peer, &mut rng), Some(8));
+    }
+
+    #[test]
+    fn pick_random_first_pieces() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(&Bitfield::new(20));
+        // piece #i is available from i + 1 peers
+        for first in 0..20 {
+            picker.add_peer(&bitfield(20, &(first..20).collect::<Vec<_>>()));
+        }
+        let peer = bitfield(20, &(0..20).collect::<Vec<_>>());
+        let picked: Vec<_> = (0..RANDOM_FIRST_PIECES)
+            .map(|_| picker.pick(&peer, &mut rng).unwrap())
+            .collect();
+        assert_ne!(picked, [0, 1, 2, 3]);
+        for index in &picked {
+            picker.done(*index);
+        }
+        let rarest = (0..20).find(|index| !picked.contains(index));
+        assert_eq!(picker.pick(&peer, &mut rng), rarest);
+    }
+
+    #[test]
+    fn finish_partial_pieces_first() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(&bitfield(3, &[]));
+        let peer = bitfield(3, &[0, 1, 2]);
+        picker.add_peer(&peer);
+        picker.add_peer(&bitfield(3, &[0]));
+        let first = picker.pick(&peer, &mut rng).unwrap();
+        let second = picker.pick(&peer, &mut rng).unwrap();
+        picker.put_back(first, false);
+        picker.put_back(second, true);
+        assert_eq!(picker.pick(&peer, &mut rng), Some(second));
+        // peers without the partial piece download other pieces
+        let other = (0..3).find(|index| *index != second).unwrap();
+        assert_eq!(picker.pick(&bitfield(3, &[other]), &mut rng), Some(other));
+    }
+
+    #[test]
+    fn pick_pieces_again_in_endgame() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut picker = PiecePicker::new(&bitfield(3, &[0]));
+        let peer = bitfield(3, &[0, 1, 2]);
+        let first = picker.pick(&peer, &mut rng).unwrap();
+        assert!(!picker.is_endgame());
+        assert_eq!(picker.pick_endgame(&peer, &mut rng), None);
+        let second = picker.pick(&peer, &mut rng).unwrap();
+        assert!(picker.is_endgame());
+
+        // the piece with the fewest downloaders is picked again
+        let picked = picker.pick_endgame(&peer, &mut rng).unwrap();
+        let other = picker.pick_endgame(&peer, &mut rng).unwrap();
+        let mut pieces = [picked, other];
+        pieces.sort();
+        assert_eq!(pieces, [first.min(second), first.max(second)]);
+        assert_eq!(picker.pick_endgame(&bitfield(3, &[0]), &mut rng), None);
+        assert!(!picker.put_back(picked, true));
+        assert!(picker.put_back(picked, false));
+        assert!(!picker.is_endgame());
+        assert!(picker.done(first));
+        assert!(!picker.done(first));
+    }
+}
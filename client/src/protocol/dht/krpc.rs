@@ -0,0 +1,453 @@
+use crate::protocol::dht::{Error, NodeId, NodeInfo};
+use crate::protocol::meta_info_file::{check_bencode_depth, Sha1HashBytes};
+use crate::protocol::tracker::{parse_compact_peers, PeerAddress};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+
+/// Length of a node ID and a compact node info (node ID + compact IPv4 address) in bytes.
+const NODE_ID_LENGTH: usize = 20;
+const COMPACT_NODE_INFO_LENGTH: usize = NODE_ID_LENGTH + 6;
+
+/// Message types (`y` key) from https://www.bittorrent.org/beps/bep_0005.html#krpc-protocol.
+const MESSAGE_TYPE_QUERY: &str = "q";
+const MESSAGE_TYPE_RESPONSE: &str = "r";
+const MESSAGE_TYPE_ERROR: &str = "e";
+
+/// Query method names (`q` key).
+const QUERY_PING: &str = "ping";
+const QUERY_FIND_NODE: &str = "find_node";
+const QUERY_GET_PEERS: &str = "get_peers";
+const QUERY_ANNOUNCE_PEER: &str = "announce_peer";
+
+/// Error codes from https://www.bittorrent.org/beps/bep_0005.html#errors.
+pub const ERROR_CODE_GENERIC: i64 = 201;
+pub const ERROR_CODE_PROTOCOL: i64 = 203;
+pub const ERROR_CODE_METHOD_UNKNOWN: i64 = 204;
+
+/// Bencoded KRPC message, only the keys matching the message type are present.
+#[derive(Serialize, Deserialize, Debug)]
+struct RawMessage {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawArguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+}
+
+/// Bencoded query arguments.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawArguments {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+}
+
+/// Bencoded response values.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawResponse {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+/// DHT queries (https://www.bittorrent.org/beps/bep_0005.html#dht-queries).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: Sha1HashBytes,
+    },
+    AnnouncePeer {
+        info_hash: Sha1HashBytes,
+        port: u16,
+        token: Vec<u8>,
+        /// The source port of the UDP packet should be used instead of `port`.
+        implied_port: bool,
+    },
+}
+
+/// Response to any of the [`Query`] types, fields which are not part of the response are empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<PeerAddress>,
+    pub token: Option<Vec<u8>>,
+}
+
+/// KRPC message (https://www.bittorrent.org/beps/bep_0005.html#krpc-protocol).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Query {
+        transaction_id: Vec<u8>,
+        id: NodeId,
+        query: Query,
+    },
+    Response {
+        transaction_id: Vec<u8>,
+        response: Response,
+    },
+    Error {
+        transaction_id: Vec<u8>,
+        code: i64,
+        message: String,
+    },
+}
+
+impl Message {
+    pub fn transaction_id(&self) -> &[u8] {
+        match self {
+            Message::Query { transaction_id, .. }
+            | Message::Response { transaction_id, .. }
+            | Message::Error { transaction_id, .. } => transaction_id,
+        }
+    }
+}
+
+/// Serialize KRPC message to bencoded bytes.
+impl TryFrom<Message> for Vec<u8> {
+    type Error = Error;
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        let raw = match message {
+            Message::Query {
+                transaction_id,
+                id,
+                query,
+            } => {
+                let mut arguments = RawArguments {
+                    id: ByteBuf::from(id.to_vec()),
+                    ..Default::default()
+                };
+                let method = match query {
+                    Query::Ping => QUERY_PING,
+                    Query::FindNode { target } => {
+                        arguments.target = Some(ByteBuf::from(target.to_vec()));
+                        QUERY_FIND_NODE
+                    }
+                    Query::GetPeers { info_hash } => {
+                        arguments.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        QUERY_GET_PEERS
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        arguments.info_hash = Some(ByteBuf::from(info_hash.to_vec()));
+                        arguments.port = Some(port as i64);
+                        arguments.token = Some(ByteBuf::from(token));
+                        arguments.implied_port = Some(implied_port as i64);
+                        QUERY_ANNOUNCE_PEER
+                    }
+                };
+                RawMessage {
+                    t: ByteBuf::from(transaction_id),
+                    y: MESSAGE_TYPE_QUERY.to_string(),
+                    q: Some(method.to_string()),
+                    a: Some(arguments),
+                    r: None,
+                    e: None,
+                }
+            }
+            Message::Response {
+                transaction_id,
+                response,
+            } => RawMessage {
+                t: ByteBuf::from(transaction_id),
+                y: MESSAGE_TYPE_RESPONSE.to_string(),
+                q: None,
+                a: None,
+                r: Some(RawResponse {
+                    id: ByteBuf::from(response.id.to_vec()),
+                    nodes: (!response.nodes.is_empty())
+                        .then(|| ByteBuf::from(encode_nodes(&response.nodes))),
+                    values: (!response.values.is_empty()).then(|| {
+                        response
+                            .values
+                            .iter()
+                            .filter_map(encode_peer)
+                            .map(ByteBuf::from)
+                            .collect()
+                    }),
+                    token: response.token.map(ByteBuf::from),
+                }),
+                e: None,
+            },
+            Message::Error {
+                transaction_id,
+                code,
+                message,
+            } => RawMessage {
+                t: ByteBuf::from(transaction_id),
+                y: MESSAGE_TYPE_ERROR.to_string(),
+                q: None,
+                a: None,
+                r: None,
+                e: Some((code, message)),
+            },
+        };
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+}
+
+/// Deserialize KRPC message from bencoded bytes.
+impl TryFrom<&[u8]> for Message {
+    type Error = Error;
+    fn try_from(data: &[u8]) -> Result<Self, Error> {
+        check_bencode_depth(data).map_err(|_| Error::MalformedMessage)?;
+        let raw: RawMessage = serde_bencode::from_bytes(data)?;
+        let transaction_id = raw.t.into_vec();
+        match raw.y.as_str() {
+            MESSAGE_TYPE_QUERY => {
+                let arguments = raw.a.ok_or(Error::MalformedMessage)?;
+                let query = match raw.q.as_deref() {
+                    Some(QUERY_PING) => Query::Ping,
+                    Some(QUERY_FIND_NODE) => Query::FindNode {
+                        target: to_node_id(arguments.target.as_deref().map(Vec::as_slice))?,
+                    },
+                    Some(QUERY_GET_PEERS) => Query::GetPeers {
+                        info_hash: to_node_id(arguments.info_hash.as_deref().map(Vec::as_slice))?,
+                    },
+                    Some(QUERY_ANNOUNCE_PEER) => Query::AnnouncePeer {
+                        info_hash: to_node_id(arguments.info_hash.as_deref().map(Vec::as_slice))?,
+                        port: arguments
+                            .port
+                            .and_then(|port| u16::try_from(port).ok())
+                            .ok_or(Error::MalformedMessage)?,
+                        token: arguments.token.ok_or(Error::MalformedMessage)?.into_vec(),
+                        implied_port: arguments.implied_port.unwrap_or(0) != 0,
+                    },
+                    method => {
+                        return Err(Error::UnknownMethod(
+                            transaction_id,
+                            method.unwrap_or_default().to_string(),
+                        ))
+                    }
+                };
+                Ok(Message::Query {
+                    transaction_id,
+                    id: to_node_id(Some(arguments.id.as_slice()))?,
+                    query,
+                })
+            }
+            MESSAGE_TYPE_RESPONSE => {
+                let response = raw.r.ok_or(Error::MalformedMessage)?;
+                let values = response
+                    .values
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|value| parse_compact_peers(value))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| Error::MalformedMessage)?;
+                Ok(Message::Response {
+                    transaction_id,
+                    response: Response {
+                        id: to_node_id(Some(response.id.as_slice()))?,
+                        nodes: decode_nodes(
+                            response
+                                .nodes
+                                .as_deref()
+                                .map(Vec::as_slice)
+                                .unwrap_or_default(),
+                        )?,
+                        values: values.into_iter().flatten().collect(),
+                        token: response.token.map(ByteBuf::into_vec),
+                    },
+                })
+            }
+            MESSAGE_TYPE_ERROR => {
+                let (code, message) = raw.e.ok_or(Error::MalformedMessage)?;
+                Ok(Message::Error {
+                    transaction_id,
+                    code,
+                    message,
+                })
+            }
+            _ => Err(Error::MalformedMessage),
+        }
+    }
+}
+
+fn to_node_id(bytes: Option<&[u8]>) -> Result<NodeId, Error> {
+    bytes
+        .and_then(|bytes| NodeId::try_from(bytes).ok())
+        .ok_or(Error::MalformedMessage)
+}
+
+/// Compact IPv4 peer address (4 bytes IP, 2 bytes port), IPv6 addresses can't be encoded.
+fn encode_peer(peer: &PeerAddress) -> Option<Vec<u8>> {
+    peer.ip().is_ipv4().then(|| peer.to_compact())
+}
+
+/// Encodes nodes to compact node info, nodes with IPv6 addresses are skipped.
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(nodes.len() * COMPACT_NODE_INFO_LENGTH);
+    for node in nodes {
+        if let Some(address) = encode_peer(&PeerAddress::from(node.address)) {
+            result.extend_from_slice(&node.id);
+            result.extend_from_slice(&address);
+        }
+    }
+    result
+}
+
+/// Decodes compact node info (https://www.bittorrent.org/beps/bep_0005.html#contact-encoding).
+pub fn decode_nodes(data: &[u8]) -> Result<Vec<NodeInfo>, Error> {
+    if !data.len().is_multiple_of(COMPACT_NODE_INFO_LENGTH) {
+        return Err(Error::MalformedMessage);
+    }
+    data.chunks(COMPACT_NODE_INFO_LENGTH)
+        .map(|chunk| {
+            let address = parse_compact_peers(&chunk[NODE_ID_LENGTH..])
+                .map_err(|_| Error::MalformedMessage)?[0];
+            Ok(NodeInfo {
+                id: to_node_id(Some(&chunk[..NODE_ID_LENGTH]))?,
+                address: address.socket_addr(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::dht::MAX_MESSAGE_SIZE;
+    use std::net::SocketAddr;
+
+    fn roundtrip(message: Message, expected: &[u8]) {
+        let bytes: Vec<u8> = message.clone().try_into().unwrap();
+        assert_eq!(bytes, expected);
+        assert_eq!(Message::try_from(bytes.as_slice()).unwrap(), message);
+    }
+
+    #[test]
+    fn encode_spec_examples() {
+        let id = *b"abcdefghij0123456789";
+        let transaction_id = b"aa".to_vec();
+        roundtrip(
+            Message::Query {
+                transaction_id: transaction_id.clone(),
+                id,
+                query: Query::Ping,
+            },
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+        );
+        roundtrip(
+            Message::Query {
+                transaction_id: transaction_id.clone(),
+                id,
+                query: Query::FindNode {
+                    target: *b"mnopqrstuvwxyz123456",
+                },
+            },
+            b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe",
+        );
+        roundtrip(
+            Message::Query {
+                transaction_id: transaction_id.clone(),
+                id,
+                query: Query::AnnouncePeer {
+                    info_hash: *b"mnopqrstuvwxyz123456",
+                    port: 6881,
+                    token: b"aoeusnth".to_vec(),
+                    implied_port: true,
+                },
+            },
+            b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+        );
+        roundtrip(
+            Message::Response {
+                transaction_id: transaction_id.clone(),
+                response: Response {
+                    id: *b"mnopqrstuvwxyz123456",
+                    ..Default::default()
+                },
+            },
+            b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re",
+        );
+        roundtrip(
+            Message::Response {
+                transaction_id: transaction_id.clone(),
+                response: Response {
+                    id,
+                    values: vec![PeerAddress::from(
+                        "97.120.106.101:11893".parse::<SocketAddr>().unwrap(),
+                    )],
+                    token: Some(b"aoeusnth".to_vec()),
+                    ..Default::default()
+                },
+            },
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.uee1:t2:aa1:y1:re",
+        );
+        roundtrip(
+            Message::Error {
+                transaction_id,
+                code: 201,
+                message: "A Generic Error Ocurred".to_string(),
+            },
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+        );
+    }
+
+    #[test]
+    fn compact_node_info_roundtrip() {
+        let nodes = vec![
+            NodeInfo {
+                id: [1; 20],
+                address: "127.0.0.1:6881".parse().unwrap(),
+            },
+            NodeInfo {
+                id: [2; 20],
+                address: "10.0.0.1:1".parse().unwrap(),
+            },
+        ];
+        let encoded = encode_nodes(&nodes);
+        assert_eq!(encoded.len(), 52);
+        assert_eq!(decode_nodes(&encoded).unwrap(), nodes);
+        assert!(decode_nodes(&encoded[1..]).is_err());
+    }
+
+    #[test]
+    fn reject_unknown_methods() {
+        let data = b"d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+        assert!(matches!(
+            Message::try_from(data.as_slice()),
+            Err(Error::UnknownMethod(transaction_id, method)) if transaction_id == b"aa" && method == "vote"
+        ));
+    }
+
+    #[test]
+    fn reject_deeply_nested_messages() {
+        // fits into a datagram, but would overflow the stack of the recursive deserializer
+        let mut data = b"d1:ad2:id20:abcdefghij01234567896:target".to_vec();
+        data.extend(vec![b'l'; 30_000]);
+        data.extend(vec![b'e'; 30_000]);
+        data.extend_from_slice(b"e1:q9:find_node1:t2:aa1:y1:qe");
+        assert!(data.len() < MAX_MESSAGE_SIZE);
+        assert!(matches!(
+            Message::try_from(data.as_slice()),
+            Err(Error::MalformedMessage)
+        ));
+    }
+}
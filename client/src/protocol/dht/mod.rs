@@ -0,0 +1,845 @@
+//! Mainline DHT (https://www.bittorrent.org/beps/bep_0005.html) used to find peers without trackers.
+pub mod krpc;
+pub mod routing_table;
+
+use crate::protocol::dht::krpc::{
+    Message, Query, Response, ERROR_CODE_GENERIC, ERROR_CODE_METHOD_UNKNOWN, ERROR_CODE_PROTOCOL,
+};
+use crate::protocol::dht::routing_table::{distance, RoutingTable, K};
+use crate::protocol::meta_info_file::Sha1HashBytes;
+use crate::protocol::tracker::PeerAddress;
+use log::debug;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Node IDs share the 160-bit space of info hashes.
+pub type NodeId = Sha1HashBytes;
+
+/// Number of parallel queries in a lookup (alpha in Kademlia).
+const ALPHA: usize = 3;
+
+/// Upper bound of the query rounds in a single lookup.
+const MAX_LOOKUP_ROUNDS: usize = 32;
+
+/// Tokens are derived from a secret which changes every 5 minutes, tokens of the previous secret
+/// are still accepted (https://www.bittorrent.org/beps/bep_0005.html#announce-peer).
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TOKEN_LENGTH: usize = 8;
+
+/// Announced peers are forgotten after 30 minutes unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Upper bounds of the announced peers stored for other nodes, so announces can't exhaust memory.
+/// Once a torrent has too many peers, the oldest announce is replaced.
+const MAX_STORED_TORRENTS: usize = 1000;
+const MAX_STORED_PEERS_PER_TORRENT: usize = 200;
+
+/// How often buckets are checked for a refresh (see [`RoutingTable::buckets_to_refresh`]).
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Upper bound of the pings sent to questionable nodes for a single new node.
+const MAX_QUESTIONABLE_PINGS: usize = 2 * K;
+
+/// Maximum number of peers returned for a single `get_peers` query.
+const MAX_PEERS_PER_RESPONSE: usize = 50;
+
+/// Largest KRPC message we are prepared to receive.
+const MAX_MESSAGE_SIZE: usize = 65536;
+
+/// DHT related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    IO(#[from] io::Error),
+    #[error("invalid bencoded KRPC message")]
+    InvalidMessage(#[from] serde_bencode::Error),
+    #[error("malformed KRPC message")]
+    MalformedMessage,
+    #[error("unknown KRPC method: {1}")]
+    UnknownMethod(Vec<u8>, String),
+    #[error("query timed out")]
+    Timeout,
+    #[error("remote node error {0}: {1}")]
+    Remote(i64, String),
+    #[error("no DHT nodes are reachable")]
+    NoNodes,
+}
+
+/// Contact information of a DHT node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+/// Configuration for [`DhtNode`].
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    bootstrap_nodes: Vec<String>,
+    query_timeout: Duration,
+    routing_table_path: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            query_timeout: Duration::from_secs(5),
+            routing_table_path: None,
+        }
+    }
+}
+
+impl DhtConfig {
+    /// Sets the `host:port` addresses of the nodes used to join the DHT.
+    pub fn with_bootstrap_nodes(mut self, bootstrap_nodes: Vec<String>) -> Self {
+        self.bootstrap_nodes = bootstrap_nodes;
+        self
+    }
+
+    pub fn with_query_timeout(mut self, query_timeout: Duration) -> Self {
+        self.query_timeout = query_timeout;
+        self
+    }
+
+    /// Sets the file the routing table is loaded from and saved to.
+    pub fn with_routing_table_path(mut self, routing_table_path: PathBuf) -> Self {
+        self.routing_table_path = Some(routing_table_path);
+        self
+    }
+}
+
+/// Secrets used to generate `get_peers` tokens.
+struct TokenSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+/// Result of an iterative lookup.
+struct Lookup {
+    peers: Vec<PeerAddress>,
+    /// Closest responding nodes with the tokens they sent.
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+}
+
+type PendingQuery = (SocketAddr, oneshot::Sender<Result<Response, Error>>);
+
+/// State shared between [`DhtNode`] and its receive task.
+struct Inner {
+    socket: UdpSocket,
+    config: DhtConfig,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_transaction_id: AtomicU16,
+    tokens: Mutex<TokenSecrets>,
+    peers: Mutex<HashMap<Sha1HashBytes, HashMap<PeerAddress, Instant>>>,
+    /// Questionable nodes currently pinged, see [`Inner::add_node`].
+    pinging: Mutex<HashSet<SocketAddr>>,
+}
+
+/// A node of the mainline DHT. It answers queries of other nodes in the background and finds
+/// peers of torrents with iterative lookups.
+pub struct DhtNode {
+    inner: Arc<Inner>,
+    receiver: JoinHandle<()>,
+    refresher: JoinHandle<()>,
+}
+
+impl Drop for DhtNode {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.refresher.abort();
+    }
+}
+
+impl DhtNode {
+    /// Binds a DHT node to `address`. The routing table (and the node ID) is restored from
+    /// the configured file if it exists, otherwise a random node ID is generated.
+    pub async fn bind(address: SocketAddr, config: DhtConfig) -> Result<Self, Error> {
+        let mut table = None;
+        if let Some(path) = &config.routing_table_path {
+            match tokio::fs::read(path).await {
+                Ok(data) => match RoutingTable::from_bytes(&data) {
+                    Ok(restored) => table = Some(restored),
+                    Err(error) => debug!("Invalid DHT routing table {:?}: {:?}", path, error),
+                },
+                Err(error) => debug!("Failed to read DHT routing table {:?}: {:?}", path, error),
+            }
+        }
+        let table = table.unwrap_or_else(|| RoutingTable::new(rand::random()));
+        let socket = UdpSocket::bind(address).await?;
+        let inner = Arc::new(Inner {
+            socket,
+            config,
+            table: Mutex::new(table),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction_id: AtomicU16::new(rand::random()),
+            tokens: Mutex::new(TokenSecrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::new(HashMap::new()),
+            pinging: Mutex::new(HashSet::new()),
+        });
+        let receiver = tokio::spawn(inner.clone().receive_loop());
+        let refresher = tokio::spawn(inner.clone().refresh_loop());
+        Ok(Self {
+            inner,
+            receiver,
+            refresher,
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.table.lock().unwrap().id()
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    /// Good nodes of the routing table.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.inner.table.lock().unwrap().nodes()
+    }
+
+    /// Joins the DHT: asks the bootstrap nodes (and the nodes of a restored routing table)
+    /// for the nodes closest to the local node ID. Returns the number of nodes in the routing table.
+    pub async fn bootstrap(&self) -> Result<usize, Error> {
+        let id = self.id();
+        let mut queries = JoinSet::new();
+        for bootstrap_node in &self.inner.config.bootstrap_nodes {
+            let addresses = match tokio::net::lookup_host(bootstrap_node.as_str()).await {
+                Ok(addresses) => addresses,
+                Err(error) => {
+                    debug!("Failed to resolve {0}: {1:?}", bootstrap_node, error);
+                    continue;
+                }
+            };
+            for address in addresses.filter(SocketAddr::is_ipv4) {
+                let inner = self.inner.clone();
+                queries.spawn(
+                    async move { inner.query(address, Query::FindNode { target: id }).await },
+                );
+            }
+        }
+        let mut seeds = vec![];
+        while let Some(result) = queries.join_next().await {
+            if let Ok(Ok(response)) = result {
+                seeds.extend(response.nodes);
+            }
+        }
+
+        self.inner.lookup(id, false, seeds).await;
+        let nodes = self.inner.table.lock().unwrap().len();
+        debug!("DHT bootstrapped with {0} nodes", nodes);
+        self.save_routing_table().await?;
+        if nodes == 0 {
+            return Err(Error::NoNodes);
+        }
+        Ok(nodes)
+    }
+
+    /// Checks whether the node at `address` is alive, returns its node ID.
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId, Error> {
+        Ok(self.inner.query(address, Query::Ping).await?.id)
+    }
+
+    /// Finds peers of a torrent with an iterative `get_peers` lookup.
+    pub async fn get_peers(&self, info_hash: Sha1HashBytes) -> Result<Vec<PeerAddress>, Error> {
+        if self.inner.table.lock().unwrap().is_empty() {
+            return Err(Error::NoNodes);
+        }
+        Ok(self.inner.lookup(info_hash, true, vec![]).await.peers)
+    }
+
+    /// Finds peers of a torrent and announces to the closest nodes that the local peer
+    /// downloads it on `port`. Returns the peers found during the lookup.
+    pub async fn announce(
+        &self,
+        info_hash: Sha1HashBytes,
+        port: u16,
+    ) -> Result<Vec<PeerAddress>, Error> {
+        if self.inner.table.lock().unwrap().is_empty() {
+            return Err(Error::NoNodes);
+        }
+        let lookup = self.inner.lookup(info_hash, true, vec![]).await;
+        let mut queries = JoinSet::new();
+        for (node, token) in lookup.closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let inner = self.inner.clone();
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port: false,
+            };
+            queries.spawn(async move { inner.query(node.address, query).await });
+        }
+        let mut announced = 0;
+        while let Some(result) = queries.join_next().await {
+            match result {
+                Ok(Ok(_)) => announced += 1,
+                Ok(Err(error)) => debug!("DHT announce error: {:?}", error),
+                Err(error) => debug!("DHT announce task error: {:?}", error),
+            }
+        }
+        debug!("Announced to {0} DHT nodes", announced);
+        Ok(lookup.peers)
+    }
+
+    /// Saves the routing table to the configured file, if there is one.
+    pub async fn save_routing_table(&self) -> Result<(), Error> {
+        let Some(path) = &self.inner.config.routing_table_path else {
+            return Ok(());
+        };
+        let data = self.inner.table.lock().unwrap().to_bytes()?;
+        tokio::fs::write(path, data).await?;
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn id(&self) -> NodeId {
+        self.table.lock().unwrap().id()
+    }
+
+    async fn send(&self, address: SocketAddr, message: Message) -> Result<(), Error> {
+        let data: Vec<u8> = message.try_into()?;
+        self.socket.send_to(&data, address).await?;
+        Ok(())
+    }
+
+    /// Sends a query and waits for the response, nodes not responding in time are marked as failed.
+    async fn query(&self, address: SocketAddr, query: Query) -> Result<Response, Error> {
+        let transaction_id = self
+            .next_transaction_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id.clone(), (address, sender));
+        let message = Message::Query {
+            transaction_id: transaction_id.clone(),
+            id: self.id(),
+            query,
+        };
+        if let Err(error) = self.send(address, message).await {
+            self.pending.lock().unwrap().remove(&transaction_id);
+            return Err(error);
+        }
+        match tokio::time::timeout(self.config.query_timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            _ => {
+                self.pending.lock().unwrap().remove(&transaction_id);
+                self.table.lock().unwrap().mark_failed(address);
+                Err(Error::Timeout)
+            }
+        }
+    }
+
+    /// Iterative lookup of the nodes closest to `target` (https://www.bittorrent.org/beps/bep_0005.html#routing-table).
+    /// In each round the closest not yet queried nodes are queried until the K closest nodes all responded
+    /// or failed. With `get_peers` set, peers of the info hash `target` are collected as well.
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        get_peers: bool,
+        seeds: Vec<NodeInfo>,
+    ) -> Lookup {
+        let id = self.id();
+        let mut candidates: BTreeMap<NodeId, NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .chain(seeds)
+            .filter(|node| node.id != id)
+            .map(|node| (distance(&node.id, &target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = vec![];
+        let mut known_peers = HashSet::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let round: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if round.is_empty() {
+                break;
+            }
+            let mut queries = JoinSet::new();
+            for node in round {
+                queried.insert(node.address);
+                let inner = self.clone();
+                let query = if get_peers {
+                    Query::GetPeers { info_hash: target }
+                } else {
+                    Query::FindNode { target }
+                };
+                queries.spawn(async move { (node, inner.query(node.address, query).await) });
+            }
+            while let Some(result) = queries.join_next().await {
+                let Ok((node, result)) = result else {
+                    continue;
+                };
+                let Ok(response) = result else {
+                    candidates.remove(&distance(&node.id, &target));
+                    continue;
+                };
+                // the node may have sent a different ID than we knew it by
+                candidates.remove(&distance(&node.id, &target));
+                let node = NodeInfo {
+                    id: response.id,
+                    address: node.address,
+                };
+                responded.insert(distance(&node.id, &target), (node, response.token));
+                for peer in response.values {
+                    if known_peers.insert(peer) {
+                        peers.push(peer);
+                    }
+                }
+                for next in response.nodes {
+                    if next.id != id && !queried.contains(&next.address) {
+                        candidates.insert(distance(&next.id, &target), next);
+                    }
+                }
+            }
+            // stop once no candidate is closer than the K closest responding nodes
+            if responded.len() >= K {
+                let furthest = responded.keys().nth(K - 1).copied();
+                if candidates
+                    .keys()
+                    .next()
+                    .is_none_or(|closest| Some(*closest) > furthest)
+                {
+                    break;
+                }
+            }
+        }
+
+        Lookup {
+            peers,
+            closest: responded.into_values().take(K).collect(),
+        }
+    }
+
+    /// Receives KRPC messages until the node is dropped: queries are answered, responses are
+    /// handed over to the pending queries.
+    async fn receive_loop(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    debug!("DHT receive error: {:?}", error);
+                    continue;
+                }
+            };
+            let reply = match Message::try_from(&buf[..size]) {
+                Ok(Message::Query {
+                    transaction_id,
+                    id,
+                    query,
+                }) => {
+                    self.add_node(NodeInfo { id, address: from });
+                    Some(self.handle_query(from, transaction_id, query))
+                }
+                Ok(Message::Response {
+                    transaction_id,
+                    response,
+                }) => {
+                    if let Some(sender) = self.take_pending(&transaction_id, from) {
+                        self.add_node(NodeInfo {
+                            id: response.id,
+                            address: from,
+                        });
+                        let _ = sender.send(Ok(response));
+                    }
+                    None
+                }
+                Ok(Message::Error {
+                    transaction_id,
+                    code,
+                    message,
+                }) => {
+                    if let Some(sender) = self.take_pending(&transaction_id, from) {
+                        let _ = sender.send(Err(Error::Remote(code, message)));
+                    }
+                    None
+                }
+                Err(Error::UnknownMethod(transaction_id, method)) => Some(Message::Error {
+                    transaction_id,
+                    code: ERROR_CODE_METHOD_UNKNOWN,
+                    message: format!("Method Unknown: {method}"),
+                }),
+                Err(error) => {
+                    debug!("[{0}] invalid KRPC message: {1:?}", from, error);
+                    None
+                }
+            };
+            if let Some(reply) = reply {
+                if let Err(error) = self.send(from, reply).await {
+                    debug!("[{0}] failed to send KRPC reply: {1:?}", from, error);
+                }
+            }
+        }
+    }
+
+    /// Adds a node seen alive to the routing table. If its bucket is full of good nodes, questionable
+    /// nodes of the bucket are pinged in the background (see [`Self::replace_questionable`]).
+    fn add_node(self: &Arc<Self>, info: NodeInfo) {
+        let mut table = self.table.lock().unwrap();
+        if !table.insert(info) && table.questionable_node(&info.id, Instant::now()).is_some() {
+            tokio::spawn(self.clone().replace_questionable(info));
+        }
+    }
+
+    /// Pings the least recently seen questionable nodes of the full bucket of `candidate`, until one of them
+    /// fails to respond and `candidate` takes its place or all nodes of the bucket are good
+    /// (https://www.bittorrent.org/beps/bep_0005.html#routing-table).
+    async fn replace_questionable(self: Arc<Self>, candidate: NodeInfo) {
+        for _ in 0..MAX_QUESTIONABLE_PINGS {
+            let questionable = self
+                .table
+                .lock()
+                .unwrap()
+                .questionable_node(&candidate.id, Instant::now());
+            let Some(questionable) = questionable else {
+                return;
+            };
+            // another new node of the bucket waits for the same node already
+            if !self.pinging.lock().unwrap().insert(questionable.address) {
+                return;
+            }
+            let result = self.query(questionable.address, Query::Ping).await;
+            self.pinging.lock().unwrap().remove(&questionable.address);
+            let mut table = self.table.lock().unwrap();
+            match result {
+                Ok(_) => continue,
+                // queries timing out are marked as failed already
+                Err(Error::Timeout) => {}
+                Err(_) => table.mark_failed(questionable.address),
+            }
+            if table.insert(candidate) {
+                debug!(
+                    "[{0}] replaced unresponsive DHT node {1}",
+                    candidate.address, questionable.address
+                );
+                return;
+            }
+        }
+    }
+
+    /// Refreshes buckets which didn't change for 15 minutes with a lookup of a random ID in their range,
+    /// so the routing table of a long running node stays up to date.
+    async fn refresh_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REFRESH_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let targets = self
+                .table
+                .lock()
+                .unwrap()
+                .buckets_to_refresh(Instant::now());
+            for target in targets {
+                self.lookup(target, false, vec![]).await;
+            }
+        }
+    }
+
+    /// Removes the pending query of a response, if it came from the queried address.
+    fn take_pending(
+        &self,
+        transaction_id: &[u8],
+        from: SocketAddr,
+    ) -> Option<oneshot::Sender<Result<Response, Error>>> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(transaction_id)?.0 != from {
+            return None;
+        }
+        pending.remove(transaction_id).map(|(_, sender)| sender)
+    }
+
+    /// Answers a query of another node (https://www.bittorrent.org/beps/bep_0005.html#dht-queries).
+    fn handle_query(&self, from: SocketAddr, transaction_id: Vec<u8>, query: Query) -> Message {
+        let mut response = Response {
+            id: self.id(),
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = self.table.lock().unwrap().closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                response.nodes = self.table.lock().unwrap().closest(&info_hash, K);
+                response.values = self.stored_peers(&info_hash);
+                response.token = Some(self.token(from.ip()));
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if !self.is_valid_token(from.ip(), &token) {
+                    return Message::Error {
+                        transaction_id,
+                        code: ERROR_CODE_PROTOCOL,
+                        message: "Bad Token".to_string(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Message::Error {
+                        transaction_id,
+                        code: ERROR_CODE_GENERIC,
+                        message: "Invalid Port".to_string(),
+                    };
+                }
+                self.store_peer(info_hash, PeerAddress::new(from.ip(), port));
+            }
+        }
+        Message::Response {
+            transaction_id,
+            response,
+        }
+    }
+
+    /// Stores a peer announced for `info_hash`. Announces of new torrents are dropped while
+    /// [`MAX_STORED_TORRENTS`] torrents have peers which did not expire yet.
+    fn store_peer(&self, info_hash: Sha1HashBytes, peer: PeerAddress) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_STORED_TORRENTS {
+            peers.retain(|_, announced| {
+                announced.retain(|_, announced_at| announced_at.elapsed() < PEER_TTL);
+                !announced.is_empty()
+            });
+            if peers.len() >= MAX_STORED_TORRENTS {
+                return;
+            }
+        }
+        let announced = peers.entry(info_hash).or_default();
+        announced.retain(|_, announced_at| announced_at.elapsed() < PEER_TTL);
+        if !announced.contains_key(&peer) && announced.len() >= MAX_STORED_PEERS_PER_TORRENT {
+            let oldest = announced
+                .iter()
+                .min_by_key(|(_, announced_at)| **announced_at)
+                .map(|(peer, _)| *peer);
+            if let Some(oldest) = oldest {
+                announced.remove(&oldest);
+            }
+        }
+        announced.insert(peer, Instant::now());
+    }
+
+    /// Peers announced for `info_hash` which did not expire yet.
+    fn stored_peers(&self, info_hash: &Sha1HashBytes) -> Vec<PeerAddress> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(announced) = peers.get_mut(info_hash) else {
+            return vec![];
+        };
+        announced.retain(|_, announced_at| announced_at.elapsed() < PEER_TTL);
+        let stored = announced
+            .keys()
+            .take(MAX_PEERS_PER_RESPONSE)
+            .copied()
+            .collect();
+        if announced.is_empty() {
+            peers.remove(info_hash);
+        }
+        stored
+    }
+
+    /// Rotates the token secrets if the current one is too old.
+    fn token_secrets(&self) -> std::sync::MutexGuard<'_, TokenSecrets> {
+        let mut secrets = self.tokens.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_ROTATION_INTERVAL {
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+            secrets.rotated = Instant::now();
+        }
+        secrets
+    }
+
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        token_for(&self.token_secrets().current, ip)
+    }
+
+    fn is_valid_token(&self, ip: IpAddr, token: &[u8]) -> bool {
+        let secrets = self.token_secrets();
+        token == token_for(&secrets.current, ip) || token == token_for(&secrets.previous, ip)
+    }
+}
+
+/// Token of an IP address: truncated SHA-1 hash of the secret and the address.
+fn token_for(secret: &[u8; 16], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..TOKEN_LENGTH].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn test_config() -> DhtConfig {
+        DhtConfig::default()
+            .with_bootstrap_nodes(vec![])
+            .with_query_timeout(Duration::from_millis(500))
+    }
+
+    /// Starts a swarm of DHT nodes on loopback, all bootstrapped from the first node.
+    async fn start_swarm(size: usize) -> Vec<DhtNode> {
+        let first = DhtNode::bind("127.0.0.1:0".parse().unwrap(), test_config())
+            .await
+            .unwrap();
+        let bootstrap = first.local_addr().unwrap().to_string();
+        let mut nodes = vec![first];
+        for _ in 1..size {
+            let config = test_config().with_bootstrap_nodes(vec![bootstrap.clone()]);
+            let node = DhtNode::bind("127.0.0.1:0".parse().unwrap(), config)
+                .await
+                .unwrap();
+            node.bootstrap().await.unwrap();
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn ping_and_unknown_nodes() {
+        let nodes = start_swarm(2).await;
+        let address = nodes[0].local_addr().unwrap();
+        assert_eq!(nodes[1].ping(address).await.unwrap(), nodes[0].id());
+
+        // nothing listens on port 1
+        let config = test_config().with_query_timeout(Duration::from_millis(50));
+        let lonely = DhtNode::bind("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        assert!(matches!(
+            lonely.ping("127.0.0.1:1".parse().unwrap()).await,
+            Err(Error::Timeout)
+        ));
+        assert!(matches!(lonely.bootstrap().await, Err(Error::NoNodes)));
+        assert!(matches!(
+            lonely.get_peers([0; 20]).await,
+            Err(Error::NoNodes)
+        ));
+    }
+
+    #[tokio::test]
+    async fn announce_and_find_peers_in_swarm() {
+        let nodes = start_swarm(12).await;
+        assert!(nodes.iter().all(|node| !node.nodes().is_empty()));
+
+        let info_hash = [0x42; 20];
+        assert!(nodes[3].announce(info_hash, 7000).await.unwrap().is_empty());
+        nodes[5].announce(info_hash, 7001).await.unwrap();
+
+        let mut peers: Vec<String> = nodes[11]
+            .get_peers(info_hash)
+            .await
+            .unwrap()
+            .iter()
+            .map(|peer| peer.to_string())
+            .collect();
+        peers.sort();
+        assert_eq!(peers, vec!["127.0.0.1:7000", "127.0.0.1:7001"]);
+        assert!(nodes[11].get_peers([0x43; 20]).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reject_announce_with_invalid_token() {
+        let nodes = start_swarm(2).await;
+        let address = nodes[0].local_addr().unwrap();
+        let query = Query::AnnouncePeer {
+            info_hash: [1; 20],
+            port: 7000,
+            token: b"invalid!".to_vec(),
+            implied_port: false,
+        };
+        assert!(matches!(
+            nodes[1].inner.query(address, query).await,
+            Err(Error::Remote(ERROR_CODE_PROTOCOL, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn limit_stored_peers() {
+        let nodes = start_swarm(1).await;
+        let inner = &nodes[0].inner;
+        for port in 1..=MAX_STORED_PEERS_PER_TORRENT as u16 + 10 {
+            inner.store_peer([1; 20], PeerAddress::new([127, 0, 0, 1].into(), port));
+        }
+        let peers = inner.peers.lock().unwrap()[&[1; 20]].clone();
+        assert_eq!(peers.len(), MAX_STORED_PEERS_PER_TORRENT);
+        // the oldest announces were replaced
+        assert!(!peers.contains_key(&PeerAddress::new([127, 0, 0, 1].into(), 1)));
+
+        for index in 0..MAX_STORED_TORRENTS * 2 {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&(index as u64).to_be_bytes());
+            inner.store_peer(info_hash, PeerAddress::new([127, 0, 0, 1].into(), 7000));
+        }
+        assert_eq!(inner.peers.lock().unwrap().len(), MAX_STORED_TORRENTS);
+        assert_eq!(inner.stored_peers(&[1; 20]).len(), MAX_PEERS_PER_RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn restore_routing_table_from_file() {
+        let nodes = start_swarm(3).await;
+        let dir = TempDir::new("dht");
+        let config = test_config().with_routing_table_path(dir.join("dht.dat"));
+        let node = DhtNode::bind("127.0.0.1:0".parse().unwrap(), config.clone())
+            .await
+            .unwrap();
+        let first = nodes[0].local_addr().unwrap();
+        node.ping(first).await.unwrap();
+        node.save_routing_table().await.unwrap();
+        let id = node.id();
+        drop(node);
+
+        let restored = DhtNode::bind("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        assert_eq!(restored.id(), id);
+        assert_eq!(restored.nodes()[0].address, first);
+        // restored nodes are used to bootstrap without any bootstrap node
+        assert!(restored.bootstrap().await.unwrap() >= 2);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::protocol::dht::krpc::{decode_nodes, encode_nodes};
+use crate::protocol::dht::{Error, NodeId, NodeInfo};
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of nodes in a bucket (https://www.bittorrent.org/beps/bep_0005.html#routing-table).
+pub const K: usize = 8;
+
+/// Number of buckets, one for each possible length of the common prefix with the local node ID.
+const BUCKETS_COUNT: usize = 160;
+
+/// Nodes failing to respond to this many queries in a row are bad and get replaced by new nodes.
+const MAX_FAILED_QUERIES: u32 = 2;
+
+/// Nodes not seen for 15 minutes are questionable, buckets not changed for 15 minutes are refreshed
+/// (https://www.bittorrent.org/beps/bep_0005.html#routing-table).
+pub const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Returns the XOR distance of two node IDs, distances compare like unsigned integers.
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut result = [0; 20];
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    result
+}
+
+/// Length of the common prefix of two node IDs in bits.
+fn common_prefix_length(a: &NodeId, b: &NodeId) -> usize {
+    let distance = distance(a, b);
+    distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map(|index| index * 8 + distance[index].leading_zeros() as usize)
+        .unwrap_or(BUCKETS_COUNT)
+}
+
+/// A node in the routing table.
+#[derive(Debug, Clone)]
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    failed_queries: u32,
+}
+
+impl Node {
+    fn is_bad(&self) -> bool {
+        self.failed_queries >= MAX_FAILED_QUERIES
+    }
+
+    /// Nodes not seen for a while must be pinged before they can be replaced.
+    fn is_questionable(&self, now: Instant) -> bool {
+        !self.is_bad() && now.saturating_duration_since(self.last_seen) >= QUESTIONABLE_AFTER
+    }
+}
+
+/// Bencoded form of the routing table used for persistence.
+#[derive(Serialize, Deserialize)]
+struct RawRoutingTable {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+/// Kademlia routing table (https://www.bittorrent.org/beps/bep_0005.html#routing-table).
+/// Nodes are kept in K-buckets by the length of their common ID prefix with the local node,
+/// so the table knows many nodes close to the local node and only a few far away.
+#[derive(Debug)]
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+    /// When each bucket last got a new node, a node refreshed or a refresh lookup.
+    changed: Vec<Instant>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![vec![]; BUCKETS_COUNT],
+            changed: vec![Instant::now(); BUCKETS_COUNT],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a node which has been seen alive or refreshes it if it is already in the table.
+    /// Full buckets only accept new nodes in place of bad ones, see [`Self::questionable_node`].
+    /// Returns whether the node is in the table.
+    pub fn insert(&mut self, info: NodeInfo) -> bool {
+        if info.id == self.id {
+            return false;
+        }
+        let index = common_prefix_length(&self.id, &info.id);
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|node| node.info.id == info.id) {
+            // move to the end, buckets are ordered by last seen
+            let mut node = bucket.remove(position);
+            node.info.address = info.address;
+            node.last_seen = Instant::now();
+            node.failed_queries = 0;
+            bucket.push(node);
+            self.changed[index] = Instant::now();
+            return true;
+        }
+        if bucket.len() >= K {
+            let Some(bad) = bucket.iter().position(Node::is_bad) else {
+                return false;
+            };
+            bucket.remove(bad);
+        }
+        bucket.push(Node {
+            info,
+            last_seen: Instant::now(),
+            failed_queries: 0,
+        });
+        self.changed[index] = Instant::now();
+        true
+    }
+
+    /// The least recently seen questionable node of the full bucket a node with `id` belongs into.
+    /// It has to be pinged: if it doesn't respond, it becomes bad and the new node takes its place.
+    pub fn questionable_node(&self, id: &NodeId, now: Instant) -> Option<NodeInfo> {
+        let bucket = &self.buckets[common_prefix_length(&self.id, id).min(BUCKETS_COUNT - 1)];
+        if bucket.len() < K {
+            return None;
+        }
+        bucket
+            .iter()
+            .find(|node| node.is_questionable(now))
+            .map(|node| node.info)
+    }
+
+    /// Returns a random target ID for each bucket with nodes which didn't change for
+    /// [`QUESTIONABLE_AFTER`], a `find_node` lookup of the target refreshes the bucket.
+    /// The buckets count as refreshed from now on.
+    pub fn buckets_to_refresh(&mut self, now: Instant) -> Vec<NodeId> {
+        let mut targets = vec![];
+        for index in 0..BUCKETS_COUNT {
+            if self.buckets[index].is_empty()
+                || now.saturating_duration_since(self.changed[index]) < QUESTIONABLE_AFTER
+            {
+                continue;
+            }
+            self.changed[index] = now;
+            targets.push(self.random_id_in_bucket(index));
+        }
+        targets
+    }
+
+    /// Random node ID sharing exactly `index` leading bits with the local node ID.
+    fn random_id_in_bucket(&self, index: usize) -> NodeId {
+        let mut id: NodeId = rand::random();
+        for bit in 0..=index {
+            let mask = 0x80 >> (bit % 8);
+            let local = self.id[bit / 8] & mask;
+            let value = if bit == index { local ^ mask } else { local };
+            id[bit / 8] = (id[bit / 8] & !mask) | value;
+        }
+        id
+    }
+
+    /// Records a query the node at `address` did not respond to.
+    pub fn mark_failed(&mut self, address: SocketAddr) {
+        for node in self.buckets.iter_mut().flatten() {
+            if node.info.address == address {
+                node.failed_queries += 1;
+            }
+        }
+    }
+
+    /// Returns up to `count` good nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| !node.is_bad())
+            .map(|node| node.info)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// All good nodes of the table.
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.closest(&self.id, usize::MAX)
+    }
+
+    /// Serializes the local node ID and all good nodes, so a restarted node doesn't need to bootstrap from scratch.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let raw = RawRoutingTable {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: ByteBuf::from(encode_nodes(&self.nodes())),
+        };
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+
+    /// Restores a routing table serialized by [`Self::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let raw: RawRoutingTable = serde_bencode::from_bytes(data)?;
+        let id = NodeId::try_from(raw.id.as_slice()).map_err(|_| Error::MalformedMessage)?;
+        let mut table = Self::new(id);
+        for node in decode_nodes(raw.nodes.as_slice())? {
+            table.insert(node);
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    fn id_with_prefix(first_byte: u8, last_byte: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first_byte;
+        id[19] = last_byte;
+        id
+    }
+
+    #[test]
+    fn buckets_by_common_prefix() {
+        let local = [0; 20];
+        assert_eq!(common_prefix_length(&local, &id_with_prefix(0x80, 0)), 0);
+        assert_eq!(common_prefix_length(&local, &id_with_prefix(0x01, 0)), 7);
+        assert_eq!(common_prefix_length(&local, &id_with_prefix(0, 1)), 159);
+        assert_eq!(common_prefix_length(&local, &local), 160);
+    }
+
+    #[test]
+    fn full_buckets_only_replace_bad_nodes() {
+        let mut table = RoutingTable::new([0; 20]);
+        // all nodes share the first bucket (highest bit set)
+        for i in 0..K as u8 {
+            assert!(table.insert(node(id_with_prefix(0x80, i), 1000 + i as u16)));
+        }
+        assert!(!table.insert(node(id_with_prefix(0x80, 100), 2000)));
+        assert!(!table.insert(NodeInfo {
+            id: [0; 20],
+            address: SocketAddr::from(([127, 0, 0, 1], 1)),
+        }));
+
+        table.mark_failed(SocketAddr::from(([127, 0, 0, 1], 1000)));
+        assert!(!table.insert(node(id_with_prefix(0x80, 100), 2000)));
+        table.mark_failed(SocketAddr::from(([127, 0, 0, 1], 1000)));
+        assert!(table.insert(node(id_with_prefix(0x80, 100), 2000)));
+        assert_eq!(table.len(), K);
+        assert!(table
+            .nodes()
+            .iter()
+            .all(|node| node.id != id_with_prefix(0x80, 0)));
+    }
+
+    #[test]
+    fn ping_questionable_nodes_of_full_buckets() {
+        let mut table = RoutingTable::new([0; 20]);
+        for i in 0..K as u8 {
+            table.insert(node(id_with_prefix(0x80, i), 1000 + i as u16));
+        }
+        let candidate = id_with_prefix(0x80, 100);
+        let now = Instant::now();
+        assert_eq!(table.questionable_node(&candidate, now), None);
+
+        let later = now + QUESTIONABLE_AFTER;
+        assert_eq!(
+            table.questionable_node(&candidate, later).unwrap().id,
+            id_with_prefix(0x80, 0)
+        );
+        // seen again, the next least recently seen node is questionable
+        table.insert(node(id_with_prefix(0x80, 0), 1000));
+        assert_eq!(
+            table.questionable_node(&candidate, later).unwrap().id,
+            id_with_prefix(0x80, 1)
+        );
+        // buckets with space don't need pings
+        assert_eq!(
+            table.questionable_node(&id_with_prefix(0x40, 0), later),
+            None
+        );
+    }
+
+    #[test]
+    fn refresh_unchanged_buckets() {
+        let mut table = RoutingTable::new([0x5a; 20]);
+        let mut id = [0x5a; 20];
+        id[0] ^= 0x01;
+        table.insert(node(id, 1));
+        let now = Instant::now();
+        assert!(table.buckets_to_refresh(now).is_empty());
+
+        let later = now + QUESTIONABLE_AFTER;
+        let targets = table.buckets_to_refresh(later);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(common_prefix_length(&table.id(), &targets[0]), 7);
+        // refreshed buckets wait another 15 minutes
+        assert!(table.buckets_to_refresh(later).is_empty());
+    }
+
+    #[test]
+    fn closest_nodes_by_xor_distance() {
+        let mut table = RoutingTable::new([0; 20]);
+        for (i, first_byte) in [0x80, 0x40, 0x20, 0x10, 0x08].into_iter().enumerate() {
+            table.insert(node(id_with_prefix(first_byte, 0), i as u16));
+        }
+        let closest: Vec<NodeId> = table
+            .closest(&id_with_prefix(0x30, 0), 3)
+            .iter()
+            .map(|node| node.id)
+            .collect();
+        assert_eq!(
+            closest,
+            vec![
+                id_with_prefix(0x20, 0),
+                id_with_prefix(0x10, 0),
+                id_with_prefix(0x08, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn persist_routing_table() {
+        let mut table = RoutingTable::new([7; 20]);
+        table.insert(node([1; 20], 1));
+        table.insert(node([2; 20], 2));
+        let restored = RoutingTable::from_bytes(&table.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.id(), [7; 20]);
+        assert_eq!(restored.nodes(), table.nodes());
+    }
+}
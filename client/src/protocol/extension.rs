@@ -0,0 +1,89 @@
+use crate::protocol::meta_info_file::check_bencode_depth;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+/// Extended message ID of the extended handshake (https://www.bittorrent.org/beps/bep_0010.html).
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Client name and version advertised in the extended handshake.
+pub const CLIENT_NAME: &str = concat!("bittorrent-client-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Number of outstanding block requests we accept from a peer, advertised as `reqq`.
+pub const DEFAULT_REQUEST_QUEUE_LENGTH: i64 = 250;
+
+/// Extended handshake message sent right after the handshake by peers supporting the extension protocol
+/// (https://www.bittorrent.org/beps/bep_0010.html#handshake-message).
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ExtendedHandshake {
+    /// Supported extensions mapped to the extended message ID the sender expects for them,
+    /// 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// Local TCP listen port of the sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    /// Compact IP address (4 or 16 bytes) of the receiver as the sender sees it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// Number of outstanding requests the sender supports without dropping any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// Size of the info dictionary in bytes (https://www.bittorrent.org/beps/bep_0009.html#extension-header).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl Default for ExtendedHandshake {
+    fn default() -> Self {
+        Self {
+            m: BTreeMap::new(),
+            p: None,
+            v: Some(ByteBuf::from(CLIENT_NAME.as_bytes())),
+            yourip: None,
+            reqq: Some(DEFAULT_REQUEST_QUEUE_LENGTH),
+            metadata_size: None,
+        }
+    }
+}
+
+impl ExtendedHandshake {
+    /// Returns the extended message ID of the given extension, if it is enabled.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != EXTENDED_HANDSHAKE_ID)
+    }
+
+    /// Client name and version of the sender.
+    pub fn client_name(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|v| String::from_utf8_lossy(v.as_ref()).to_string())
+    }
+
+    /// Our IP address as the sender sees it.
+    pub fn your_ip(&self) -> Option<IpAddr> {
+        let yourip = self.yourip.as_ref()?;
+        match yourip.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(yourip.as_slice()).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(yourip.as_slice()).ok()?)),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, serde_bencode::Error> {
+        serde_bencode::to_bytes(self)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, serde_bencode::Error> {
+        check_bencode_depth(data)
+            .map_err(|error| serde_bencode::Error::Custom(error.to_string()))?;
+        serde_bencode::from_bytes(data)
+    }
+}
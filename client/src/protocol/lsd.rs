@@ -0,0 +1,404 @@
+use crate::protocol::meta_info_file::Sha1HashBytes;
+use crate::protocol::tracker::PeerAddress;
+use log::debug;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Port of the local service discovery multicast groups (https://www.bittorrent.org/beps/bep_0014.html).
+pub const LSD_PORT: u16 = 6771;
+
+/// IPv4 multicast group of local service discovery.
+pub const LSD_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+
+/// IPv6 (organization-local) multicast group of local service discovery.
+pub const LSD_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// Start line of announce messages.
+const ANNOUNCE_START_LINE: &str = "BT-SEARCH * HTTP/1.1";
+
+/// Length of the random cookie identifying our own announces.
+const COOKIE_LENGTH: usize = 16;
+
+/// Largest announce we are prepared to receive.
+const MAX_MESSAGE_SIZE: usize = 1500;
+
+/// Local service discovery related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    IO(#[from] io::Error),
+    #[error("malformed LSD announce")]
+    MalformedMessage,
+}
+
+/// Announce multicast into the local network: the sender downloads the torrents
+/// of `info_hashes` and accepts connections on `port` (https://www.bittorrent.org/beps/bep_0014.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<Sha1HashBytes>,
+    /// Random value the sender can recognize its own announces by, if any.
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    /// Serializes the announce for the multicast group it is sent to.
+    pub fn to_bytes(&self, group: SocketAddr) -> Vec<u8> {
+        let mut message = format!(
+            "{ANNOUNCE_START_LINE}\r\nHost: {0}\r\nPort: {1}\r\n",
+            group, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {0}\r\n", hex::encode(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {0}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses an announce, header names are case-insensitive and unknown headers are ignored.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let message = std::str::from_utf8(data).map_err(|_| Error::MalformedMessage)?;
+        let mut lines = message.lines();
+        if lines.next().map(str::trim) != Some(ANNOUNCE_START_LINE) {
+            return Err(Error::MalformedMessage);
+        }
+        let mut port = None;
+        let mut info_hashes = vec![];
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or(Error::MalformedMessage)?;
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse().map_err(|_| Error::MalformedMessage)?),
+                "infohash" => info_hashes.push(
+                    hex::decode(value)
+                        .ok()
+                        .and_then(|info_hash| Sha1HashBytes::try_from(info_hash).ok())
+                        .ok_or(Error::MalformedMessage)?,
+                ),
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        match port {
+            Some(port) if port != 0 && !info_hashes.is_empty() => Ok(Self {
+                port,
+                info_hashes,
+                cookie,
+            }),
+            _ => Err(Error::MalformedMessage),
+        }
+    }
+}
+
+/// Configuration for [`LocalServiceDiscovery`].
+#[derive(Debug, Clone)]
+pub struct LsdConfig {
+    multicast_port: u16,
+    announce_interval: Duration,
+    ipv6: bool,
+}
+
+impl Default for LsdConfig {
+    fn default() -> Self {
+        Self {
+            multicast_port: LSD_PORT,
+            announce_interval: Duration::from_secs(5 * 60),
+            ipv6: true,
+        }
+    }
+}
+
+impl LsdConfig {
+    /// Sets the port of the multicast groups, other clients only listen on [`LSD_PORT`].
+    pub fn with_multicast_port(mut self, multicast_port: u16) -> Self {
+        self.multicast_port = multicast_port;
+        self
+    }
+
+    pub fn with_announce_interval(mut self, announce_interval: Duration) -> Self {
+        self.announce_interval = announce_interval;
+        self
+    }
+
+    /// Whether the IPv6 multicast group is joined as well.
+    pub fn with_ipv6(mut self, ipv6: bool) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+}
+
+/// State shared between [`LocalServiceDiscovery`] and its background tasks.
+struct Inner {
+    /// Sockets joined to the multicast groups, paired with their group address.
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    port: u16,
+    cookie: String,
+    torrents: Mutex<HashMap<Sha1HashBytes, mpsc::Sender<Vec<PeerAddress>>>>,
+}
+
+/// Local service discovery: announces torrents to the local network via multicast and
+/// listens for the announces of other peers in the network (https://www.bittorrent.org/beps/bep_0014.html).
+pub struct LocalServiceDiscovery {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for LocalServiceDiscovery {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// Binds a socket to the multicast port shared with other clients on the same host and joins `group`.
+fn join_group(group: IpAddr, port: u16) -> Result<UdpSocket, Error> {
+    let (domain, address) = match group {
+        IpAddr::V4(_) => (
+            Domain::IPV4,
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+        ),
+        IpAddr::V6(_) => (
+            Domain::IPV6,
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        ),
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    match group {
+        IpAddr::V4(group) => {
+            socket.bind(&address.into())?;
+            socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+            // other clients may run on the same host
+            socket.set_multicast_loop_v4(true)?;
+        }
+        IpAddr::V6(group) => {
+            socket.set_only_v6(true)?;
+            socket.bind(&address.into())?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(true)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+impl LocalServiceDiscovery {
+    /// Joins the multicast groups, `port` is the TCP port announced to other peers.
+    /// Failing to join the IPv6 group is not an error, as long as the IPv4 group is joined.
+    pub async fn bind(port: u16, config: LsdConfig) -> Result<Self, Error> {
+        let mut sockets = vec![(
+            join_group(IpAddr::V4(LSD_IPV4_GROUP), config.multicast_port)?,
+            SocketAddr::from((LSD_IPV4_GROUP, config.multicast_port)),
+        )];
+        if config.ipv6 {
+            match join_group(IpAddr::V6(LSD_IPV6_GROUP), config.multicast_port) {
+                Ok(socket) => sockets.push((
+                    socket,
+                    SocketAddr::from((LSD_IPV6_GROUP, config.multicast_port)),
+                )),
+                Err(error) => debug!("Failed to join LSD IPv6 group: {:?}", error),
+            }
+        }
+        let cookie = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(COOKIE_LENGTH)
+            .map(char::from)
+            .collect();
+        let inner = Arc::new(Inner {
+            sockets,
+            port,
+            cookie,
+            torrents: Mutex::new(HashMap::new()),
+        });
+        let mut tasks: Vec<JoinHandle<()>> = (0..inner.sockets.len())
+            .map(|index| tokio::spawn(inner.clone().receive_loop(index)))
+            .collect();
+        tasks.push(tokio::spawn(
+            inner.clone().announce_loop(config.announce_interval),
+        ));
+        Ok(Self { inner, tasks })
+    }
+
+    /// Announces the torrent right away and periodically later on, peers announcing it
+    /// in the local network are sent to `peers_tx`.
+    pub async fn add_torrent(
+        &self,
+        info_hash: Sha1HashBytes,
+        peers_tx: mpsc::Sender<Vec<PeerAddress>>,
+    ) -> Result<(), Error> {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, peers_tx);
+        self.inner.announce(vec![info_hash]).await
+    }
+
+    /// Stops announcing the torrent and looking for its peers.
+    pub fn remove_torrent(&self, info_hash: &Sha1HashBytes) {
+        self.inner.torrents.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Inner {
+    /// Sends an announce of `info_hashes` to all joined groups.
+    async fn announce(&self, info_hashes: Vec<Sha1HashBytes>) -> Result<(), Error> {
+        let announce = LsdAnnounce {
+            port: self.port,
+            info_hashes,
+            cookie: Some(self.cookie.clone()),
+        };
+        let mut result = Ok(());
+        for (socket, group) in &self.sockets {
+            if let Err(error) = socket.send_to(&announce.to_bytes(*group), group).await {
+                debug!("[{0}] LSD announce failed: {1:?}", group, error);
+                result = Err(error.into());
+            }
+        }
+        result
+    }
+
+    /// Re-announces all torrents every `interval`.
+    async fn announce_loop(self: Arc<Self>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let info_hashes: Vec<Sha1HashBytes> =
+                self.torrents.lock().unwrap().keys().copied().collect();
+            if !info_hashes.is_empty() {
+                let _ = self.announce(info_hashes).await;
+            }
+        }
+    }
+
+    /// Receives announces of the socket at `index` and hands over the peers of our torrents.
+    async fn receive_loop(self: Arc<Self>, index: usize) {
+        let socket = &self.sockets[index].0;
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let (length, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    debug!("LSD receive error: {:?}", error);
+                    continue;
+                }
+            };
+            let announce = match LsdAnnounce::from_bytes(&buf[..length]) {
+                Ok(announce) => announce,
+                Err(error) => {
+                    debug!("[{0}] invalid LSD announce: {1:?}", from, error);
+                    continue;
+                }
+            };
+            if announce.cookie.as_ref() == Some(&self.cookie) {
+                continue;
+            }
+            let peer = PeerAddress::new(from.ip(), announce.port);
+            let torrents = self.torrents.lock().unwrap();
+            for info_hash in &announce.info_hashes {
+                if let Some(peers_tx) = torrents.get(info_hash) {
+                    debug!("[{0}] LSD peer of {1}", peer, hex::encode(info_hash));
+                    let _ = peers_tx.try_send(vec![peer]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_roundtrip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some("cookie123".to_string()),
+        };
+        let bytes = announce.to_bytes(SocketAddr::from((LSD_IPV4_GROUP, LSD_PORT)));
+        assert_eq!(
+            String::from_utf8(bytes.clone()).unwrap(),
+            "BT-SEARCH * HTTP/1.1\r\n\
+             Host: 239.192.152.143:6771\r\n\
+             Port: 6881\r\n\
+             Infohash: abababababababababababababababababababab\r\n\
+             Infohash: 0101010101010101010101010101010101010101\r\n\
+             cookie: cookie123\r\n\
+             \r\n\r\n"
+        );
+        assert_eq!(LsdAnnounce::from_bytes(&bytes).unwrap(), announce);
+        assert!(
+            String::from_utf8(announce.to_bytes(SocketAddr::from((LSD_IPV6_GROUP, LSD_PORT))))
+                .unwrap()
+                .contains("Host: [ff15::efc0:988f]:6771\r\n")
+        );
+    }
+
+    #[test]
+    fn parse_foreign_announces() {
+        let announce = LsdAnnounce::from_bytes(
+            b"BT-SEARCH * HTTP/1.1\nhost: 239.192.152.143:6771\nPORT: 51413\nINFOHASH: ABABABABABABABABABABABABABABABABABABABAB\n\n",
+        )
+        .unwrap();
+        assert_eq!(announce.port, 51413);
+        assert_eq!(announce.info_hashes, vec![[0xab; 20]]);
+        assert_eq!(announce.cookie, None);
+
+        for invalid in [
+            &b"NOTIFY * HTTP/1.1\r\nPort: 1\r\nInfohash: abababababababababababababababababababab\r\n\r\n"[..],
+            b"BT-SEARCH * HTTP/1.1\r\nInfohash: abababababababababababababababababababab\r\n\r\n",
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\nInfohash: abab\r\n\r\n",
+            b"BT-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n",
+        ] {
+            assert!(matches!(
+                LsdAnnounce::from_bytes(invalid),
+                Err(Error::MalformedMessage)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_peers_in_local_network() {
+        // a random multicast port keeps the test away from real clients
+        let config = LsdConfig::default()
+            .with_multicast_port(rand::thread_rng().gen_range(20000..60000))
+            .with_ipv6(false);
+        let local = LocalServiceDiscovery::bind(1111, config.clone())
+            .await
+            .unwrap();
+        let remote = LocalServiceDiscovery::bind(2222, config).await.unwrap();
+
+        let (local_tx, mut local_rx) = mpsc::channel(4);
+        let (remote_tx, mut remote_rx) = mpsc::channel(4);
+        remote.add_torrent([1; 20], remote_tx).await.unwrap();
+        local.add_torrent([1; 20], local_tx).await.unwrap();
+
+        let peers = tokio::time::timeout(Duration::from_secs(5), remote_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].port(), 1111);
+        // our own announces are recognized by the cookie
+        while let Ok(Some(peers)) =
+            tokio::time::timeout(Duration::from_millis(200), local_rx.recv()).await
+        {
+            assert!(peers.iter().all(|peer| peer.port() != 1111));
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::protocol::meta_info_file::{Sha1HashBytes, SHA1_HASH_BYTE_LENGTH};
+use crate::protocol::tracker::PeerAddress;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Magnet URI scheme prefix (https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format).
+const MAGNET_URI_PREFIX: &str = "magnet:?";
+
+/// Prefix of the exact topic (`xt`) parameter for BitTorrent info hashes.
+const BTIH_URN_PREFIX: &str = "urn:btih:";
+
+/// RFC 4648 base32 alphabet, used by older clients to encode info hashes in magnet URIs.
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Magnet URI related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("not a magnet URI")]
+    InvalidScheme,
+    #[error("magnet URI has no BitTorrent info hash (xt=urn:btih:...)")]
+    MissingInfoHash,
+    #[error("invalid info hash in magnet URI: {0}")]
+    InvalidInfoHash(String),
+    #[error("invalid peer address in magnet URI: {0}")]
+    InvalidPeerAddress(String),
+    #[error("invalid percent encoding in magnet URI")]
+    InvalidEncoding(#[from] std::string::FromUtf8Error),
+}
+
+/// Parsed magnet URI (https://www.bittorrent.org/beps/bep_0009.html#magnet-uri-format).
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: Sha1HashBytes,
+    /// Display name (`dn`).
+    pub display_name: Option<String>,
+    /// Tracker URLs (`tr`).
+    pub trackers: Vec<String>,
+    /// Web seed URLs (`ws`).
+    pub web_seeds: Vec<String>,
+    /// Peer addresses (`x.pe`).
+    pub peers: Vec<PeerAddress>,
+}
+
+impl FromStr for MagnetLink {
+    type Err = Error;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let query = uri
+            .strip_prefix(MAGNET_URI_PREFIX)
+            .ok_or(Error::InvalidScheme)?;
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut web_seeds = vec![];
+        let mut peers = vec![];
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            // parameters can be numbered to define multiple values (tr.1, tr.2, x.pe.1, ...)
+            let key = match key.rsplit_once('.') {
+                Some((key, index))
+                    if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) =>
+                {
+                    key
+                }
+                _ => key,
+            };
+            // `+` is a space in display names, a plus sign must be encoded as `%2B`
+            let value = if key == "dn" {
+                urlencoding::decode(&value.replace('+', " "))?.into_owned()
+            } else {
+                urlencoding::decode(value)?.into_owned()
+            };
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_URN_PREFIX) {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "ws" => web_seeds.push(value),
+                "x.pe" => {
+                    let address = SocketAddr::from_str(&value)
+                        .map_err(|_| Error::InvalidPeerAddress(value.clone()))?;
+                    peers.push(PeerAddress::from(address));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(Error::MissingInfoHash)?,
+            display_name,
+            trackers,
+            web_seeds,
+            peers,
+        })
+    }
+}
+
+/// Parses a hex (40 characters) or base32 (32 characters) encoded info hash.
+fn parse_info_hash(hash: &str) -> Result<Sha1HashBytes, Error> {
+    let invalid = || Error::InvalidInfoHash(hash.to_string());
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => decode_base32(hash).ok_or_else(invalid)?,
+        _ => return Err(invalid()),
+    };
+    let mut result = [0; SHA1_HASH_BYTE_LENGTH];
+    result.copy_from_slice(&bytes);
+    Ok(result)
+}
+
+/// Decodes unpadded base32 (RFC 4648) data.
+fn decode_base32(data: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(data.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_magnet_uri() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:9ecd4676fd0f0474151a4b74a5958f42639cebdf&dn=ubuntu+23.10&tr=https%3A%2F%2Ftorrent.ubuntu.com%2Fannounce&tr.1=udp%3A%2F%2Ftracker.example%3A6969&ws=http%3A%2F%2Fexample.com%2Fubuntu.iso&x.pe=10.0.0.1%3A6881&x.pe=%5B%3A%3A1%5D%3A51413"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "9ecd4676fd0f0474151a4b74a5958f42639cebdf"
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("ubuntu 23.10"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "https://torrent.ubuntu.com/announce",
+                "udp://tracker.example:6969"
+            ]
+        );
+        assert_eq!(magnet.web_seeds, vec!["http://example.com/ubuntu.iso"]);
+        let peers: Vec<String> = magnet.peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(peers, vec!["10.0.0.1:6881", "[::1]:51413"]);
+    }
+
+    #[test]
+    fn decode_plus_signs_and_numbered_peers() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:9ecd4676fd0f0474151a4b74a5958f42639cebdf&dn=c%2B%2B+primer&x.pe.1=10.0.0.1%3A6881&x.pe.2=10.0.0.2%3A6882"
+            .parse()
+            .unwrap();
+        assert_eq!(magnet.display_name.as_deref(), Some("c++ primer"));
+        let peers: Vec<String> = magnet.peers.iter().map(|p| p.to_string()).collect();
+        assert_eq!(peers, vec!["10.0.0.1:6881", "10.0.0.2:6882"]);
+    }
+
+    #[test]
+    fn parse_base32_info_hash() {
+        let magnet: MagnetLink = "magnet:?xt=urn:btih:T3GUM5X5B4CHIFI2JN2KLFMPIJRZZ267"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            hex::encode(magnet.info_hash),
+            "9ecd4676fd0f0474151a4b74a5958f42639cebdf"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_magnet_uris() {
+        assert!(matches!(
+            "http://example.com".parse::<MagnetLink>(),
+            Err(Error::InvalidScheme)
+        ));
+        assert!(matches!(
+            "magnet:?dn=test".parse::<MagnetLink>(),
+            Err(Error::MissingInfoHash)
+        ));
+        assert!(matches!(
+            "magnet:?xt=urn:btih:1234".parse::<MagnetLink>(),
+            Err(Error::InvalidInfoHash(_))
+        ));
+    }
+}
@@ -1,15 +1,24 @@
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use std::path::{Component, Path, PathBuf};
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// Standard size of SHA1 hashes in bytes from https://wiki.theory.org/BitTorrentSpecification.
 pub const SHA1_HASH_BYTE_LENGTH: usize = 20;
 pub type Sha1HashBytes = [u8; SHA1_HASH_BYTE_LENGTH];
 
+/// Largest torrent file (or info dictionary) accepted, even torrents of terabytes have only a few megabytes
+/// of piece hashes.
+pub const MAX_TORRENT_FILE_SIZE: usize = 32 << 20;
+
+/// Deepest nesting of bencoded lists and dictionaries accepted, torrents only nest a few levels deep.
+/// Deeper (hostile) inputs are rejected before they could exhaust the stack of the recursive parsers.
+pub const MAX_BENCODE_DEPTH: usize = 64;
+
 /// Meta info (torrent file) related errors.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -19,13 +28,41 @@ pub enum Error {
     FailedToParseFile(#[from] serde_bencode::Error),
     #[error("invalid number of bytes in info.pieces")]
     InvalidPiecesData,
+    #[error("info must contain either length or files")]
+    MissingFileLayout,
+    #[error("invalid file path in info.files: {0:?}")]
+    InvalidFilePath(Vec<String>),
+    #[error("invalid info.length: {0}")]
+    InvalidLength(isize),
+    #[error("invalid info.piece length: {0}")]
+    InvalidPieceLength(isize),
+    #[error("total length of the files is too large")]
+    LengthOverflow,
+    #[error("info.pieces contains {actual} hashes instead of {expected}")]
+    PieceCountMismatch { expected: usize, actual: usize },
+    #[error("invalid bencoded data at byte {0}")]
+    InvalidBencode(usize),
+    #[error("missing info dictionary")]
+    MissingInfo,
+    #[error("torrent is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("bencoded data is nested deeper than {0} levels")]
+    NestingTooDeep(usize),
 }
 
 /// Raw meta (torrent) file info base struct.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 struct RawMetaInfo {
     info: RawMetaInfoFile,
+    #[serde(default)]
     announce: String,
+    /// Tiers of tracker URLs (https://www.bittorrent.org/beps/bep_0012.html).
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_list: Option<Vec<Vec<String>>>,
 }
 
 /// Raw meta (torrent) file info.
@@ -34,16 +71,31 @@ struct RawMetaInfoFile {
     pieces: ByteBuf,
     #[serde(rename = "piece length")]
     piece_length: isize,
-    length: isize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length: Option<isize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<RawMetaInfoFileEntry>>,
     name: String,
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    md5sum: Option<String>,
+    /// Peers of private torrents must only be obtained from trackers (https://www.bittorrent.org/beps/bep_0027.html).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<i64>,
+}
+
+/// Raw file entry of a multi-file meta (torrent) file info.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Hash)]
+struct RawMetaInfoFileEntry {
+    length: isize,
+    path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     md5sum: Option<String>,
 }
 
 impl RawMetaInfoFile {
     /// Parse file piece hashes (SHA-1) of meta info (torrent) file.
     pub fn parse_pieces(&self) -> Result<Vec<Sha1HashBytes>, Error> {
-        if self.pieces.len() % SHA1_HASH_BYTE_LENGTH != 0 {
+        if !self.pieces.len().is_multiple_of(SHA1_HASH_BYTE_LENGTH) {
             return Err(Error::InvalidPiecesData);
         }
         Ok(self
@@ -53,29 +105,161 @@ impl RawMetaInfoFile {
             .collect())
     }
 
-    /// Returns SHA-1 hash of the whole info part.
-    pub fn sha1_hash(&self) -> Result<Sha1HashBytes, Error> {
-        let mut hasher = Sha1::default();
-        let info_bytes = serde_bencode::to_bytes(self)?;
-        let info_bytes = info_bytes.as_slice();
-        hasher.update(info_bytes);
-        let result: Sha1HashBytes = hasher.finalize().to_vec().try_into().unwrap();
-        Ok(result)
+    /// Parse the file layout of meta info (torrent) file.
+    /// Single-file torrents have one entry named after the torrent, multi-file torrents have entries
+    /// with paths relative to the torrent's root directory.
+    pub fn parse_files(&self) -> Result<Vec<FileEntry>, Error> {
+        let entries: Vec<(PathBuf, usize)> = match (&self.length, &self.files) {
+            (Some(length), None) if *length < 0 => return Err(Error::InvalidLength(*length)),
+            (Some(length), None) => vec![(PathBuf::from(&self.name), *length as usize)],
+            (None, Some(files)) => files
+                .iter()
+                .map(|file| {
+                    let path: PathBuf = file.path.iter().collect();
+                    let is_safe = !file.path.is_empty()
+                        && path
+                            .components()
+                            .all(|component| matches!(component, Component::Normal(_)));
+                    if !is_safe || file.length < 0 {
+                        return Err(Error::InvalidFilePath(file.path.clone()));
+                    }
+                    Ok((path, file.length as usize))
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(Error::MissingFileLayout),
+        };
+
+        let mut offset: usize = 0;
+        entries
+            .into_iter()
+            .map(|(path, length)| {
+                let entry = FileEntry {
+                    path,
+                    length,
+                    offset,
+                };
+                offset = offset.checked_add(length).ok_or(Error::LengthOverflow)?;
+                Ok(entry)
+            })
+            .collect()
+    }
+}
+
+/// Returns the end position (exclusive) of the bencoded value starting at `start`
+/// (https://wiki.theory.org/BitTorrentSpecification#Bencoding). Values nested deeper than
+/// [`MAX_BENCODE_DEPTH`] are rejected.
+pub(crate) fn bencode_value_end(data: &[u8], start: usize) -> Result<usize, Error> {
+    nested_value_end(data, start, 0)
+}
+
+/// Rejects bencoded `data` nested deeper than [`MAX_BENCODE_DEPTH`]. `serde_bencode` deserializes
+/// recursively, so untrusted input must pass this check first or it can overflow the stack.
+pub(crate) fn check_bencode_depth(data: &[u8]) -> Result<(), Error> {
+    bencode_value_end(data, 0)?;
+    Ok(())
+}
+
+fn nested_value_end(data: &[u8], start: usize, depth: usize) -> Result<usize, Error> {
+    match data.get(start) {
+        Some(b'i') => data[start..]
+            .iter()
+            .position(|byte| *byte == b'e')
+            .map(|end| start + end + 1)
+            .ok_or(Error::InvalidBencode(start)),
+        Some(b'l') | Some(b'd') => {
+            if depth == MAX_BENCODE_DEPTH {
+                return Err(Error::NestingTooDeep(MAX_BENCODE_DEPTH));
+            }
+            let mut position = start + 1;
+            while data.get(position) != Some(&b'e') {
+                position = nested_value_end(data, position, depth + 1)?;
+            }
+            Ok(position + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let colon = data[start..]
+                .iter()
+                .position(|byte| *byte == b':')
+                .map(|colon| start + colon)
+                .ok_or(Error::InvalidBencode(start))?;
+            let length: usize = std::str::from_utf8(&data[start..colon])
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .ok_or(Error::InvalidBencode(start))?;
+            let end = (colon + 1)
+                .checked_add(length)
+                .filter(|end| *end <= data.len())
+                .ok_or(Error::InvalidBencode(start))?;
+            Ok(end)
+        }
+        _ => Err(Error::InvalidBencode(start)),
+    }
+}
+
+/// Finds the original bencoded bytes of the top level `info` dictionary.
+fn raw_info_bytes(data: &[u8]) -> Result<&[u8], Error> {
+    if data.first() != Some(&b'd') {
+        return Err(Error::InvalidBencode(0));
     }
+    let mut position = 1;
+    while data.get(position) != Some(&b'e') {
+        let key_end = bencode_value_end(data, position)?;
+        let value_end = bencode_value_end(data, key_end)?;
+        if &data[position..key_end] == b"4:info" {
+            return Ok(&data[key_end..value_end]);
+        }
+        position = value_end;
+    }
+    Err(Error::MissingInfo)
+}
+
+/// Returns SHA-1 hash of the original bytes of the info dictionary, so keys that are not modeled
+/// in [`RawMetaInfoFile`] (`source`, etc.) are part of the hash as well.
+fn info_hash(data: &[u8]) -> Result<Sha1HashBytes, Error> {
+    Ok(Sha1::digest(raw_info_bytes(data)?).into())
+}
+
+/// A single file of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path of the file, relative to the torrent's root directory for multi-file torrents.
+    pub path: PathBuf,
+    /// Length of the file in bytes.
+    pub length: usize,
+    /// Offset of the file's first byte in the concatenated torrent data.
+    pub offset: usize,
+}
+
+/// Part of a byte range of torrent data that falls into a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSegment {
+    /// Index of the file in [`TorrentFile::files`].
+    pub file_index: usize,
+    /// Offset inside the file.
+    pub offset: usize,
+    pub length: usize,
 }
 
 /// Parsed torrent file from [`RawMetaInfo`].
 #[derive(Debug)]
 pub struct TorrentFile {
     pub announce: String,
+    /// Tiers of tracker URLs from `announce-list`, empty if the torrent has none.
+    pub announce_list: Vec<Vec<String>>,
     pub info_hash: Sha1HashBytes,
     pub piece_hashes: Vec<Sha1HashBytes>,
     pub piece_length: isize,
+    /// Total length of all files.
     pub length: isize,
     pub name: String,
+    pub files: Vec<FileEntry>,
+    pub multi_file: bool,
+    /// Private torrents get peers from their trackers only, neither from the DHT nor from peer exchange.
+    pub private: bool,
 }
 
 impl TorrentFile {
+    #[allow(clippy::too_many_arguments)]
     pub const fn new(
         announce: String,
         info_hash: Sha1HashBytes,
@@ -83,6 +267,8 @@ impl TorrentFile {
         piece_length: isize,
         length: isize,
         name: String,
+        files: Vec<FileEntry>,
+        multi_file: bool,
     ) -> Self {
         Self {
             announce,
@@ -91,36 +277,405 @@ impl TorrentFile {
             piece_length,
             length,
             name,
+            files,
+            multi_file,
+            announce_list: Vec::new(),
+            private: false,
         }
     }
+
+    pub fn with_announce_list(mut self, announce_list: Vec<Vec<String>>) -> Self {
+        self.announce_list = announce_list;
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    /// Returns the tracker tiers of the torrent. If `announce-list` is present `announce` is ignored,
+    /// otherwise `announce` forms the only tier (https://www.bittorrent.org/beps/bep_0012.html).
+    pub fn trackers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .map(|tier| tier.iter().filter(|url| !url.is_empty()).cloned().collect())
+            .filter(|tier: &Vec<String>| !tier.is_empty())
+            .collect();
+        if !tiers.is_empty() {
+            tiers
+        } else if self.announce.is_empty() {
+            vec![]
+        } else {
+            vec![vec![self.announce.clone()]]
+        }
+    }
+
+    /// Returns the paths of all files under `root`.
+    /// For single-file torrents `root` is the path of the file itself,
+    /// for multi-file torrents `root` is the directory containing the files.
+    pub fn file_paths(&self, root: &Path) -> Vec<PathBuf> {
+        if self.multi_file {
+            self.files
+                .iter()
+                .map(|file| root.join(&file.path))
+                .collect()
+        } else {
+            vec![root.to_path_buf()]
+        }
+    }
+
+    /// Maps a byte range of the concatenated torrent data onto the files it covers.
+    /// Zero length files are skipped, ranges past the end of the torrent are truncated.
+    pub fn file_segments(&self, offset: usize, length: usize) -> Vec<FileSegment> {
+        let end = offset.saturating_add(length);
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= offset);
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, file)| file.offset < end)
+            .filter(|(_, file)| file.length > 0)
+            .map(|(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                FileSegment {
+                    file_index: first + index,
+                    offset: start - file.offset,
+                    length: stop - start,
+                }
+            })
+            .collect()
+    }
+
+    /// Maps a piece onto the files it covers.
+    pub fn piece_segments(&self, index: usize) -> Vec<FileSegment> {
+        self.file_segments(index * self.piece_length as usize, self.piece_size(index))
+    }
+
+    /// Number of pieces in the torrent.
+    pub fn pieces_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Size of the piece with the given index in bytes, the last piece can be shorter than `piece_length`.
+    pub fn piece_size(&self, index: usize) -> usize {
+        let piece_length = self.piece_length as usize;
+        let start = index * piece_length;
+        piece_length.min((self.length as usize).saturating_sub(start))
+    }
+}
+
+/// Rejects inputs larger than [`MAX_TORRENT_FILE_SIZE`] or nested deeper than [`MAX_BENCODE_DEPTH`],
+/// before they are deserialized.
+fn check_bounds(data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_TORRENT_FILE_SIZE {
+        return Err(Error::TooLarge(MAX_TORRENT_FILE_SIZE));
+    }
+    check_bencode_depth(data)
 }
 
-/// Convert [`RawMetaInfo`] to [`TorrentFile`].
-impl TryFrom<RawMetaInfo> for TorrentFile {
-    type Error = Error;
+impl TorrentFile {
+    /// Parses the content of a meta info (torrent) file, e.g. an embedded resource.
+    pub fn from_bytes(content: &[u8]) -> Result<Self, Error> {
+        check_bounds(content)?;
+        let raw: RawMetaInfo =
+            serde_bencode::from_bytes(content).map_err(Error::FailedToParseFile)?;
+        Self::from_raw(raw, info_hash(content)?)
+    }
+
+    /// Parses a meta info (torrent) file read from `reader` until its end, e.g. an HTTP response body.
+    /// At most [`MAX_TORRENT_FILE_SIZE`] bytes are read.
+    pub async fn from_reader(reader: impl AsyncRead + Unpin) -> Result<Self, Error> {
+        let mut content = vec![];
+        reader
+            .take(MAX_TORRENT_FILE_SIZE as u64 + 1)
+            .read_to_end(&mut content)
+            .await
+            .map_err(Error::FailedToReadFile)?;
+        Self::from_bytes(&content)
+    }
 
-    fn try_from(raw: RawMetaInfo) -> Result<Self, Self::Error> {
+    /// Constructs [`TorrentFile`] from the bencoded info dictionary only (e.g. received from peers via metadata exchange).
+    pub fn from_info_bytes(info: &[u8], announce: String) -> Result<Self, Error> {
+        check_bounds(info)?;
+        let raw = RawMetaInfo {
+            info: serde_bencode::from_bytes(info).map_err(Error::FailedToParseFile)?,
+            announce,
+            announce_list: None,
+        };
+        Self::from_raw(raw, Sha1::digest(info).into())
+    }
+
+    /// Converts [`RawMetaInfo`] to [`TorrentFile`], `info_hash` must be computed from the original info bytes.
+    /// Rejects torrents whose lengths don't fit into memory offsets or whose piece hashes don't cover all files.
+    fn from_raw(raw: RawMetaInfo, info_hash: Sha1HashBytes) -> Result<Self, Error> {
+        let files = raw.info.parse_files()?;
+        let length = files
+            .iter()
+            .try_fold(0usize, |sum, file| sum.checked_add(file.length))
+            .and_then(|length| isize::try_from(length).ok())
+            .ok_or(Error::LengthOverflow)?;
+        if raw.info.piece_length <= 0 {
+            return Err(Error::InvalidPieceLength(raw.info.piece_length));
+        }
+        let piece_hashes = raw.info.parse_pieces()?;
+        let expected = (length as usize).div_ceil(raw.info.piece_length as usize);
+        if piece_hashes.len() != expected {
+            return Err(Error::PieceCountMismatch {
+                expected,
+                actual: piece_hashes.len(),
+            });
+        }
         Ok(TorrentFile::new(
             raw.announce.clone(),
-            raw.info.sha1_hash()?,
-            raw.info.parse_pieces()?,
+            info_hash,
+            piece_hashes,
             raw.info.piece_length,
-            raw.info.length,
+            length,
             raw.info.name,
-        ))
+            files,
+            raw.info.files.is_some(),
+        )
+        .with_announce_list(raw.announce_list.unwrap_or_default())
+        .with_private(raw.info.private == Some(1)))
     }
 }
 
-/// Meta info file parser function.
-pub async fn parse(file_path: &str) -> Result<TorrentFile, Error> {
-    let mut file = File::open(file_path)
+/// Meta info file parser function, see [`TorrentFile::from_reader`].
+pub async fn parse(file_path: impl AsRef<Path>) -> Result<TorrentFile, Error> {
+    let file = File::open(file_path)
         .await
         .map_err(Error::FailedToReadFile)?;
-    let mut content = vec![];
-    file.read_to_end(&mut content)
-        .await
-        .map_err(Error::FailedToReadFile)?;
-    let result: RawMetaInfo =
-        serde_bencode::from_bytes(content.as_slice()).map_err(Error::FailedToParseFile)?;
-    result.try_into()
+    TorrentFile::from_reader(file).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTI_FILE_TORRENT: &[u8] = b"d8:announce23:http://tracker/announce4:infod5:filesld6:lengthi5e4:pathl1:a5:a.txteed6:lengthi0e4:pathl5:emptyeed6:lengthi20e4:pathl5:b.bineee4:name4:test12:piece lengthi10e6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccceee";
+
+    fn multi_file_torrent() -> TorrentFile {
+        TorrentFile::from_bytes(MULTI_FILE_TORRENT).unwrap()
+    }
+
+    #[test]
+    fn parse_multi_file_layout() {
+        let torrent = multi_file_torrent();
+        assert!(torrent.multi_file);
+        assert_eq!(torrent.length, 25);
+        assert_eq!(torrent.pieces_count(), 3);
+        assert_eq!(torrent.piece_size(2), 5);
+        assert_eq!(
+            torrent.files,
+            vec![
+                FileEntry {
+                    path: PathBuf::from("a/a.txt"),
+                    length: 5,
+                    offset: 0
+                },
+                FileEntry {
+                    path: PathBuf::from("empty"),
+                    length: 0,
+                    offset: 5
+                },
+                FileEntry {
+                    path: PathBuf::from("b.bin"),
+                    length: 20,
+                    offset: 5
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.file_paths(Path::new("out")),
+            vec![
+                PathBuf::from("out/a/a.txt"),
+                PathBuf::from("out/empty"),
+                PathBuf::from("out/b.bin")
+            ]
+        );
+    }
+
+    #[test]
+    fn map_byte_ranges_onto_files() {
+        let torrent = multi_file_torrent();
+        assert_eq!(
+            torrent.piece_segments(0),
+            vec![
+                FileSegment {
+                    file_index: 0,
+                    offset: 0,
+                    length: 5
+                },
+                FileSegment {
+                    file_index: 2,
+                    offset: 0,
+                    length: 5
+                },
+            ]
+        );
+        assert_eq!(
+            torrent.piece_segments(2),
+            vec![FileSegment {
+                file_index: 2,
+                offset: 15,
+                length: 5
+            }]
+        );
+        assert_eq!(
+            torrent.file_segments(3, 4),
+            vec![
+                FileSegment {
+                    file_index: 0,
+                    offset: 3,
+                    length: 2
+                },
+                FileSegment {
+                    file_index: 2,
+                    offset: 0,
+                    length: 2
+                },
+            ]
+        );
+        assert!(torrent.file_segments(25, 10).is_empty());
+    }
+
+    #[test]
+    fn parse_announce_list_tiers() {
+        let raw = b"d8:announce23:http://tracker/announce13:announce-listll8:udp://a18:udp://a2el0:el8:http://bee4:infod6:lengthi10e4:name4:test12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = TorrentFile::from_bytes(raw).unwrap();
+        assert_eq!(
+            torrent.trackers(),
+            vec![
+                vec!["udp://a1".to_string(), "udp://a2".to_string()],
+                vec!["http://b".to_string()]
+            ]
+        );
+        assert_eq!(
+            multi_file_torrent().trackers(),
+            vec![vec!["http://tracker/announce".to_string()]]
+        );
+    }
+
+    #[test]
+    fn reject_unsafe_file_paths() {
+        let raw = b"d8:announce23:http://tracker/announce4:infod5:filesld6:lengthi5e4:pathl2:..6:escapeeee4:name4:test12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(raw),
+            Err(Error::InvalidFilePath(_))
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_lengths() {
+        let negative_length = b"d4:infod6:lengthi-5e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(negative_length),
+            Err(Error::InvalidLength(-5))
+        ));
+        let negative_piece_length = b"d4:infod6:lengthi10e4:name4:test12:piece lengthi-10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(negative_piece_length),
+            Err(Error::InvalidPieceLength(-10))
+        ));
+        // 100000 bytes need 7 pieces of 16 KiB
+        let missing_hashes = b"d4:infod6:lengthi100000e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(missing_hashes),
+            Err(Error::PieceCountMismatch {
+                expected: 7,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn info_hash_covers_unmodeled_keys() {
+        let info: &[u8] = b"d6:lengthi10e4:name4:test12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:XYZe";
+        let mut content = b"d8:announce23:http://tracker/announce7:comment5:hello4:info".to_vec();
+        content.extend_from_slice(info);
+        content.extend_from_slice(b"e");
+        let torrent = TorrentFile::from_bytes(&content).unwrap();
+        let expected: Sha1HashBytes = Sha1::digest(info).into();
+        assert_eq!(torrent.info_hash, expected);
+        assert!(torrent.private);
+    }
+
+    #[test]
+    fn info_hash_of_nested_info_key_is_ignored() {
+        let content = b"d8:announce23:http://tracker/announce4:infod6:lengthi10e4:name4:info12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae1:xd4:infoi1eee";
+        let expected: Sha1HashBytes = Sha1::digest(
+            b"d6:lengthi10e4:name4:info12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+        )
+        .into();
+        assert_eq!(
+            TorrentFile::from_bytes(content).unwrap().info_hash,
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_hostile_inputs() {
+        let mut nested = vec![b'l'; MAX_BENCODE_DEPTH + 1];
+        nested.extend(vec![b'e'; MAX_BENCODE_DEPTH + 1]);
+        let mut content = b"d4:infod6:lengthi10e4:name4:test12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaae1:x".to_vec();
+        content.extend_from_slice(&nested);
+        content.push(b'e');
+        assert!(matches!(
+            TorrentFile::from_bytes(&content),
+            Err(Error::NestingTooDeep(MAX_BENCODE_DEPTH))
+        ));
+        assert!(matches!(
+            TorrentFile::from_info_bytes(&nested, String::new()),
+            Err(Error::NestingTooDeep(_))
+        ));
+        // the top level dictionary and 63 nested lists are fine
+        let mut content = MULTI_FILE_TORRENT[..MULTI_FILE_TORRENT.len() - 1].to_vec();
+        content.extend_from_slice(b"1:x");
+        content.extend_from_slice(&nested[2..nested.len() - 2]);
+        content.push(b'e');
+        assert!(TorrentFile::from_bytes(&content).is_ok());
+
+        // lengths summing up past the address space, and pieces without bytes
+        let overflowing = b"d4:infod5:filesld6:lengthi9223372036854775807e4:pathl1:aeed6:lengthi9223372036854775807e4:pathl1:beed6:lengthi9223372036854775807e4:pathl1:ceee4:name4:test12:piece lengthi10e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(overflowing),
+            Err(Error::LengthOverflow)
+        ));
+        let empty_pieces =
+            b"d4:infod6:lengthi10e4:name4:test12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert!(matches!(
+            TorrentFile::from_bytes(empty_pieces),
+            Err(Error::InvalidPieceLength(0))
+        ));
+
+        let oversized = tokio::io::repeat(b'l').take(MAX_TORRENT_FILE_SIZE as u64 * 2);
+        assert!(matches!(
+            TorrentFile::from_reader(oversized).await,
+            Err(Error::TooLarge(MAX_TORRENT_FILE_SIZE))
+        ));
+        let torrent = TorrentFile::from_reader(MULTI_FILE_TORRENT).await.unwrap();
+        assert_eq!(torrent.info_hash, multi_file_torrent().info_hash);
+    }
+
+    #[tokio::test]
+    async fn info_hash_of_real_world_torrents() {
+        let cases = [
+            (
+                "../examples/torrents/ubuntu-desktop.torrent",
+                "9ecd4676fd0f0474151a4b74a5958f42639cebdf",
+            ),
+            (
+                "../examples/torrents/ubuntu-live-server.torrent",
+                "d6b4535ba8f2b34012bc633569f113e77017e032",
+            ),
+        ];
+        for (path, expected) in cases {
+            let torrent = parse(path).await.unwrap();
+            assert_eq!(hex::encode(torrent.info_hash), expected);
+        }
+    }
 }
@@ -0,0 +1,307 @@
+use crate::protocol::meta_info_file::{bencode_value_end, Sha1HashBytes};
+use crate::protocol::peer_wire::{self, PeerConnection, PeerMessage};
+use crate::protocol::transport::Transport;
+use bytes::Bytes;
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+/// Name of the metadata exchange extension in the extended handshake (https://www.bittorrent.org/beps/bep_0009.html).
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// Metadata is exchanged in 16 KiB pieces, only the last piece can be shorter.
+pub const METADATA_PIECE_SIZE: usize = 16384;
+
+/// Upper bound of the accepted metadata size, larger sizes are rejected to protect against hostile peers.
+pub const MAX_METADATA_SIZE: usize = 32 << 20;
+
+/// `msg_type` values from https://www.bittorrent.org/beps/bep_0009.html#extension-message.
+const MSG_TYPE_REQUEST: i64 = 0;
+const MSG_TYPE_DATA: i64 = 1;
+const MSG_TYPE_REJECT: i64 = 2;
+
+/// Metadata exchange related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("protocol error")]
+    Protocol(#[from] peer_wire::Error),
+    #[error("invalid bencoded metadata message")]
+    InvalidMessage(#[from] serde_bencode::Error),
+    #[error("malformed metadata message")]
+    MalformedMessage,
+    #[error("invalid metadata message type: {0}")]
+    InvalidMessageType(i64),
+    #[error("peer does not support metadata exchange")]
+    NotSupported,
+    #[error("invalid metadata size: {0}")]
+    InvalidMetadataSize(i64),
+    #[error("peer rejected metadata piece #{0}")]
+    Rejected(usize),
+    #[error("invalid metadata piece #{0}")]
+    InvalidPiece(usize),
+    #[error("metadata does not match info hash")]
+    InvalidMetadataHash,
+}
+
+/// Bencoded header of metadata messages.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
+struct RawMetadataMessage {
+    msg_type: i64,
+    piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+/// Metadata exchange message (https://www.bittorrent.org/beps/bep_0009.html#extension-message).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Bytes,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+/// Serialize metadata message to the payload of [`PeerMessage::Extended`].
+impl TryFrom<MetadataMessage> for Bytes {
+    type Error = Error;
+    fn try_from(msg: MetadataMessage) -> Result<Self, Self::Error> {
+        let (header, data) = match msg {
+            MetadataMessage::Request { piece } => (
+                RawMetadataMessage {
+                    msg_type: MSG_TYPE_REQUEST,
+                    piece: piece as i64,
+                    total_size: None,
+                },
+                Bytes::new(),
+            ),
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                RawMetadataMessage {
+                    msg_type: MSG_TYPE_DATA,
+                    piece: piece as i64,
+                    total_size: Some(total_size as i64),
+                },
+                data,
+            ),
+            MetadataMessage::Reject { piece } => (
+                RawMetadataMessage {
+                    msg_type: MSG_TYPE_REJECT,
+                    piece: piece as i64,
+                    total_size: None,
+                },
+                Bytes::new(),
+            ),
+        };
+        let mut result = serde_bencode::to_bytes(&header)?;
+        result.extend_from_slice(data.as_ref());
+        Ok(result.into())
+    }
+}
+
+/// Deserialize metadata message from the payload of [`PeerMessage::Extended`].
+impl TryFrom<Bytes> for MetadataMessage {
+    type Error = Error;
+    fn try_from(raw: Bytes) -> Result<Self, Self::Error> {
+        // data messages carry the raw metadata piece right after the bencoded dictionary
+        let header_end = bencode_value_end(raw.as_ref(), 0).map_err(|_| Error::MalformedMessage)?;
+        let header: RawMetadataMessage = serde_bencode::from_bytes(&raw[..header_end])?;
+        let piece = usize::try_from(header.piece).map_err(|_| Error::MalformedMessage)?;
+        match header.msg_type {
+            MSG_TYPE_REQUEST => Ok(MetadataMessage::Request { piece }),
+            MSG_TYPE_DATA => {
+                let total_size = header.total_size.unwrap_or(-1);
+                Ok(MetadataMessage::Data {
+                    piece,
+                    total_size: usize::try_from(total_size)
+                        .map_err(|_| Error::InvalidMetadataSize(total_size))?,
+                    data: raw.slice(header_end..),
+                })
+            }
+            MSG_TYPE_REJECT => Ok(MetadataMessage::Reject { piece }),
+            msg_type => Err(Error::InvalidMessageType(msg_type)),
+        }
+    }
+}
+
+/// Fetches the info dictionary of a torrent from a peer which already completed the handshake
+/// with `info_hash`, [`UT_METADATA`] must be registered on the connection before the handshake
+/// (see [`PeerConnection::register_extension`]). The returned bytes are verified against `info_hash`.
+pub async fn fetch_metadata<T: Transport>(
+    connection: &PeerConnection<T>,
+    info_hash: Sha1HashBytes,
+) -> Result<Vec<u8>, Error> {
+    let local_id = connection
+        .local_extension_id(UT_METADATA)
+        .ok_or(Error::NotSupported)?;
+    if !connection.supports_extensions() {
+        return Err(Error::NotSupported);
+    }
+
+    // wait for the peer's extended handshake to find out the metadata size
+    let handshake = connection.wait_for_extended_handshake().await?;
+    if handshake.extension_id(UT_METADATA).is_none() {
+        return Err(Error::NotSupported);
+    }
+    let size = handshake.metadata_size.ok_or(Error::NotSupported)?;
+    let metadata_size = match usize::try_from(size) {
+        Ok(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+        _ => return Err(Error::InvalidMetadataSize(size)),
+    };
+
+    let pieces_count = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..pieces_count {
+        connection
+            .send_extended(UT_METADATA, MetadataMessage::Request { piece }.try_into()?)
+            .await?;
+    }
+
+    let mut metadata = vec![0; metadata_size];
+    let mut received = vec![false; pieces_count];
+    while received.iter().any(|received| !received) {
+        let PeerMessage::Extended { id, payload } = connection.recv().await? else {
+            continue;
+        };
+        if id != local_id {
+            continue;
+        }
+        match MetadataMessage::try_from(payload)? {
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let start = piece * METADATA_PIECE_SIZE;
+                let expected_length = METADATA_PIECE_SIZE.min(metadata_size.saturating_sub(start));
+                if piece >= pieces_count
+                    || total_size != metadata_size
+                    || data.len() != expected_length
+                {
+                    return Err(Error::InvalidPiece(piece));
+                }
+                metadata[start..start + data.len()].copy_from_slice(data.as_ref());
+                received[piece] = true;
+            }
+            MetadataMessage::Reject { piece } => return Err(Error::Rejected(piece)),
+            // we don't have the metadata yet, so requests are left unanswered
+            MetadataMessage::Request { .. } => {}
+        }
+    }
+
+    let hash: Sha1HashBytes = Sha1::digest(&metadata).into();
+    if hash != info_hash {
+        return Err(Error::InvalidMetadataHash);
+    }
+    debug!("metadata of {0} bytes received", metadata_size);
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::transport::MemoryTransport;
+    use std::time::Duration;
+
+    /// Serves `metadata` from the remote end of a connection, like a peer which has the torrent.
+    async fn serve_metadata(
+        transport: MemoryTransport,
+        metadata: Vec<u8>,
+        info_hash: Sha1HashBytes,
+    ) {
+        let mut connection = PeerConnection::new(transport, Duration::from_secs(5));
+        connection.register_extension("ut_pex");
+        let local_id = connection.register_extension(UT_METADATA);
+        connection.set_metadata_size(metadata.len());
+        connection
+            .handshake("-RT0100-remotepeer01".to_string(), info_hash)
+            .await
+            .unwrap();
+        while let Ok(message) = connection.recv().await {
+            let PeerMessage::Extended { id, payload } = message else {
+                continue;
+            };
+            if id != local_id {
+                continue;
+            }
+            let MetadataMessage::Request { piece } = MetadataMessage::try_from(payload).unwrap()
+            else {
+                panic!("unexpected metadata message");
+            };
+            let start = piece * METADATA_PIECE_SIZE;
+            let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+            let response = MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: Bytes::copy_from_slice(&metadata[start..end]),
+            };
+            connection
+                .send_extended(UT_METADATA, response.try_into().unwrap())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn connect(
+        transport: MemoryTransport,
+        info_hash: Sha1HashBytes,
+    ) -> PeerConnection<MemoryTransport> {
+        let mut connection = PeerConnection::new(transport, Duration::from_secs(5));
+        connection.register_extension(UT_METADATA);
+        connection
+            .handshake("-RT0100-localpeer001".to_string(), info_hash)
+            .await
+            .unwrap();
+        connection
+    }
+
+    #[test]
+    fn metadata_message_roundtrip() {
+        let messages = vec![
+            MetadataMessage::Request { piece: 1 },
+            MetadataMessage::Reject { piece: 2 },
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 5,
+                data: Bytes::from_static(b"d1:ae"),
+            },
+        ];
+        for message in messages {
+            let bytes: Bytes = message.clone().try_into().unwrap();
+            assert_eq!(MetadataMessage::try_from(bytes).unwrap(), message);
+        }
+        let bytes: Bytes = MetadataMessage::Request { piece: 0 }.try_into().unwrap();
+        assert_eq!(bytes.as_ref(), b"d8:msg_typei0e5:piecei0ee");
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_in_pieces() {
+        let metadata: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let info_hash: Sha1HashBytes = Sha1::digest(&metadata).into();
+        let (local, remote) = MemoryTransport::pair();
+        tokio::spawn(serve_metadata(remote, metadata.clone(), info_hash));
+        let local = connect(local, info_hash).await;
+        assert_eq!(fetch_metadata(&local, info_hash).await.unwrap(), metadata);
+    }
+
+    #[tokio::test]
+    async fn fetch_metadata_rejects_invalid_hash() {
+        let (local, remote) = MemoryTransport::pair();
+        tokio::spawn(serve_metadata(remote, b"d4:name4:teste".to_vec(), [0; 20]));
+        let local = connect(local, [0; 20]).await;
+        assert!(matches!(
+            fetch_metadata(&local, [0; 20]).await,
+            Err(Error::InvalidMetadataHash)
+        ));
+    }
+}
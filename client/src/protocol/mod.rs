@@ -1,4 +1,11 @@
+pub mod dht;
+pub mod extension;
+pub mod lsd;
+pub mod magnet;
 pub mod meta_info_file;
+pub mod metadata;
 pub mod peer_wire;
+pub mod pex;
 pub mod tracker;
 pub mod transport;
+pub mod udp_tracker;
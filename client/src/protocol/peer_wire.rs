@@ -1,16 +1,43 @@
+use crate::protocol::extension::{ExtendedHandshake, EXTENDED_HANDSHAKE_ID};
 use crate::protocol::meta_info_file::{Sha1HashBytes, SHA1_HASH_BYTE_LENGTH};
 use crate::protocol::transport::Transport;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::debug;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use serde_bytes::ByteBuf;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::sync::Mutex;
 
 /// Default protocol ID based on specification: https://wiki.theory.org/BitTorrentSpecification.
 const DEFAULT_PROTOCOL_ID: &str = "BitTorrent protocol";
 
+/// Length of the big endian length prefix of every message after the handshake (https://wiki.theory.org/BitTorrentSpecification#Messages).
+const MESSAGE_LENGTH_PREFIX_BYTES_LENGTH: usize = 4;
+
+/// Upper bound of a single message's length, larger messages are rejected to protect against hostile peers.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// Message IDs from https://wiki.theory.org/BitTorrentSpecification#Messages.
+const MESSAGE_ID_CHOKE: u8 = 0;
+const MESSAGE_ID_UNCHOKE: u8 = 1;
+const MESSAGE_ID_INTERESTED: u8 = 2;
+const MESSAGE_ID_NOT_INTERESTED: u8 = 3;
+const MESSAGE_ID_HAVE: u8 = 4;
+const MESSAGE_ID_BITFIELD: u8 = 5;
+const MESSAGE_ID_REQUEST: u8 = 6;
+const MESSAGE_ID_PIECE: u8 = 7;
+const MESSAGE_ID_CANCEL: u8 = 8;
+const MESSAGE_ID_PORT: u8 = 9;
+/// Extension protocol message ID (https://www.bittorrent.org/beps/bep_0010.html).
+const MESSAGE_ID_EXTENDED: u8 = 20;
+
+/// Length of the reserved bytes in the handshake message.
+const RESERVED_BYTES_LENGTH: usize = 8;
+
 /// Errors from Peer Wire protocol (https://wiki.theory.org/BitTorrentSpecification#Peer_wire_protocol_.28TCP.29).
 #[derive(Error, Debug)]
 pub enum Error {
@@ -24,14 +51,80 @@ pub enum Error {
     InvalidResponseHandshake(HandshakeMessage),
     #[error("peer connection I/O timeout: {0:?}")]
     StreamIoTimeout(Duration),
+    #[error("peer closed the connection")]
+    ConnectionClosed,
+    #[error("empty peer message")]
+    EmptyMessage,
+    #[error("unknown peer message ID: {0}")]
+    UnknownMessageId(u8),
+    #[error("invalid payload length {1} for peer message ID {0}")]
+    InvalidMessagePayloadLength(u8, usize),
+    #[error("peer message is too large: {0} bytes")]
+    MessageTooLarge(usize),
+    #[error("invalid extended handshake")]
+    InvalidExtendedHandshake(#[from] serde_bencode::Error),
+    #[error("peer does not support extension: {0}")]
+    ExtensionNotSupported(String),
+    #[error("unsupported protocol: {0}")]
+    UnsupportedProtocol(String),
+}
+
+/// Reserved bits of the handshake message used to advertise protocol extensions
+/// (https://wiki.theory.org/BitTorrentSpecification#Reserved_Bytes).
+/// The 8 reserved bytes are handled as a big endian 64-bit number.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ReservedFlags(u64);
+
+impl ReservedFlags {
+    /// Extension protocol (https://www.bittorrent.org/beps/bep_0010.html).
+    pub const EXTENSION_PROTOCOL: Self = Self(0x10 << 16);
+    /// Fast extension (https://www.bittorrent.org/beps/bep_0006.html).
+    pub const FAST: Self = Self(0x04);
+    /// DHT, peers supporting it send their DHT port in a [`PeerMessage::Port`] message
+    /// (https://www.bittorrent.org/beps/bep_0005.html).
+    pub const DHT: Self = Self(0x01);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bytes(bytes: [u8; RESERVED_BYTES_LENGTH]) -> Self {
+        Self(u64::from_be_bytes(bytes))
+    }
+
+    pub const fn to_bytes(self) -> [u8; RESERVED_BYTES_LENGTH] {
+        self.0.to_be_bytes()
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for ReservedFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReservedFlags({0})", hex::encode(self.to_bytes()))
+    }
 }
 
+/// Extensions this client advertises in its handshake.
+pub const SUPPORTED_RESERVED_FLAGS: ReservedFlags = ReservedFlags::EXTENSION_PROTOCOL;
+
 /// Handshake message used to do handshake with peers.
 #[derive(Debug)]
 pub struct HandshakeMessage {
     peer_id: String,
     info_hash: Sha1HashBytes,
     protocol_id: String,
+    reserved: ReservedFlags,
 }
 
 impl HandshakeMessage {
@@ -45,8 +138,26 @@ impl HandshakeMessage {
             peer_id,
             info_hash,
             protocol_id: protocol_id_final,
+            reserved: SUPPORTED_RESERVED_FLAGS,
         }
     }
+
+    pub fn with_reserved(mut self, reserved: ReservedFlags) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn info_hash(&self) -> Sha1HashBytes {
+        self.info_hash
+    }
+
+    pub fn reserved(&self) -> ReservedFlags {
+        self.reserved
+    }
 }
 
 /// Serialize handshake message to bytes.
@@ -55,7 +166,7 @@ impl From<HandshakeMessage> for BytesMut {
         let mut result = BytesMut::new();
         result.put_u8(msg.protocol_id.len() as u8);
         result.put_slice(msg.protocol_id.as_bytes());
-        result.put_slice(&[0; 8]);
+        result.put_slice(&msg.reserved.to_bytes());
         result.put_slice(msg.info_hash.as_slice());
         result.put_slice(msg.peer_id.as_bytes());
         result
@@ -74,6 +185,10 @@ impl TryFrom<Vec<u8>> for HandshakeMessage {
         }
         let message = &raw[1..message_size];
         let protocol_id = &message[0..protocol_id_length];
+        let reserved: [u8; RESERVED_BYTES_LENGTH] = message
+            [protocol_id_length..protocol_id_length + RESERVED_BYTES_LENGTH]
+            .try_into()
+            .map_err(|_| Error::InvalidHandshakeMessageBytesLength)?;
         let info_hash: [u8; SHA1_HASH_BYTE_LENGTH] = message
             [protocol_id_length + 8..protocol_id_length + SHA1_HASH_BYTE_LENGTH + 8]
             .try_into()
@@ -83,35 +198,413 @@ impl TryFrom<Vec<u8>> for HandshakeMessage {
             String::from_utf8_lossy(peer_id).to_string(),
             info_hash,
             Some(String::from_utf8_lossy(protocol_id).to_string()),
-        ))
+        )
+        .with_reserved(ReservedFlags::from_bytes(reserved)))
+    }
+}
+
+/// Messages exchanged with a peer after a successful handshake (https://wiki.theory.org/BitTorrentSpecification#Messages).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        piece_index: u32,
+    },
+    Bitfield(Bytes),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    /// Extension protocol message, `id` 0 is the extended handshake, other IDs are negotiated in it.
+    Extended {
+        id: u8,
+        payload: Bytes,
+    },
+}
+
+impl PeerMessage {
+    /// Returns the message ID or [`None`] for keep-alive messages, which have no ID.
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            PeerMessage::KeepAlive => None,
+            PeerMessage::Choke => Some(MESSAGE_ID_CHOKE),
+            PeerMessage::Unchoke => Some(MESSAGE_ID_UNCHOKE),
+            PeerMessage::Interested => Some(MESSAGE_ID_INTERESTED),
+            PeerMessage::NotInterested => Some(MESSAGE_ID_NOT_INTERESTED),
+            PeerMessage::Have { .. } => Some(MESSAGE_ID_HAVE),
+            PeerMessage::Bitfield(_) => Some(MESSAGE_ID_BITFIELD),
+            PeerMessage::Request { .. } => Some(MESSAGE_ID_REQUEST),
+            PeerMessage::Piece { .. } => Some(MESSAGE_ID_PIECE),
+            PeerMessage::Cancel { .. } => Some(MESSAGE_ID_CANCEL),
+            PeerMessage::Port(_) => Some(MESSAGE_ID_PORT),
+            PeerMessage::Extended { .. } => Some(MESSAGE_ID_EXTENDED),
+        }
+    }
+
+    /// Length of the message without the length prefix.
+    fn payload_length(&self) -> usize {
+        match self {
+            PeerMessage::KeepAlive => 0,
+            PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => 1,
+            PeerMessage::Have { .. } => 5,
+            PeerMessage::Bitfield(bitfield) => 1 + bitfield.len(),
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => 13,
+            PeerMessage::Piece { block, .. } => 9 + block.len(),
+            PeerMessage::Port(_) => 3,
+            PeerMessage::Extended { payload, .. } => 2 + payload.len(),
+        }
+    }
+
+    /// Tries to decode a single length prefixed message from the beginning of `buf`.
+    /// Returns [`None`] if `buf` does not contain a full message yet, otherwise the decoded message
+    /// is returned and its bytes are consumed from `buf`.
+    pub fn decode(buf: &mut BytesMut) -> Result<Option<Self>, Error> {
+        if buf.len() < MESSAGE_LENGTH_PREFIX_BYTES_LENGTH {
+            return Ok(None);
+        }
+        let length = u32::from_be_bytes(
+            buf[..MESSAGE_LENGTH_PREFIX_BYTES_LENGTH]
+                .try_into()
+                .expect("length prefix slice must have 4 bytes"),
+        ) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(Error::MessageTooLarge(length));
+        }
+        if buf.len() < MESSAGE_LENGTH_PREFIX_BYTES_LENGTH + length {
+            buf.reserve(MESSAGE_LENGTH_PREFIX_BYTES_LENGTH + length - buf.len());
+            return Ok(None);
+        }
+        buf.advance(MESSAGE_LENGTH_PREFIX_BYTES_LENGTH);
+        let message = buf.split_to(length).freeze();
+        Self::try_from(message).map(Some)
+    }
+}
+
+/// Serialize peer message to length prefixed bytes.
+impl From<PeerMessage> for BytesMut {
+    fn from(msg: PeerMessage) -> Self {
+        let payload_length = msg.payload_length();
+        let mut result =
+            BytesMut::with_capacity(MESSAGE_LENGTH_PREFIX_BYTES_LENGTH + payload_length);
+        result.put_u32(payload_length as u32);
+        if let Some(id) = msg.id() {
+            result.put_u8(id);
+        }
+        match msg {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => {}
+            PeerMessage::Have { piece_index } => result.put_u32(piece_index),
+            PeerMessage::Bitfield(bitfield) => result.put_slice(bitfield.as_ref()),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                result.put_u32(index);
+                result.put_u32(begin);
+                result.put_u32(length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                result.put_u32(index);
+                result.put_u32(begin);
+                result.put_slice(block.as_ref());
+            }
+            PeerMessage::Port(port) => result.put_u16(port),
+            PeerMessage::Extended { id, payload } => {
+                result.put_u8(id);
+                result.put_slice(payload.as_ref());
+            }
+        }
+        result
+    }
+}
+
+/// Deserialize peer message from bytes (without the length prefix) to enum.
+impl TryFrom<Bytes> for PeerMessage {
+    type Error = Error;
+    fn try_from(mut raw: Bytes) -> Result<Self, Self::Error> {
+        if raw.is_empty() {
+            return Ok(PeerMessage::KeepAlive);
+        }
+        let id = raw.get_u8();
+        let expect_length = |length: usize| {
+            if raw.len() != length {
+                return Err(Error::InvalidMessagePayloadLength(id, raw.len()));
+            }
+            Ok(())
+        };
+        match id {
+            MESSAGE_ID_CHOKE => expect_length(0).map(|_| PeerMessage::Choke),
+            MESSAGE_ID_UNCHOKE => expect_length(0).map(|_| PeerMessage::Unchoke),
+            MESSAGE_ID_INTERESTED => expect_length(0).map(|_| PeerMessage::Interested),
+            MESSAGE_ID_NOT_INTERESTED => expect_length(0).map(|_| PeerMessage::NotInterested),
+            MESSAGE_ID_HAVE => {
+                expect_length(4)?;
+                Ok(PeerMessage::Have {
+                    piece_index: raw.get_u32(),
+                })
+            }
+            MESSAGE_ID_BITFIELD => Ok(PeerMessage::Bitfield(raw)),
+            MESSAGE_ID_REQUEST | MESSAGE_ID_CANCEL => {
+                expect_length(12)?;
+                let (index, begin, length) = (raw.get_u32(), raw.get_u32(), raw.get_u32());
+                if id == MESSAGE_ID_REQUEST {
+                    Ok(PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    })
+                } else {
+                    Ok(PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    })
+                }
+            }
+            MESSAGE_ID_PIECE => {
+                if raw.len() < 8 {
+                    return Err(Error::InvalidMessagePayloadLength(id, raw.len()));
+                }
+                Ok(PeerMessage::Piece {
+                    index: raw.get_u32(),
+                    begin: raw.get_u32(),
+                    block: raw,
+                })
+            }
+            MESSAGE_ID_PORT => {
+                expect_length(2)?;
+                Ok(PeerMessage::Port(raw.get_u16()))
+            }
+            MESSAGE_ID_EXTENDED => {
+                if raw.is_empty() {
+                    return Err(Error::InvalidMessagePayloadLength(id, raw.len()));
+                }
+                Ok(PeerMessage::Extended {
+                    id: raw.get_u8(),
+                    payload: raw,
+                })
+            }
+            id => Err(Error::UnknownMessageId(id)),
+        }
+    }
+}
+
+/// Set of pieces a peer has, as sent in [`PeerMessage::Bitfield`] (the high bit in the first byte is piece 0).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// Constructs an empty [`Bitfield`] for `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Constructs a [`Bitfield`] for `len` pieces from raw bytes, bits after `len` are ignored.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut result = Self::new(len);
+        let count = result.bytes.len().min(bytes.len());
+        result.bytes[..count].copy_from_slice(&bytes[..count]);
+        if !len.is_multiple_of(8) {
+            if let Some(last) = result.bytes.last_mut() {
+                *last &= 0xff << (8 - len % 8);
+            }
+        }
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.as_slice()
     }
 }
 
 /// A peer connection wrapper, that should contain a transport implementation (see: [`Transport`])
 /// to perform Peer Wire protocol (https://wiki.theory.org/BitTorrentSpecification#Peer_wire_protocol_.28TCP.29) operations.
+/// The transport is split into a read and a write half with a lock each, so [`Self::send`] doesn't wait
+/// for a pending [`Self::recv`] of another task.
 pub struct PeerConnection<S>
 where
     S: Transport,
 {
-    stream: Arc<Mutex<S>>,
+    reader: Mutex<ReadHalf<S>>,
+    writer: Mutex<WriteHalf<S>>,
+    peer_addr: Option<SocketAddr>,
+    read_buffer: Mutex<BytesMut>,
     io_timeout: Duration,
+    peer_reserved: ReservedFlags,
+    local_extensions: ExtendedHandshake,
+    peer_extensions: std::sync::Mutex<Option<ExtendedHandshake>>,
 }
 
 impl<T: Transport> PeerConnection<T> {
     pub fn new(stream: T, io_timeout: Duration) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        let (reader, writer) = io::split(stream);
         Self {
-            stream: Arc::new(Mutex::new(stream)),
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+            peer_addr,
+            read_buffer: Mutex::new(BytesMut::new()),
             io_timeout,
+            peer_reserved: ReservedFlags::empty(),
+            local_extensions: ExtendedHandshake::default(),
+            peer_extensions: std::sync::Mutex::new(None),
         }
     }
-    pub fn stream(&self) -> Arc<Mutex<T>> {
-        self.stream.clone()
+
+    /// Address of the peer, fails if the transport wasn't connected when the connection was created.
+    pub fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.peer_addr
+            .ok_or_else(|| Error::ConnectionFailure(io::ErrorKind::NotConnected.into()))
+    }
+
+    /// Registers an extension (e.g. `ut_metadata`) to be advertised in the extended handshake
+    /// and returns the extended message ID the peer has to use when sending its messages to us.
+    /// Extensions must be registered before [`Self::handshake`].
+    pub fn register_extension(&mut self, name: &str) -> u8 {
+        if let Some(id) = self.local_extension_id(name) {
+            return id;
+        }
+        let id = self.local_extensions.m.len() as u8 + 1;
+        self.local_extensions.m.insert(name.to_string(), id as i64);
+        id
+    }
+
+    /// Sets the size of the info dictionary advertised in the extended handshake, if we have it.
+    pub fn set_metadata_size(&mut self, metadata_size: usize) {
+        self.local_extensions.metadata_size = Some(metadata_size as i64);
+    }
+
+    /// Extended message ID of an extension registered with [`Self::register_extension`].
+    pub fn local_extension_id(&self, name: &str) -> Option<u8> {
+        self.local_extensions.extension_id(name)
+    }
+
+    /// Reserved bits the peer sent in its handshake.
+    pub fn peer_reserved(&self) -> ReservedFlags {
+        self.peer_reserved
+    }
+
+    /// Whether both sides support the extension protocol.
+    pub fn supports_extensions(&self) -> bool {
+        self.peer_reserved
+            .intersection(SUPPORTED_RESERVED_FLAGS)
+            .contains(ReservedFlags::EXTENSION_PROTOCOL)
+    }
+
+    /// Extended handshake received from the peer, if any yet.
+    pub fn peer_extended_handshake(&self) -> Option<ExtendedHandshake> {
+        self.peer_extensions.lock().unwrap().clone()
+    }
+
+    /// Extended message ID the peer expects for the given extension.
+    pub fn peer_extension_id(&self, name: &str) -> Option<u8> {
+        self.peer_extensions
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|handshake| handshake.extension_id(name))
+    }
+
+    /// Sends an extension message to the peer using the message ID the peer assigned to the extension.
+    pub async fn send_extended(&self, name: &str, payload: Bytes) -> Result<(), Error> {
+        let id = self
+            .peer_extension_id(name)
+            .ok_or_else(|| Error::ExtensionNotSupported(name.to_string()))?;
+        self.send(PeerMessage::Extended { id, payload }).await
+    }
+
+    /// Receives messages until the peer's extended handshake arrives, other messages are dropped.
+    pub async fn wait_for_extended_handshake(&self) -> Result<ExtendedHandshake, Error> {
+        loop {
+            if let Some(handshake) = self.peer_extended_handshake() {
+                return Ok(handshake);
+            }
+            self.recv().await?;
+        }
+    }
+
+    /// Sends our extended handshake with all registered extensions.
+    async fn send_extended_handshake(&self) -> Result<(), Error> {
+        let mut handshake = self.local_extensions.clone();
+        let peer = self.peer_addr()?;
+        handshake.yourip = Some(match peer.ip() {
+            IpAddr::V4(ip) => ByteBuf::from(ip.octets()),
+            IpAddr::V6(ip) => ByteBuf::from(ip.octets()),
+        });
+        self.send(PeerMessage::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: handshake.to_bytes()?.into(),
+        })
+        .await
     }
 
     /// Send serialized handshake request to peer.
     async fn send_handshake_request(&self, message: &[u8]) -> Result<(), Error> {
-        let stream = self.stream.lock().await;
-        let peer = stream.peer_addr().unwrap();
+        let peer = self.peer_addr()?;
         debug!(
             "[{0}:{1}] start handshake with peer...",
             peer.ip(),
@@ -119,100 +612,55 @@ impl<T: Transport> PeerConnection<T> {
         );
 
         // send handshake message immediately
-        loop {
-            stream.writable().await.map_err(Error::ConnectionFailure)?;
-            match stream.try_write(message) {
-                Ok(_) => {
-                    break;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(error) => {
-                    return Err(Error::ConnectionFailure(error));
-                }
-            }
-        }
-
-        Ok(())
+        let mut writer = self.writer.lock().await;
+        writer
+            .write_all(message)
+            .await
+            .map_err(Error::ConnectionFailure)?;
+        writer.flush().await.map_err(Error::ConnectionFailure)
     }
 
     /// Read handshake message from the live peer connection.
-    /// Important: [`Self::send_handshake_request`] must be called before reading from connection.
-    async fn read_handshake_response(&self, info_hash: Sha1HashBytes) -> Result<(), Error> {
-        let mut stream = self.stream.lock().await;
-        let peer = stream.peer_addr().unwrap();
+    /// Important: the initiator of the connection must call [`Self::send_handshake_request`] before reading from connection.
+    async fn read_handshake(&self) -> Result<HandshakeMessage, Error> {
+        let peer = self.peer_addr()?;
+        let mut reader = self.reader.lock().await;
 
-        let mut last_first_byte_zero_fail: Option<Instant> = None;
-        // wait for handshake message from peer
-        loop {
-            // wait for handshake message's first byte to find out how many bytes we should read
-            let mut buf = [0u8; 1];
-            loop {
-                stream.readable().await.map_err(Error::ConnectionFailure)?;
-                match stream.try_read(&mut buf) {
-                    Ok(_) => {
-                        break;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        continue;
-                    }
-                    Err(error) => {
-                        return Err(Error::ConnectionFailure(error));
-                    }
-                }
+        // wait for handshake message's first byte to find out how many bytes we should read
+        let handshake_first_byte = match reader.read_u8().await {
+            Ok(byte) => byte,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(Error::ConnectionClosed);
             }
+            Err(error) => return Err(Error::ConnectionFailure(error)),
+        };
 
-            if let Some(handshake_protocol_id_length) = buf.first() {
-                let handshake_first_byte = *handshake_protocol_id_length;
-                if handshake_first_byte == 0 {
-                    match last_first_byte_zero_fail {
-                        None => last_first_byte_zero_fail = Some(Instant::now()),
-                        Some(last) => {
-                            if last.elapsed() >= self.io_timeout {
-                                return Err(Error::StreamIoTimeout(self.io_timeout));
-                            } else {
-                                continue;
-                            }
-                        }
-                    }
-                }
-
-                // <protocol ID length> + 49 + 1 (first byte)
-                let handshake_length = handshake_first_byte as usize + 50;
-
-                // wait for handshake message's first byte
-                let mut buf = Vec::with_capacity(handshake_length);
-                buf.push(handshake_first_byte);
-                stream
-                    .read_buf(&mut buf)
-                    .await
-                    .map_err(Error::ConnectionFailure)?;
-                if !buf.is_empty() {
-                    let response_handshake = HandshakeMessage::try_from(buf)?;
-                    debug!(
-                        "[{0}:{1}] handshake response received: {2:?}",
-                        peer.ip(),
-                        peer.port(),
-                        response_handshake
-                    );
-
-                    // validate
-                    if response_handshake.info_hash.as_slice() != info_hash.as_slice() {
-                        debug!("{0:?} != {1:?}", info_hash, response_handshake.info_hash);
-                        return Err(Error::InvalidResponseHandshake(response_handshake));
-                    }
-                    debug!("[{0}:{1}] handshake is valid", peer.ip(), peer.port());
+        // <protocol ID length> + 49 (https://wiki.theory.org/BitTorrentSpecification#Handshake)
+        let handshake_length = handshake_first_byte as usize + 49;
 
-                    break;
-                }
-            }
+        // read the rest of the handshake message, but nothing after it
+        let mut buf = vec![0; handshake_length];
+        buf[0] = handshake_first_byte;
+        reader
+            .read_exact(&mut buf[1..])
+            .await
+            .map_err(Error::ConnectionFailure)?;
+        let handshake = HandshakeMessage::try_from(buf)?;
+        debug!(
+            "[{0}:{1}] handshake received: {2:?}",
+            peer.ip(),
+            peer.port(),
+            handshake
+        );
+        if handshake.protocol_id != DEFAULT_PROTOCOL_ID {
+            return Err(Error::UnsupportedProtocol(handshake.protocol_id));
         }
-
-        Ok(())
+        Ok(handshake)
     }
 
     /// Perform full handshake on a [`PeerConnection`].
+    /// If both sides support the extension protocol, our extended handshake is sent right after it,
+    /// the peer's extended handshake is captured by [`Self::recv`].
     pub async fn handshake(
         &mut self,
         peer_id: String,
@@ -225,10 +673,325 @@ impl<T: Transport> PeerConnection<T> {
         )
         .await
         .map_err(|_| Error::StreamIoTimeout(self.io_timeout))??;
-        tokio::time::timeout(self.io_timeout, self.read_handshake_response(info_hash))
+        let response = tokio::time::timeout(self.io_timeout, self.read_handshake())
             .await
             .map_err(|_| Error::StreamIoTimeout(self.io_timeout))??;
 
+        // validate
+        if response.info_hash != info_hash {
+            debug!("{0:?} != {1:?}", info_hash, response.info_hash);
+            return Err(Error::InvalidResponseHandshake(response));
+        }
+        self.peer_reserved = response.reserved();
+
+        if self.supports_extensions() {
+            self.send_extended_handshake().await?;
+        }
+
         Ok(())
     }
+
+    /// Reads the handshake of a peer which connected to us, without answering it yet.
+    /// The info hash of the returned handshake selects the torrent, then [`Self::respond_handshake`]
+    /// completes the handshake.
+    pub async fn accept_handshake(&mut self) -> Result<HandshakeMessage, Error> {
+        let handshake = tokio::time::timeout(self.io_timeout, self.read_handshake())
+            .await
+            .map_err(|_| Error::StreamIoTimeout(self.io_timeout))??;
+        self.peer_reserved = handshake.reserved();
+        Ok(handshake)
+    }
+
+    /// Answers the handshake read by [`Self::accept_handshake`] with our handshake for `info_hash`,
+    /// followed by our extended handshake if both sides support the extension protocol.
+    pub async fn respond_handshake(
+        &mut self,
+        peer_id: String,
+        info_hash: Sha1HashBytes,
+    ) -> Result<(), Error> {
+        let message: BytesMut = HandshakeMessage::new(peer_id, info_hash, None).into();
+        tokio::time::timeout(
+            self.io_timeout,
+            self.send_handshake_request(message.as_ref()),
+        )
+        .await
+        .map_err(|_| Error::StreamIoTimeout(self.io_timeout))??;
+
+        if self.supports_extensions() {
+            self.send_extended_handshake().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a single [`PeerMessage`] to the peer.
+    pub async fn send(&self, message: PeerMessage) -> Result<(), Error> {
+        let message: BytesMut = message.into();
+        tokio::time::timeout(self.io_timeout, async {
+            let mut writer = self.writer.lock().await;
+            writer.write_all(message.as_ref()).await?;
+            writer.flush().await
+        })
+        .await
+        .map_err(|_| Error::StreamIoTimeout(self.io_timeout))?
+        .map_err(Error::ConnectionFailure)
+    }
+
+    /// Receive the next [`PeerMessage`] from the peer.
+    /// Partially received messages are kept in an internal buffer, so this method is cancellation safe
+    /// and can be used in `tokio::select!`.
+    /// The peer's extended handshake is stored (see [`Self::peer_extended_handshake`]) before it is returned,
+    /// a malformed one is returned without being stored.
+    pub async fn recv(&self) -> Result<PeerMessage, Error> {
+        let mut read_buffer = self.read_buffer.lock().await;
+        loop {
+            if let Some(message) = PeerMessage::decode(&mut read_buffer)? {
+                if let PeerMessage::Extended {
+                    id: EXTENDED_HANDSHAKE_ID,
+                    payload,
+                } = &message
+                {
+                    match ExtendedHandshake::from_bytes(payload.as_ref()) {
+                        Ok(handshake) => *self.peer_extensions.lock().unwrap() = Some(handshake),
+                        Err(error) => debug!("ignoring malformed extended handshake: {error}"),
+                    }
+                }
+                return Ok(message);
+            }
+            let read = self
+                .reader
+                .lock()
+                .await
+                .read_buf(&mut *read_buffer)
+                .await
+                .map_err(Error::ConnectionFailure)?;
+            if read == 0 {
+                return Err(Error::ConnectionClosed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::transport::MemoryTransport;
+
+    fn all_messages() -> Vec<PeerMessage> {
+        vec![
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { piece_index: 42 },
+            PeerMessage::Bitfield(Bytes::from_static(&[0b1010_0000, 0xff])),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 16384,
+                block: Bytes::from_static(b"block data"),
+            },
+            PeerMessage::Cancel {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Port(6881),
+            PeerMessage::Extended {
+                id: 3,
+                payload: Bytes::from_static(b"d8:msg_typei0e5:piecei0ee"),
+            },
+        ]
+    }
+
+    #[test]
+    fn encode_matches_specification() {
+        let encoded: BytesMut = PeerMessage::KeepAlive.into();
+        assert_eq!(encoded.as_ref(), &[0, 0, 0, 0]);
+        let encoded: BytesMut = PeerMessage::Interested.into();
+        assert_eq!(encoded.as_ref(), &[0, 0, 0, 1, 2]);
+        let encoded: BytesMut = PeerMessage::Have { piece_index: 258 }.into();
+        assert_eq!(encoded.as_ref(), &[0, 0, 0, 5, 4, 0, 0, 1, 2]);
+        let encoded: BytesMut = PeerMessage::Port(6881).into();
+        assert_eq!(encoded.as_ref(), &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn encode_decode_roundtrip() {
+        for message in all_messages() {
+            let mut encoded: BytesMut = message.clone().into();
+            let decoded = PeerMessage::decode(&mut encoded).unwrap();
+            assert_eq!(decoded, Some(message));
+            assert!(encoded.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_waits_for_full_message() {
+        let encoded: BytesMut = PeerMessage::Have { piece_index: 7 }.into();
+        let mut partial = BytesMut::from(&encoded[..6]);
+        assert_eq!(PeerMessage::decode(&mut partial).unwrap(), None);
+        partial.extend_from_slice(&encoded[6..]);
+        assert_eq!(
+            PeerMessage::decode(&mut partial).unwrap(),
+            Some(PeerMessage::Have { piece_index: 7 })
+        );
+    }
+
+    #[test]
+    fn decode_rejects_invalid_messages() {
+        let mut unknown = BytesMut::from(&[0, 0, 0, 1, 99][..]);
+        assert!(matches!(
+            PeerMessage::decode(&mut unknown),
+            Err(Error::UnknownMessageId(99))
+        ));
+        let mut invalid_have = BytesMut::from(&[0, 0, 0, 3, 4, 0, 1][..]);
+        assert!(matches!(
+            PeerMessage::decode(&mut invalid_have),
+            Err(Error::InvalidMessagePayloadLength(4, 2))
+        ));
+        let mut too_large = BytesMut::from(&[0xff, 0xff, 0xff, 0xff][..]);
+        assert!(matches!(
+            PeerMessage::decode(&mut too_large),
+            Err(Error::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn bitfield_bit_order() {
+        let mut bitfield = Bitfield::from_bytes(&[0b1000_0001, 0b1111_1111], 10);
+        assert!(bitfield.has(0));
+        assert!(!bitfield.has(1));
+        assert!(bitfield.has(7));
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.count(), 4);
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0001, 0b1100_0000]);
+        bitfield.set(1);
+        assert!(bitfield.has(1));
+    }
+
+    #[tokio::test]
+    async fn send_and_recv_over_transport() {
+        let (local, remote) = MemoryTransport::pair();
+        let local = PeerConnection::new(local, Duration::from_secs(5));
+        let remote = PeerConnection::new(remote, Duration::from_secs(5));
+        for message in all_messages() {
+            local.send(message).await.unwrap();
+        }
+        for message in all_messages() {
+            assert_eq!(remote.recv().await.unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn handshake_keeps_reserved_flags() {
+        let message = HandshakeMessage::new("-RT0100-abcdefghijkl".to_string(), [7; 20], None)
+            .with_reserved(ReservedFlags::EXTENSION_PROTOCOL.union(ReservedFlags::DHT));
+        let encoded: BytesMut = message.into();
+        assert_eq!(&encoded[20..28], &[0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        let decoded = HandshakeMessage::try_from(encoded.to_vec()).unwrap();
+        assert!(decoded
+            .reserved()
+            .contains(ReservedFlags::EXTENSION_PROTOCOL));
+        assert!(decoded.reserved().contains(ReservedFlags::DHT));
+        assert!(!decoded.reserved().contains(ReservedFlags::FAST));
+        assert_eq!(decoded.peer_id(), "-RT0100-abcdefghijkl");
+        assert_eq!(decoded.info_hash(), [7; 20]);
+    }
+
+    #[tokio::test]
+    async fn exchange_extended_handshake() {
+        let (local, remote) = MemoryTransport::pair();
+        let mut local = PeerConnection::new(local, Duration::from_secs(5));
+        let mut remote = PeerConnection::new(remote, Duration::from_secs(5));
+        assert_eq!(local.register_extension("ut_metadata"), 1);
+        assert_eq!(local.register_extension("ut_pex"), 2);
+        assert_eq!(local.register_extension("ut_metadata"), 1);
+        remote.register_extension("ut_pex");
+        remote.set_metadata_size(1234);
+
+        let remote = tokio::spawn(async move {
+            remote
+                .handshake("-RT0100-remotepeer01".to_string(), [1; 20])
+                .await
+                .unwrap();
+            let handshake = remote.wait_for_extended_handshake().await.unwrap();
+            assert_eq!(handshake.extension_id("ut_metadata"), Some(1));
+            assert_eq!(handshake.extension_id("ut_pex"), Some(2));
+            assert_eq!(remote.peer_extension_id("ut_pex"), Some(2));
+            remote
+        });
+        local
+            .handshake("-RT0100-localpeer001".to_string(), [1; 20])
+            .await
+            .unwrap();
+        assert!(local.supports_extensions());
+        let handshake = local.wait_for_extended_handshake().await.unwrap();
+        assert_eq!(handshake.extension_id("ut_pex"), Some(1));
+        assert_eq!(handshake.extension_id("ut_metadata"), None);
+        assert_eq!(handshake.metadata_size, Some(1234));
+        assert_eq!(handshake.reqq, Some(250));
+        assert!(handshake
+            .client_name()
+            .unwrap()
+            .starts_with("bittorrent-client-rs/"));
+        assert_eq!(handshake.your_ip(), Some(IpAddr::from([127, 0, 0, 1])));
+        assert!(matches!(
+            local.send_extended("ut_metadata", Bytes::new()).await,
+            Err(Error::ExtensionNotSupported(_))
+        ));
+        remote.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignore_malformed_extended_handshake() {
+        let (local, remote) = MemoryTransport::pair();
+        let local = PeerConnection::new(local, Duration::from_secs(5));
+        let remote = PeerConnection::new(remote, Duration::from_secs(5));
+        let mut nested = b"d1:m".to_vec();
+        nested.extend(vec![b'l'; 100_000]);
+        nested.extend(vec![b'e'; 100_000]);
+        nested.push(b'e');
+        for payload in [b"not bencode".to_vec(), nested] {
+            let message = PeerMessage::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: Bytes::from(payload),
+            };
+            local.send(message.clone()).await.unwrap();
+            assert_eq!(remote.recv().await.unwrap(), message);
+        }
+        assert!(remote.peer_extended_handshake().is_none());
+        local.send(PeerMessage::Choke).await.unwrap();
+        assert_eq!(remote.recv().await.unwrap(), PeerMessage::Choke);
+    }
+
+    #[tokio::test]
+    async fn send_while_recv_is_pending() {
+        let (local, remote) = MemoryTransport::pair();
+        let local = std::sync::Arc::new(PeerConnection::new(local, Duration::from_secs(5)));
+        let remote = PeerConnection::new(remote, Duration::from_secs(5));
+        let receiver = local.clone();
+        let pending = tokio::spawn(async move { receiver.recv().await });
+        tokio::task::yield_now().await;
+
+        // the idle peer sends nothing, sending must not wait for it
+        local.send(PeerMessage::Interested).await.unwrap();
+        assert_eq!(remote.recv().await.unwrap(), PeerMessage::Interested);
+        remote.send(PeerMessage::Unchoke).await.unwrap();
+        assert_eq!(pending.await.unwrap().unwrap(), PeerMessage::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn recv_fails_on_closed_connection() {
+        let (local, remote) = MemoryTransport::pair();
+        let remote = PeerConnection::new(remote, Duration::from_secs(5));
+        drop(local);
+        assert!(matches!(remote.recv().await, Err(Error::ConnectionClosed)));
+    }
 }
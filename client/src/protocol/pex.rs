@@ -0,0 +1,351 @@
+use crate::protocol::meta_info_file::check_bencode_depth;
+use crate::protocol::tracker::{self, parse_compact_peers, parse_compact_peers6, PeerAddress};
+use bytes::Bytes;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Name of the peer exchange extension in the extended handshake (https://www.bittorrent.org/beps/bep_0011.html).
+pub const UT_PEX: &str = "ut_pex";
+
+/// Peer exchange messages are sent at most once a minute.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of added and dropped peers in a single message, each.
+pub const MAX_PEX_PEERS: usize = 50;
+
+/// Flags of added peers (https://www.bittorrent.org/beps/bep_0011.html#pex-message-format).
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+/// Peer exchange related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid bencoded peer exchange message")]
+    InvalidMessage(#[from] serde_bencode::Error),
+    #[error("peer exchange message is not bencoded or nested too deeply")]
+    MalformedMessage,
+    #[error("malformed peer list")]
+    MalformedPeers(#[from] tracker::Error),
+}
+
+/// Bencoded peer exchange message, peers are in compact form.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct RawPexMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added: Option<ByteBuf>,
+    #[serde(rename = "added.f", default, skip_serializing_if = "Option::is_none")]
+    added_flags: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f", default, skip_serializing_if = "Option::is_none")]
+    added6_flags: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dropped: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dropped6: Option<ByteBuf>,
+}
+
+/// A peer added to the swarm, `flags` is a combination of the `FLAG_*` constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PexPeer {
+    pub address: PeerAddress,
+    pub flags: u8,
+}
+
+/// Peer exchange message: the changes of the sender's connected peers since its previous message
+/// (https://www.bittorrent.org/beps/bep_0011.html).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<PexPeer>,
+    pub dropped: Vec<PeerAddress>,
+}
+
+/// Splits peers into compact IPv4 and IPv6 lists, `None` stands for an empty list.
+fn encode_peers<'a>(
+    peers: impl Iterator<Item = &'a PeerAddress>,
+) -> (Option<ByteBuf>, Option<ByteBuf>) {
+    let (mut v4, mut v6) = (vec![], vec![]);
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(_) => v4.extend(peer.to_compact()),
+            IpAddr::V6(_) => v6.extend(peer.to_compact()),
+        }
+    }
+    let non_empty = |list: Vec<u8>| (!list.is_empty()).then(|| ByteBuf::from(list));
+    (non_empty(v4), non_empty(v6))
+}
+
+/// Compact peer list of an optional message field.
+fn compact(list: &Option<ByteBuf>) -> &[u8] {
+    list.as_deref().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Pairs compact peers with their flags, peers without flags get none.
+fn decode_added(
+    peers: Vec<PeerAddress>,
+    flags: Option<&ByteBuf>,
+) -> impl Iterator<Item = PexPeer> + '_ {
+    peers
+        .into_iter()
+        .enumerate()
+        .map(move |(index, address)| PexPeer {
+            address,
+            flags: flags
+                .and_then(|flags| flags.get(index).copied())
+                .unwrap_or_default(),
+        })
+}
+
+/// Serialize peer exchange message to the payload of [`PeerMessage::Extended`](crate::protocol::peer_wire::PeerMessage::Extended).
+impl TryFrom<PexMessage> for Bytes {
+    type Error = Error;
+    fn try_from(msg: PexMessage) -> Result<Self, Self::Error> {
+        let (v4, v6): (Vec<PexPeer>, Vec<PexPeer>) = msg
+            .added
+            .iter()
+            .partition(|peer| peer.address.ip().is_ipv4());
+        let flags = |peers: &[PexPeer]| {
+            (!peers.is_empty())
+                .then(|| ByteBuf::from(peers.iter().map(|peer| peer.flags).collect::<Vec<u8>>()))
+        };
+        let (added, _) = encode_peers(v4.iter().map(|peer| &peer.address));
+        let (_, added6) = encode_peers(v6.iter().map(|peer| &peer.address));
+        let (dropped, dropped6) = encode_peers(msg.dropped.iter());
+        let raw = RawPexMessage {
+            added,
+            added_flags: flags(&v4),
+            added6,
+            added6_flags: flags(&v6),
+            dropped,
+            dropped6,
+        };
+        Ok(serde_bencode::to_bytes(&raw)?.into())
+    }
+}
+
+/// Deserialize peer exchange message from the payload of [`PeerMessage::Extended`](crate::protocol::peer_wire::PeerMessage::Extended).
+impl TryFrom<Bytes> for PexMessage {
+    type Error = Error;
+    fn try_from(raw: Bytes) -> Result<Self, Self::Error> {
+        check_bencode_depth(raw.as_ref()).map_err(|_| Error::MalformedMessage)?;
+        let raw: RawPexMessage = serde_bencode::from_bytes(raw.as_ref())?;
+        let mut added: Vec<PexPeer> = decode_added(
+            parse_compact_peers(compact(&raw.added))?,
+            raw.added_flags.as_ref(),
+        )
+        .collect();
+        added.extend(decode_added(
+            parse_compact_peers6(compact(&raw.added6))?,
+            raw.added6_flags.as_ref(),
+        ));
+        let mut dropped = parse_compact_peers(compact(&raw.dropped))?;
+        dropped.extend(parse_compact_peers6(compact(&raw.dropped6))?);
+        Ok(Self { added, dropped })
+    }
+}
+
+/// Peer exchange state of a single connection: the peers the remote peer already knows from us,
+/// and the timestamps limiting how often messages are sent and accepted.
+#[derive(Debug, Default)]
+pub struct PexState {
+    sent: HashSet<PeerAddress>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the next message for the remote peer if [`PEX_INTERVAL`] elapsed since the previous one:
+    /// peers `connected` since then are added (except the remote peer itself), disconnected ones are dropped.
+    /// `flags` are sent along with all added peers. Returns `None` if nothing changed.
+    pub fn next_message(
+        &mut self,
+        connected: &HashSet<PeerAddress>,
+        remote: PeerAddress,
+        flags: u8,
+        now: Instant,
+    ) -> Option<PexMessage> {
+        if self
+            .last_sent
+            .is_some_and(|last_sent| now.duration_since(last_sent) < PEX_INTERVAL)
+        {
+            return None;
+        }
+        let added: Vec<PexPeer> = connected
+            .iter()
+            .filter(|peer| **peer != remote && !self.sent.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .map(|address| PexPeer {
+                address: *address,
+                flags,
+            })
+            .collect();
+        let dropped: Vec<PeerAddress> = self
+            .sent
+            .iter()
+            .filter(|peer| !connected.contains(peer))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+        for peer in &dropped {
+            self.sent.remove(peer);
+        }
+        self.sent.extend(added.iter().map(|peer| peer.address));
+        self.last_sent = Some(now);
+        Some(PexMessage { added, dropped })
+    }
+
+    /// Returns the peers worth connecting to from a received message. Peers sending messages
+    /// more often than every half [`PEX_INTERVAL`] are ignored, and at most [`MAX_PEX_PEERS`]
+    /// are taken from a single message.
+    pub fn receive(&mut self, message: PexMessage, now: Instant) -> Vec<PeerAddress> {
+        if self
+            .last_received
+            .is_some_and(|last_received| now.duration_since(last_received) < PEX_INTERVAL / 2)
+        {
+            return vec![];
+        }
+        self.last_received = Some(now);
+        message
+            .added
+            .into_iter()
+            .map(|peer| peer.address)
+            .filter(|address| address.port() != 0 && !address.ip().is_unspecified())
+            .take(MAX_PEX_PEERS)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> PeerAddress {
+        PeerAddress::from(address.parse::<std::net::SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn pex_message_roundtrip() {
+        let message = PexMessage {
+            added: vec![
+                PexPeer {
+                    address: peer("1.2.3.4:6881"),
+                    flags: FLAG_SEED | FLAG_REACHABLE,
+                },
+                PexPeer {
+                    address: peer("[2001:db8::1]:6882"),
+                    flags: FLAG_SUPPORTS_UTP,
+                },
+            ],
+            dropped: vec![peer("5.6.7.8:6883")],
+        };
+        let bytes: Bytes = message.clone().try_into().unwrap();
+        assert_eq!(
+            bytes.as_ref(),
+            b"d5:added6:\x01\x02\x03\x04\x1a\xe17:added.f1:\x126:added618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe28:added6.f1:\x047:dropped6:\x05\x06\x07\x08\x1a\xe3e"
+        );
+        assert_eq!(PexMessage::try_from(bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn missing_flags_and_malformed_peers() {
+        let message =
+            PexMessage::try_from(Bytes::from_static(b"d5:added6:\x01\x02\x03\x04\x1a\xe1e"))
+                .unwrap();
+        assert_eq!(
+            message.added,
+            vec![PexPeer {
+                address: peer("1.2.3.4:6881"),
+                flags: 0,
+            }]
+        );
+        assert!(matches!(
+            PexMessage::try_from(Bytes::from_static(b"d5:added5:\x01\x02\x03\x04\x1ae")),
+            Err(Error::MalformedPeers(_))
+        ));
+
+        let mut nested = b"d5:added".to_vec();
+        nested.extend(vec![b'l'; 100_000]);
+        nested.extend(vec![b'e'; 100_000]);
+        nested.push(b'e');
+        assert!(matches!(
+            PexMessage::try_from(Bytes::from(nested)),
+            Err(Error::MalformedMessage)
+        ));
+    }
+
+    #[test]
+    fn send_changes_at_most_once_per_interval() {
+        let remote = peer("9.9.9.9:1");
+        let mut connected = HashSet::from([remote, peer("1.1.1.1:1"), peer("2.2.2.2:2")]);
+        let mut state = PexState::new();
+        let start = Instant::now();
+
+        let first = state
+            .next_message(&connected, remote, FLAG_REACHABLE, start)
+            .unwrap();
+        assert_eq!(first.added.len(), 2);
+        assert!(first.dropped.is_empty());
+
+        connected.remove(&peer("1.1.1.1:1"));
+        connected.insert(peer("3.3.3.3:3"));
+        let later = start + PEX_INTERVAL / 2;
+        assert!(state
+            .next_message(&connected, remote, FLAG_REACHABLE, later)
+            .is_none());
+
+        let later = start + PEX_INTERVAL;
+        let second = state
+            .next_message(&connected, remote, FLAG_REACHABLE, later)
+            .unwrap();
+        assert_eq!(
+            second.added,
+            vec![PexPeer {
+                address: peer("3.3.3.3:3"),
+                flags: FLAG_REACHABLE,
+            }]
+        );
+        assert_eq!(second.dropped, vec![peer("1.1.1.1:1")]);
+        assert!(state
+            .next_message(&connected, remote, FLAG_REACHABLE, later + PEX_INTERVAL)
+            .is_none());
+    }
+
+    #[test]
+    fn rate_limit_received_peers() {
+        let mut state = PexState::new();
+        let start = Instant::now();
+        let message = PexMessage {
+            added: (0..100)
+                .map(|port| PexPeer {
+                    address: peer(&format!("1.2.3.4:{port}")),
+                    flags: 0,
+                })
+                .collect(),
+            dropped: vec![],
+        };
+        let peers = state.receive(message.clone(), start);
+        assert_eq!(peers.len(), MAX_PEX_PEERS);
+        assert!(!peers.contains(&peer("1.2.3.4:0")));
+        assert!(state
+            .receive(message.clone(), start + PEX_INTERVAL / 4)
+            .is_empty());
+        assert_eq!(
+            state.receive(message, start + PEX_INTERVAL).len(),
+            MAX_PEX_PEERS
+        );
+    }
+}
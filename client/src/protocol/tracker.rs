@@ -1,11 +1,13 @@
-use crate::protocol::meta_info_file::Sha1HashBytes;
+use crate::protocol::meta_info_file::{check_bencode_depth, Sha1HashBytes};
 use byteorder::{BigEndian, ReadBytesExt};
+use rand::seq::SliceRandom;
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::io::Cursor;
-use std::net::{AddrParseError, IpAddr};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use thiserror::Error;
 use urlencoding::encode_binary;
 
@@ -19,6 +21,13 @@ const COMPACT_PEER_ADDRESS_PORT_BYTES_LENGTH: usize = 2;
 const COMPACT_PEER_ADDRESS_BYTES_LENGTH: usize =
     COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH + COMPACT_PEER_ADDRESS_PORT_BYTES_LENGTH;
 
+/// Compact IPv6 peers list's (`peers6`) peer address IP bytes length (https://www.bittorrent.org/beps/bep_0007.html).
+const COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH: usize = 16;
+
+/// Compact IPv6 peers list's (`peers6`) peer address bytes length (https://www.bittorrent.org/beps/bep_0007.html).
+const COMPACT_PEER6_ADDRESS_BYTES_LENGTH: usize =
+    COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH + COMPACT_PEER_ADDRESS_PORT_BYTES_LENGTH;
+
 /// Errors from tracker related operations.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -28,6 +37,12 @@ pub enum Error {
     PeerAddressInvalidLength(usize),
     #[error("I/O error")]
     IO(#[from] io::Error),
+    #[error("tracker failure: {0}")]
+    TrackerFailure(String),
+    #[error("invalid tracker response")]
+    InvalidResponse(#[from] serde_bencode::Error),
+    #[error("tracker does not support scrape: {0}")]
+    ScrapeNotSupported(String),
 }
 
 /// Tracker URL.
@@ -40,7 +55,10 @@ pub struct TrackerUrl {
     bytes_downloaded: usize,
     left_bytes: usize,
     compact: bool,
-    tracker_id: Option<usize>,
+    event: AnnounceEvent,
+    tracker_id: Option<String>,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
 }
 
 #[allow(dead_code)]
@@ -55,7 +73,10 @@ impl TrackerUrl {
             bytes_downloaded: 0,
             left_bytes: 0,
             compact: false,
+            event: AnnounceEvent::None,
             tracker_id: None,
+            ipv4: None,
+            ipv6: None,
         }
     }
 
@@ -93,15 +114,32 @@ impl TrackerUrl {
         self
     }
 
-    pub fn with_tracker_id(mut self, tracker_id: Option<usize>) -> Self {
+    pub fn with_event(mut self, event: AnnounceEvent) -> Self {
+        self.event = event;
+        self
+    }
+
+    pub fn with_tracker_id(mut self, tracker_id: Option<String>) -> Self {
         self.tracker_id = tracker_id;
         self
     }
+
+    /// Sets the IPv4 address reported to the tracker besides the one the request comes from (https://www.bittorrent.org/beps/bep_0007.html).
+    pub fn with_ipv4(mut self, ipv4: Option<Ipv4Addr>) -> Self {
+        self.ipv4 = ipv4;
+        self
+    }
+
+    /// Sets the IPv6 address reported to the tracker besides the one the request comes from (https://www.bittorrent.org/beps/bep_0007.html).
+    pub fn with_ipv6(mut self, ipv6: Option<Ipv6Addr>) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
 }
 
 /// Serialize [`TrackerUrl`] into a full URL.
-impl ToString for TrackerUrl {
-    fn to_string(&self) -> String {
+impl fmt::Display for TrackerUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let compact = self.compact as i32;
         let compact = compact.to_string();
         let mut query_params = HashMap::from([
@@ -116,18 +154,188 @@ impl ToString for TrackerUrl {
             ("left", self.left_bytes.to_string()),
             ("compact", compact),
         ]);
-        if let Some(tracker_id) = self.tracker_id {
-            query_params.insert("trackerid", tracker_id.to_string());
+        if let Some(event) = self.event.as_str() {
+            query_params.insert("event", event.to_string());
+        }
+        if let Some(ipv4) = self.ipv4 {
+            query_params.insert("ipv4", ipv4.to_string());
+        }
+        if let Some(ipv6) = self.ipv6 {
+            query_params.insert(
+                "ipv6",
+                encode_binary(ipv6.to_string().as_bytes()).to_string(),
+            );
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query_params.insert(
+                "trackerid",
+                encode_binary(tracker_id.as_bytes()).to_string(),
+            );
         }
         let query_params: Vec<String> = query_params
             .iter()
             .map(|(k, v)| format!("{0}={1}", k, v))
             .collect();
         let query_params = query_params.join("&");
-        format!("{0}?{1}", self.announce_url, query_params)
+        // private trackers put passkeys into the query of the announce URL
+        let separator = if self.announce_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        write!(f, "{0}{1}{2}", self.announce_url, separator, query_params)
+    }
+}
+
+/// Tracker URLs grouped into tiers (https://www.bittorrent.org/beps/bep_0012.html).
+/// Tiers are tried in order, the URLs within a tier are shuffled once and the first
+/// responsive URL is moved to the front of its tier.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    /// Constructs tiers from `announce-list`, shuffling the URLs of each tier. Empty tiers are dropped.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = rand::thread_rng();
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
+        Self { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// All URLs in the order they should be tried.
+    pub fn urls(&self) -> Vec<String> {
+        self.tiers.iter().flatten().cloned().collect()
+    }
+
+    /// Moves `url` to the front of its tier after it responded successfully.
+    pub fn promote(&mut self, url: &str) {
+        for tier in self.tiers.iter_mut() {
+            if let Some(position) = tier.iter().position(|tier_url| tier_url == url) {
+                let url = tier.remove(position);
+                tier.insert(0, url);
+                return;
+            }
+        }
+    }
+}
+
+/// Announce event reported to the tracker (https://wiki.theory.org/BitTorrentSpecification#Tracker_Request_Parameters).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum AnnounceEvent {
+    /// Regular announce performed in intervals.
+    #[default]
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the `event` query parameter, regular announces omit it.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+}
+
+/// Derives the scrape URL from an HTTP announce URL: the last path segment must start with
+/// `announce`, which is replaced by `scrape` (https://wiki.theory.org/BitTorrentSpecification#Tracker_.27scrape.27_Convention).
+pub fn scrape_url(announce_url: &str) -> Result<String, Error> {
+    let not_supported = || Error::ScrapeNotSupported(announce_url.to_string());
+    let path_end = announce_url.find('?').unwrap_or(announce_url.len());
+    let segment_start = announce_url[..path_end]
+        .rfind('/')
+        .ok_or_else(not_supported)?
+        + 1;
+    let rest = announce_url[segment_start..]
+        .strip_prefix("announce")
+        .ok_or_else(not_supported)?;
+    Ok(format!(
+        "{0}scrape{1}",
+        &announce_url[..segment_start],
+        rest
+    ))
+}
+
+/// Full scrape request URL asking for the statistics of all `info_hashes` at once.
+pub fn scrape_request_url(scrape_url: &str, info_hashes: &[Sha1HashBytes]) -> String {
+    let query_params: Vec<String> = info_hashes
+        .iter()
+        .map(|info_hash| format!("info_hash={0}", encode_binary(info_hash)))
+        .collect();
+    let separator = if scrape_url.contains('?') { '&' } else { '?' };
+    format!("{0}{1}{2}", scrape_url, separator, query_params.join("&"))
+}
+
+/// Scrape URL response struct.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct ScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+impl ScrapeResponse {
+    /// Parses a bencoded scrape response, `failure reason` is returned as [`Error::TrackerFailure`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        check_response_depth(data)?;
+        let status: TrackerResponseStatus = serde_bencode::from_bytes(data)?;
+        if let Some(reason) = status.failure_reason {
+            return Err(Error::TrackerFailure(reason));
+        }
+        Ok(serde_bencode::from_bytes(data)?)
+    }
+
+    /// Statistics of all torrents in the response by info hash, entries with invalid info hashes are skipped.
+    pub fn files(&self) -> HashMap<Sha1HashBytes, ScrapeStats> {
+        self.files
+            .iter()
+            .filter_map(|(info_hash, stats)| {
+                Some((Sha1HashBytes::try_from(info_hash.as_slice()).ok()?, *stats))
+            })
+            .collect()
     }
 }
 
+/// Statistics of a single torrent returned by the tracker's scrape convention
+/// (https://wiki.theory.org/BitTorrentSpecification#Tracker_.27scrape.27_Convention).
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+#[serde(default)]
+pub struct ScrapeStats {
+    /// Number of seeders.
+    pub complete: usize,
+    /// Number of times the download was completed.
+    pub downloaded: usize,
+    /// Number of leechers.
+    pub incomplete: usize,
+}
+
+/// Fields of a tracker response which are present even if the request failed.
+#[derive(Deserialize)]
+struct TrackerResponseStatus {
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+}
+
 /// Announce URL response struct.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Hash)]
 pub struct AnnounceResponse {
@@ -142,26 +350,45 @@ pub struct AnnounceResponse {
     tracker_id: Option<String>,
     complete: Option<usize>,
     incomplete: Option<usize>,
+    #[serde(default)]
     peers: AnnounceResponsePeers,
+    /// Compact IPv6 peers (https://www.bittorrent.org/beps/bep_0007.html).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
 }
 
 /// Address of a peer.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PeerAddress {
     ip: IpAddr,
     port: u16,
 }
 
 /// Serialize [`PeerAddress`] as [`String`].
-impl ToString for PeerAddress {
-    fn to_string(&self) -> String {
-        format!("{0}:{1}", self.ip(), self.port())
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{0}", self.socket_addr())
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> Self {
+        Self::new(address.ip(), address.port())
     }
 }
 
 impl PeerAddress {
+    /// IPv4-mapped IPv6 addresses are stored as IPv4, so the same peer is equal regardless of
+    /// the address family it was received with.
     pub const fn new(ip: IpAddr, port: u16) -> Self {
-        Self { ip, port }
+        Self {
+            ip: ip.to_canonical(),
+            port,
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
     }
 
     pub fn ip(&self) -> IpAddr {
@@ -170,12 +397,91 @@ impl PeerAddress {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// Compact form of the address: 4 (IPv4) or 16 (IPv6) bytes IP address and 2 bytes port,
+    /// both in network byte order (see [`parse_compact_peers`] and [`parse_compact_peers6`]).
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut result = match self.ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        result.extend_from_slice(&self.port.to_be_bytes());
+        result
+    }
 }
 
 impl AnnounceResponse {
-    /// Parse peers from [`AnnounceResponse`] as [`PeerAddress`].
+    /// Constructs [`AnnounceResponse`] from the fields of a binary tracker response (e.g. UDP trackers).
+    /// `peers` contains compact IPv6 addresses if `ipv6` is set, compact IPv4 addresses otherwise.
+    pub fn from_compact(
+        interval: usize,
+        complete: usize,
+        incomplete: usize,
+        peers: Vec<u8>,
+        ipv6: bool,
+    ) -> Self {
+        let (peers, peers6) = if ipv6 {
+            (AnnounceResponsePeers::default(), Some(ByteBuf::from(peers)))
+        } else {
+            (AnnounceResponsePeers::Compact(ByteBuf::from(peers)), None)
+        };
+        Self {
+            failure_reason: None,
+            warning_message: None,
+            interval,
+            min_interval: None,
+            tracker_id: None,
+            complete: Some(complete),
+            incomplete: Some(incomplete),
+            peers,
+            peers6,
+        }
+    }
+
+    /// Parses a bencoded tracker response. If the tracker sent `failure reason`, no other keys
+    /// are required and the reason is returned as [`Error::TrackerFailure`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        check_response_depth(data)?;
+        let status: TrackerResponseStatus = serde_bencode::from_bytes(data)?;
+        if let Some(reason) = status.failure_reason {
+            return Err(Error::TrackerFailure(reason));
+        }
+        Ok(serde_bencode::from_bytes(data)?)
+    }
+
+    /// Human-readable warning of the tracker, the response is processed normally.
+    pub fn warning_message(&self) -> Option<&str> {
+        self.warning_message.as_deref()
+    }
+
+    /// Number of seconds the client should wait between regular announces.
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// Minimum number of seconds between announces, if the tracker enforces it.
+    pub fn min_interval(&self) -> Option<usize> {
+        self.min_interval
+    }
+
+    /// Tracker ID that must be sent back on subsequent announces.
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    /// Number of seeders.
+    pub fn complete(&self) -> Option<usize> {
+        self.complete
+    }
+
+    /// Number of leechers.
+    pub fn incomplete(&self) -> Option<usize> {
+        self.incomplete
+    }
+
+    /// Parse peers from [`AnnounceResponse`] as [`PeerAddress`], including IPv6 peers of `peers6`.
     pub fn peers(&self) -> Result<Vec<PeerAddress>, Error> {
-        match &self.peers {
+        let mut peers = match &self.peers {
             AnnounceResponsePeers::Raw(peers) => peers
                 .iter()
                 .map(|peer| {
@@ -185,31 +491,59 @@ impl AnnounceResponse {
                         .map_err(Error::IPAddressParseFailed)?;
                     Ok(PeerAddress::new(ip, peer.port))
                 })
-                .collect(),
-            AnnounceResponsePeers::Compact(data) => data
-                .chunks(COMPACT_PEER_ADDRESS_BYTES_LENGTH)
-                .map(|parts| {
-                    if parts.len() != COMPACT_PEER_ADDRESS_BYTES_LENGTH {
-                        return Err(Error::PeerAddressInvalidLength(parts.len()));
-                    }
-                    let mut ip_bytes: [u8; COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH] =
-                        [0; COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH];
-                    ip_bytes.copy_from_slice(&parts[0..COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH]);
-                    let ip = IpAddr::from(ip_bytes);
-                    let port = Cursor::new(
-                        &parts[COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH
-                            ..COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH
-                                + COMPACT_PEER_ADDRESS_PORT_BYTES_LENGTH],
-                    )
-                    .read_u16::<BigEndian>()
-                    .map_err(Error::IO)?;
-                    Ok(PeerAddress::new(ip, port))
-                })
-                .collect(),
+                .collect::<Result<Vec<_>, Error>>()?,
+            AnnounceResponsePeers::Compact(data) => parse_compact_peers(data)?,
+        };
+        if let Some(data) = &self.peers6 {
+            peers.extend(parse_compact_peers6(data)?);
         }
+        Ok(peers)
     }
 }
 
+/// Rejects tracker responses nested deeper than the recursive `serde_bencode` parser can handle.
+fn check_response_depth(data: &[u8]) -> Result<(), Error> {
+    check_bencode_depth(data)
+        .map_err(|error| Error::InvalidResponse(serde_bencode::Error::Custom(error.to_string())))
+}
+
+/// Parses compact IPv4 peers: 4 bytes IP address and 2 bytes port, both in network byte order.
+pub fn parse_compact_peers(data: &[u8]) -> Result<Vec<PeerAddress>, Error> {
+    data.chunks(COMPACT_PEER_ADDRESS_BYTES_LENGTH)
+        .map(|parts| {
+            if parts.len() != COMPACT_PEER_ADDRESS_BYTES_LENGTH {
+                return Err(Error::PeerAddressInvalidLength(parts.len()));
+            }
+            let mut ip_bytes: [u8; COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH] =
+                [0; COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH];
+            ip_bytes.copy_from_slice(&parts[0..COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH]);
+            let port = Cursor::new(&parts[COMPACT_PEER_ADDRESS_IP_BYTES_LENGTH..])
+                .read_u16::<BigEndian>()
+                .map_err(Error::IO)?;
+            Ok(PeerAddress::new(IpAddr::from(ip_bytes), port))
+        })
+        .collect()
+}
+
+/// Parses compact IPv6 peers: 16 bytes IP address and 2 bytes port, both in network byte order
+/// (https://www.bittorrent.org/beps/bep_0007.html).
+pub fn parse_compact_peers6(data: &[u8]) -> Result<Vec<PeerAddress>, Error> {
+    data.chunks(COMPACT_PEER6_ADDRESS_BYTES_LENGTH)
+        .map(|parts| {
+            if parts.len() != COMPACT_PEER6_ADDRESS_BYTES_LENGTH {
+                return Err(Error::PeerAddressInvalidLength(parts.len()));
+            }
+            let mut ip_bytes: [u8; COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH] =
+                [0; COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH];
+            ip_bytes.copy_from_slice(&parts[0..COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH]);
+            let port = Cursor::new(&parts[COMPACT_PEER6_ADDRESS_IP_BYTES_LENGTH..])
+                .read_u16::<BigEndian>()
+                .map_err(Error::IO)?;
+            Ok(PeerAddress::new(IpAddr::from(ip_bytes), port))
+        })
+        .collect()
+}
+
 /// [`AnnounceResponse`] peers enum, that can be compact (binary)/non-compact (bencoded (https://wiki.theory.org/BitTorrentSpecification#Bencoding)).
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Hash)]
 #[serde(untagged)]
@@ -218,6 +552,13 @@ pub enum AnnounceResponsePeers {
     Compact(ByteBuf),
 }
 
+/// Responses with IPv6 peers only may omit `peers`.
+impl Default for AnnounceResponsePeers {
+    fn default() -> Self {
+        AnnounceResponsePeers::Compact(ByteBuf::new())
+    }
+}
+
 /// Bencoded (https://wiki.theory.org/BitTorrentSpecification#Bencoding) [`AnnounceResponse`] peers data.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Hash)]
 pub struct AnnounceResponsePeersRaw {
@@ -226,3 +567,239 @@ pub struct AnnounceResponsePeersRaw {
     ip: String,
     port: u16,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers() -> Vec<Vec<String>> {
+        vec![
+            vec![
+                "udp://a1".to_string(),
+                "udp://a2".to_string(),
+                "udp://a3".to_string(),
+            ],
+            vec![],
+            vec!["http://b1".to_string()],
+        ]
+    }
+
+    #[test]
+    fn failure_reason_without_other_keys() {
+        match AnnounceResponse::from_bytes(b"d14:failure reason17:torrent not founde") {
+            Err(Error::TrackerFailure(reason)) => assert_eq!(reason, "torrent not found"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn failure_reason_takes_precedence_over_peers() {
+        let response = b"d14:failure reason6:banned8:intervali1800e5:peers0:e";
+        assert!(matches!(
+            AnnounceResponse::from_bytes(response),
+            Err(Error::TrackerFailure(reason)) if reason == "banned"
+        ));
+    }
+
+    #[test]
+    fn warning_message_with_peers() {
+        let response = AnnounceResponse::from_bytes(
+            b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe115:warning message12:slow down!!!e",
+        )
+        .unwrap();
+        assert_eq!(response.warning_message(), Some("slow down!!!"));
+        assert_eq!(response.interval(), 900);
+        assert_eq!(response.peers().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn invalid_response_without_failure_reason() {
+        assert!(matches!(
+            AnnounceResponse::from_bytes(b"d5:peers0:e"),
+            Err(Error::InvalidResponse(_))
+        ));
+        assert!(matches!(
+            AnnounceResponse::from_bytes(b"<html>"),
+            Err(Error::InvalidResponse(_))
+        ));
+
+        let mut nested = b"d1:x".to_vec();
+        nested.extend(vec![b'l'; 200_000]);
+        nested.extend(vec![b'e'; 200_000]);
+        nested.push(b'e');
+        assert!(matches!(
+            AnnounceResponse::from_bytes(&nested),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn announce_url_with_query() {
+        let url = TrackerUrl::new(
+            "http://tracker/announce?passkey=secret".to_string(),
+            "id".to_string(),
+        )
+        .to_string();
+        assert!(url.starts_with("http://tracker/announce?passkey=secret&"));
+        assert_eq!(url.matches('?').count(), 1);
+    }
+
+    #[test]
+    fn parse_ipv4_and_ipv6_peers() {
+        let mut response = b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers636:".to_vec();
+        response.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        response.extend_from_slice(&[0x1a, 0xe2]);
+        response.extend_from_slice(&Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped().octets());
+        response.extend_from_slice(&[0x1a, 0xe3]);
+        response.extend_from_slice(b"e");
+        let peers: Vec<String> = AnnounceResponse::from_bytes(&response)
+            .unwrap()
+            .peers()
+            .unwrap()
+            .iter()
+            .map(|peer| peer.to_string())
+            .collect();
+        assert_eq!(peers, vec!["10.0.0.1:6881", "[::1]:6882", "10.0.0.2:6883"]);
+
+        let response = b"d8:intervali900e6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e";
+        let peers = AnnounceResponse::from_bytes(response)
+            .unwrap()
+            .peers()
+            .unwrap();
+        assert_eq!(
+            peers,
+            vec![PeerAddress::new(Ipv6Addr::LOCALHOST.into(), 6881)]
+        );
+
+        let response = b"d8:intervali900e5:peers0:6:peers65:\x00\x00\x00\x00\x00e";
+        assert!(matches!(
+            AnnounceResponse::from_bytes(response).unwrap().peers(),
+            Err(Error::PeerAddressInvalidLength(5))
+        ));
+    }
+
+    #[test]
+    fn parse_raw_ipv6_peers() {
+        let response =
+            b"d8:intervali900e5:peersld2:ip3:::17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881eeee";
+        let peers = AnnounceResponse::from_bytes(response)
+            .unwrap()
+            .peers()
+            .unwrap();
+        assert_eq!(peers[0].to_string(), "[::1]:6881");
+    }
+
+    #[test]
+    fn announce_url_with_addresses() {
+        let url = TrackerUrl::new("http://tracker/announce".to_string(), "id".to_string())
+            .with_ipv4(Some(Ipv4Addr::new(1, 2, 3, 4)))
+            .with_ipv6(Some("2001:db8::1".parse().unwrap()))
+            .to_string();
+        assert!(url.contains("ipv4=1.2.3.4"));
+        assert!(url.contains("ipv6=2001%3Adb8%3A%3A1"));
+    }
+
+    #[test]
+    fn derive_scrape_urls() {
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            ("http://example.com/a", None),
+            (
+                "http://example.com/announce?x=2/4",
+                Some("http://example.com/scrape?x=2/4"),
+            ),
+            ("http://example.com/x%064announce", None),
+        ];
+        for (announce_url, expected) in cases {
+            assert_eq!(
+                scrape_url(announce_url).ok().as_deref(),
+                expected,
+                "{announce_url}"
+            );
+        }
+        assert_eq!(
+            scrape_request_url("http://example.com/scrape?key=1", &[[0xaa; 20], [b'a'; 20]]),
+            format!(
+                "http://example.com/scrape?key=1&info_hash={0}&info_hash={1}",
+                "%AA".repeat(20),
+                "a".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn parse_scrape_response() {
+        let mut data = b"d5:filesd20:".to_vec();
+        data.extend_from_slice(&[1; 20]);
+        data.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name4:teste20:");
+        data.extend_from_slice(&[2; 20]);
+        data.extend_from_slice(b"d8:completei1eeee");
+        let files = ScrapeResponse::from_bytes(&data).unwrap().files();
+        assert_eq!(
+            files.get(&[1; 20]),
+            Some(&ScrapeStats {
+                complete: 5,
+                downloaded: 50,
+                incomplete: 10
+            })
+        );
+        assert_eq!(
+            files.get(&[2; 20]),
+            Some(&ScrapeStats {
+                complete: 1,
+                downloaded: 0,
+                incomplete: 0
+            })
+        );
+        assert!(matches!(
+            ScrapeResponse::from_bytes(b"d14:failure reason8:disablede"),
+            Err(Error::TrackerFailure(reason)) if reason == "disabled"
+        ));
+
+        let mut nested = b"d5:filesd20:".to_vec();
+        nested.extend_from_slice(&[1; 20]);
+        nested.extend(vec![b'l'; 200_000]);
+        nested.extend(vec![b'e'; 200_000]);
+        nested.extend_from_slice(b"ee");
+        assert!(matches!(
+            ScrapeResponse::from_bytes(&nested),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn tracker_tiers_are_shuffled_within_tiers() {
+        let trackers = TrackerTiers::new(tiers());
+        assert_eq!(trackers.tiers().len(), 2);
+        let mut first_tier = trackers.tiers()[0].clone();
+        first_tier.sort();
+        assert_eq!(first_tier, tiers()[0]);
+        assert_eq!(trackers.urls().last().unwrap(), "http://b1");
+    }
+
+    #[test]
+    fn promote_responsive_tracker_to_tier_front() {
+        let mut trackers = TrackerTiers::new(tiers());
+        let last = trackers.tiers()[0][2].clone();
+        trackers.promote(&last);
+        assert_eq!(trackers.tiers()[0][0], last);
+        assert_eq!(trackers.tiers()[0].len(), 3);
+        trackers.promote("http://b1");
+        assert_eq!(trackers.tiers()[1], vec!["http://b1".to_string()]);
+    }
+}
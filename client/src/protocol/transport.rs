@@ -39,3 +39,108 @@ impl Transport for TcpStream {
         self.peer_addr()
     }
 }
+
+#[cfg(test)]
+pub(crate) use memory::MemoryTransport;
+
+/// In-memory [`Transport`] used by tests to run protocol code without real sockets.
+#[cfg(test)]
+mod memory {
+    use super::Transport;
+    use async_trait::async_trait;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll, Waker};
+    use tokio::io;
+    use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+    pub(crate) struct MemoryTransport {
+        inner: Mutex<DuplexStream>,
+        peer_addr: SocketAddr,
+    }
+
+    impl MemoryTransport {
+        /// Creates two connected transports, everything written to one of them can be read from the other.
+        pub(crate) fn pair() -> (Self, Self) {
+            let (a, b) = io::duplex(1 << 20);
+            (
+                Self {
+                    inner: Mutex::new(a),
+                    peer_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 2)),
+                },
+                Self {
+                    inner: Mutex::new(b),
+                    peer_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 1)),
+                },
+            )
+        }
+
+        fn poll_now<R>(
+            &self,
+            f: impl FnOnce(Pin<&mut DuplexStream>, &mut Context<'_>) -> Poll<io::Result<R>>,
+        ) -> io::Result<R> {
+            let mut cx = Context::from_waker(Waker::noop());
+            let mut inner = self.inner.lock().unwrap();
+            match f(Pin::new(&mut inner), &mut cx) {
+                Poll::Ready(result) => result,
+                Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl AsyncRead for MemoryTransport {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.get_mut().inner.lock().unwrap()).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MemoryTransport {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut *self.get_mut().inner.lock().unwrap()).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.get_mut().inner.lock().unwrap()).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut *self.get_mut().inner.lock().unwrap()).poll_shutdown(cx)
+        }
+    }
+
+    #[async_trait]
+    impl Transport for MemoryTransport {
+        async fn writable(&self) -> io::Result<()> {
+            tokio::task::yield_now().await;
+            Ok(())
+        }
+
+        async fn readable(&self) -> io::Result<()> {
+            tokio::task::yield_now().await;
+            Ok(())
+        }
+
+        fn try_write(&self, buf: &[u8]) -> io::Result<usize> {
+            self.poll_now(|stream, cx| stream.poll_write(cx, buf))
+        }
+
+        fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut read_buf = ReadBuf::new(buf);
+            self.poll_now(|stream, cx| stream.poll_read(cx, &mut read_buf))?;
+            Ok(read_buf.filled().len())
+        }
+
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.peer_addr)
+        }
+    }
+}
@@ -0,0 +1,690 @@
+use crate::protocol::meta_info_file::{Sha1HashBytes, SHA1_HASH_BYTE_LENGTH};
+use crate::protocol::tracker::{AnnounceEvent, AnnounceResponse, ScrapeStats};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, OnceCell};
+use tokio::task::JoinHandle;
+
+/// Magic constant identifying the protocol in connect requests (https://www.bittorrent.org/beps/bep_0015.html).
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+/// Actions from https://www.bittorrent.org/beps/bep_0015.html.
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Connection IDs can be used for one minute after they are received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// Timeout of the first attempt, the n-th retransmission waits `15 * 2 ^ n` seconds.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Maximum number of retransmissions (n in `15 * 2 ^ n`).
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+/// Maximum number of info hashes in a single scrape request.
+pub const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
+/// Largest UDP tracker response we are prepared to receive.
+const MAX_RESPONSE_SIZE: usize = 65536;
+
+/// UDP tracker related errors.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    IO(#[from] io::Error),
+    #[error("invalid UDP tracker URL: {0}")]
+    InvalidUrl(String),
+    #[error("tracker did not respond after {0} retransmissions")]
+    Timeout(u32),
+    #[error("tracker error: {0}")]
+    Tracker(String),
+    #[error("invalid tracker response")]
+    InvalidResponse,
+    #[error("too many info hashes in scrape request: {0}")]
+    TooManyInfoHashes(usize),
+}
+
+/// Parameters of an announce request (https://www.bittorrent.org/beps/bep_0015.html#announce).
+#[derive(Debug, Clone)]
+pub struct UdpAnnounceRequest {
+    pub info_hash: Sha1HashBytes,
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: AnnounceEvent,
+    pub key: u32,
+    /// Number of peers wanted, -1 lets the tracker decide.
+    pub num_want: i32,
+    pub port: u16,
+}
+
+/// Pending request waiting for responses from the tracker address with its transaction ID.
+type PendingRequest = (SocketAddr, mpsc::UnboundedSender<Bytes>);
+
+/// Socket shared by all requests of an address family, a background task hands the responses
+/// to the pending requests by transaction ID.
+struct TrackerSocket {
+    socket: Arc<UdpSocket>,
+    pending: Arc<Mutex<HashMap<u32, PendingRequest>>>,
+    receiver: JoinHandle<()>,
+}
+
+impl Drop for TrackerSocket {
+    fn drop(&mut self) {
+        self.receiver.abort();
+    }
+}
+
+impl TrackerSocket {
+    async fn bind(local: SocketAddr) -> Result<Self, Error> {
+        let socket = Arc::new(UdpSocket::bind(local).await?);
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let receiver = tokio::spawn(Self::receive_loop(socket.clone(), pending.clone()));
+        Ok(Self {
+            socket,
+            pending,
+            receiver,
+        })
+    }
+
+    async fn receive_loop(
+        socket: Arc<UdpSocket>,
+        pending: Arc<Mutex<HashMap<u32, PendingRequest>>>,
+    ) {
+        let mut buf = vec![0; MAX_RESPONSE_SIZE];
+        loop {
+            let (size, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    debug!("UDP tracker receive error: {:?}", error);
+                    continue;
+                }
+            };
+            if size < 8 {
+                continue;
+            }
+            let transaction_id = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+            if let Some((tracker, sender)) = pending.lock().unwrap().get(&transaction_id) {
+                if *tracker == from {
+                    let _ = sender.send(Bytes::copy_from_slice(&buf[..size]));
+                }
+            }
+        }
+    }
+}
+
+/// Removes a pending request once it finished or was cancelled.
+struct PendingGuard<'a> {
+    pending: &'a Mutex<HashMap<u32, PendingRequest>>,
+    transaction_id: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.transaction_id);
+    }
+}
+
+/// UDP tracker client (https://www.bittorrent.org/beps/bep_0015.html).
+/// All requests of an address family are sent from the same socket, as trackers may tie connection IDs
+/// to the source address. Connection IDs are cached per tracker address and reused until they expire.
+pub struct UdpTrackerClient {
+    connection_ids: Mutex<HashMap<SocketAddr, (u64, Instant)>>,
+    ipv4_socket: OnceCell<TrackerSocket>,
+    ipv6_socket: OnceCell<TrackerSocket>,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+    connection_id_ttl: Duration,
+}
+
+impl Default for UdpTrackerClient {
+    fn default() -> Self {
+        Self {
+            connection_ids: Mutex::new(HashMap::new()),
+            ipv4_socket: OnceCell::new(),
+            ipv6_socket: OnceCell::new(),
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+            connection_id_ttl: CONNECTION_ID_TTL,
+        }
+    }
+}
+
+impl UdpTrackerClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the timeout of the first attempt, retransmissions double it each time.
+    pub fn with_base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+
+    pub fn with_max_retransmissions(mut self, max_retransmissions: u32) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    pub fn with_connection_id_ttl(mut self, connection_id_ttl: Duration) -> Self {
+        self.connection_id_ttl = connection_id_ttl;
+        self
+    }
+
+    /// Announces to the tracker at `url` (`udp://host:port[/announce]`).
+    pub async fn announce(
+        &self,
+        url: &str,
+        request: &UdpAnnounceRequest,
+    ) -> Result<AnnounceResponse, Error> {
+        let tracker = Self::resolve(url).await?;
+        let mut response = self
+            .request(tracker, ACTION_ANNOUNCE, |connection_id, transaction_id| {
+                let mut message = BytesMut::with_capacity(98);
+                message.put_u64(connection_id);
+                message.put_u32(ACTION_ANNOUNCE);
+                message.put_u32(transaction_id);
+                message.put_slice(&request.info_hash);
+                message.put_slice(&request.peer_id);
+                message.put_u64(request.downloaded);
+                message.put_u64(request.left);
+                message.put_u64(request.uploaded);
+                message.put_u32(event_code(request.event));
+                message.put_u32(0); // IP address, 0 means the sender's address
+                message.put_u32(request.key);
+                message.put_i32(request.num_want);
+                message.put_u16(request.port);
+                message
+            })
+            .await?;
+        if response.remaining() < 12 {
+            return Err(Error::InvalidResponse);
+        }
+        let interval = response.get_u32() as usize;
+        let leechers = response.get_u32() as usize;
+        let seeders = response.get_u32() as usize;
+        debug!(
+            "[{0}] announce response: {1} seeders, {2} leechers",
+            tracker, seeders, leechers
+        );
+        // trackers reached over IPv6 respond with IPv6 peers (https://www.bittorrent.org/beps/bep_0015.html#ipv6)
+        Ok(AnnounceResponse::from_compact(
+            interval,
+            seeders,
+            leechers,
+            response.to_vec(),
+            tracker.is_ipv6(),
+        ))
+    }
+
+    /// Scrapes statistics of the given torrents from the tracker at `url`,
+    /// results are in the same order as `info_hashes`.
+    pub async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[Sha1HashBytes],
+    ) -> Result<Vec<ScrapeStats>, Error> {
+        if info_hashes.len() > MAX_SCRAPE_INFO_HASHES {
+            return Err(Error::TooManyInfoHashes(info_hashes.len()));
+        }
+        let tracker = Self::resolve(url).await?;
+        let mut response = self
+            .request(tracker, ACTION_SCRAPE, |connection_id, transaction_id| {
+                let mut message =
+                    BytesMut::with_capacity(16 + info_hashes.len() * SHA1_HASH_BYTE_LENGTH);
+                message.put_u64(connection_id);
+                message.put_u32(ACTION_SCRAPE);
+                message.put_u32(transaction_id);
+                for info_hash in info_hashes {
+                    message.put_slice(info_hash);
+                }
+                message
+            })
+            .await?;
+        if response.remaining() < info_hashes.len() * 12 {
+            return Err(Error::InvalidResponse);
+        }
+        Ok(info_hashes
+            .iter()
+            .map(|_| {
+                let complete = response.get_u32() as usize;
+                let downloaded = response.get_u32() as usize;
+                let incomplete = response.get_u32() as usize;
+                ScrapeStats {
+                    complete,
+                    downloaded,
+                    incomplete,
+                }
+            })
+            .collect())
+    }
+
+    /// Resolves the tracker address of `url`.
+    async fn resolve(url: &str) -> Result<SocketAddr, Error> {
+        let parsed = reqwest::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_string()))?;
+        let (Some(host), Some(port)) = (parsed.host_str(), parsed.port()) else {
+            return Err(Error::InvalidUrl(url.to_string()));
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let tracker = tokio::net::lookup_host((host, port)).await?.next();
+        tracker.ok_or_else(|| Error::InvalidUrl(url.to_string()))
+    }
+
+    /// Returns the socket of `tracker`'s address family, it's bound on first use.
+    async fn socket(&self, tracker: SocketAddr) -> Result<&TrackerSocket, Error> {
+        let (cell, local) = if tracker.is_ipv4() {
+            (&self.ipv4_socket, "0.0.0.0:0")
+        } else {
+            (&self.ipv6_socket, "[::]:0")
+        };
+        cell.get_or_try_init(|| TrackerSocket::bind(local.parse().unwrap()))
+            .await
+    }
+
+    /// Sends the request built by `message` from a connection ID and a transaction ID, returns the
+    /// response body. If the tracker responds with an error to a cached connection ID, the ID may have
+    /// expired on its side: it is dropped and the request is sent once more with a new one.
+    async fn request(
+        &self,
+        tracker: SocketAddr,
+        action: u32,
+        build_message: impl Fn(u64, u32) -> BytesMut,
+    ) -> Result<Bytes, Error> {
+        let socket = self.socket(tracker).await?;
+        let (connection_id, cached) = self.connection_id(socket, tracker).await?;
+        let transaction_id = rand::random();
+        let message = build_message(connection_id, transaction_id);
+        let reason = match self
+            .send_with_retransmission(socket, tracker, &message, action, transaction_id)
+            .await
+        {
+            Err(Error::Tracker(reason)) if cached => reason,
+            result => return result,
+        };
+        debug!(
+            "[{0}] error with cached connection ID, reconnecting: {1}",
+            tracker, reason
+        );
+        self.connection_ids.lock().unwrap().remove(&tracker);
+        let (connection_id, _) = self.connection_id(socket, tracker).await?;
+        let transaction_id = rand::random();
+        let message = build_message(connection_id, transaction_id);
+        self.send_with_retransmission(socket, tracker, &message, action, transaction_id)
+            .await
+    }
+
+    /// Returns a cached connection ID for `tracker` or requests a new one, and whether it was cached.
+    async fn connection_id(
+        &self,
+        socket: &TrackerSocket,
+        tracker: SocketAddr,
+    ) -> Result<(u64, bool), Error> {
+        if let Some((connection_id, received)) = self.connection_ids.lock().unwrap().get(&tracker) {
+            if received.elapsed() < self.connection_id_ttl {
+                return Ok((*connection_id, true));
+            }
+        }
+
+        let transaction_id = rand::random();
+        let mut message = BytesMut::with_capacity(16);
+        message.put_u64(PROTOCOL_ID);
+        message.put_u32(ACTION_CONNECT);
+        message.put_u32(transaction_id);
+        let mut response = self
+            .send_with_retransmission(socket, tracker, &message, ACTION_CONNECT, transaction_id)
+            .await?;
+        if response.remaining() < 8 {
+            return Err(Error::InvalidResponse);
+        }
+        let connection_id = response.get_u64();
+        self.connection_ids
+            .lock()
+            .unwrap()
+            .insert(tracker, (connection_id, Instant::now()));
+        Ok((connection_id, false))
+    }
+
+    /// Sends `message` until a response with the same transaction ID arrives, waiting `base_timeout * 2 ^ n`
+    /// after the n-th attempt. Returns the response body after the action and transaction ID.
+    async fn send_with_retransmission(
+        &self,
+        socket: &TrackerSocket,
+        tracker: SocketAddr,
+        message: &[u8],
+        action: u32,
+        transaction_id: u32,
+    ) -> Result<Bytes, Error> {
+        let (sender, mut responses) = mpsc::unbounded_channel();
+        socket
+            .pending
+            .lock()
+            .unwrap()
+            .insert(transaction_id, (tracker, sender));
+        let _pending = PendingGuard {
+            pending: &socket.pending,
+            transaction_id,
+        };
+        for n in 0..=self.max_retransmissions {
+            socket.socket.send_to(message, tracker).await?;
+            let timeout = self.base_timeout * 2u32.pow(n);
+            let receive = async {
+                let mut response = responses.recv().await.ok_or(Error::InvalidResponse)?;
+                let response_action = response.get_u32();
+                response.advance(4); // transaction ID, checked by the receive task
+                match response_action {
+                    ACTION_ERROR => Err(Error::Tracker(
+                        String::from_utf8_lossy(response.as_ref()).to_string(),
+                    )),
+                    response_action if response_action == action => Ok(response),
+                    _ => Err(Error::InvalidResponse),
+                }
+            };
+            match tokio::time::timeout(timeout, receive).await {
+                Ok(result) => return result,
+                Err(_) => debug!("UDP tracker request timed out after {0:?}", timeout),
+            }
+        }
+        Err(Error::Timeout(self.max_retransmissions))
+    }
+}
+
+/// Event codes of announce requests.
+fn event_code(event: AnnounceEvent) -> u32 {
+    match event {
+        AnnounceEvent::None => 0,
+        AnnounceEvent::Completed => 1,
+        AnnounceEvent::Started => 2,
+        AnnounceEvent::Stopped => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    /// Local stand-in tracker, counting requests per action and optionally dropping the first requests.
+    /// Connection IDs are only valid for the address they were sent to, and only until the generation
+    /// is increased.
+    struct TestTracker {
+        url: String,
+        connects: Arc<AtomicUsize>,
+        announces: Arc<AtomicUsize>,
+        generation: Arc<AtomicU64>,
+    }
+
+    fn issued_connection_id(from: SocketAddr, generation: u64) -> u64 {
+        CONNECTION_ID ^ from.port() as u64 ^ (generation << 32)
+    }
+
+    async fn start_tracker(drop_first: usize) -> TestTracker {
+        start_tracker_on("127.0.0.1:0", drop_first).await
+    }
+
+    async fn start_tracker_on(address: &str, drop_first: usize) -> TestTracker {
+        let socket = UdpSocket::bind(address).await.unwrap();
+        let ipv6 = socket.local_addr().unwrap().is_ipv6();
+        let url = format!("udp://{0}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let announces = Arc::new(AtomicUsize::new(0));
+        let generation = Arc::new(AtomicU64::new(0));
+        let (connect_count, announce_count) = (connects.clone(), announces.clone());
+        let current_generation = generation.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2048];
+            let mut received = 0;
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+                received += 1;
+                if received <= drop_first {
+                    continue;
+                }
+                let mut request = Bytes::copy_from_slice(&buf[..size]);
+                let connection_id = request.get_u64();
+                let action = request.get_u32();
+                let transaction_id = request.get_u32();
+                let expected_id =
+                    issued_connection_id(from, current_generation.load(Ordering::SeqCst));
+                let mut response = BytesMut::new();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        connect_count.fetch_add(1, Ordering::SeqCst);
+                        response.put_u32(ACTION_CONNECT);
+                        response.put_u32(transaction_id);
+                        response.put_u64(expected_id);
+                    }
+                    _ if connection_id != expected_id => {
+                        response.put_u32(ACTION_ERROR);
+                        response.put_u32(transaction_id);
+                        response.put_slice(b"invalid connection id");
+                    }
+                    ACTION_ANNOUNCE => {
+                        announce_count.fetch_add(1, Ordering::SeqCst);
+                        let info_hash = request.copy_to_bytes(20);
+                        if info_hash.as_ref() == [0xff; 20] {
+                            response.put_u32(ACTION_ERROR);
+                            response.put_u32(transaction_id);
+                            response.put_slice(b"unregistered torrent");
+                        } else {
+                            request.advance(20 + 24);
+                            assert_eq!(request.get_u32(), 2); // started
+                            response.put_u32(ACTION_ANNOUNCE);
+                            response.put_u32(transaction_id);
+                            response.put_u32(1800);
+                            response.put_u32(3);
+                            response.put_u32(5);
+                            if ipv6 {
+                                response.put_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+                                response.put_u16(6881);
+                            } else {
+                                response
+                                    .put_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                            }
+                        }
+                    }
+                    ACTION_SCRAPE => {
+                        response.put_u32(ACTION_SCRAPE);
+                        response.put_u32(transaction_id);
+                        let mut index = 0;
+                        while request.remaining() >= 20 {
+                            request.advance(20);
+                            index += 1;
+                            response.put_u32(index * 10);
+                            response.put_u32(index * 100);
+                            response.put_u32(index);
+                        }
+                    }
+                    _ => panic!("unexpected action {action}"),
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+        TestTracker {
+            url,
+            connects,
+            announces,
+            generation,
+        }
+    }
+
+    fn announce_request(info_hash: Sha1HashBytes) -> UdpAnnounceRequest {
+        UdpAnnounceRequest {
+            info_hash,
+            peer_id: *b"-RT0100-abcdefghijkl",
+            downloaded: 0,
+            left: 1000,
+            uploaded: 0,
+            event: AnnounceEvent::Started,
+            key: 42,
+            num_want: -1,
+            port: 6881,
+        }
+    }
+
+    fn test_client() -> UdpTrackerClient {
+        UdpTrackerClient::new()
+            .with_base_timeout(Duration::from_millis(50))
+            .with_max_retransmissions(3)
+    }
+
+    #[tokio::test]
+    async fn announce_and_cache_connection_id() {
+        let tracker = start_tracker(0).await;
+        let client = test_client();
+        let response = client
+            .announce(&tracker.url, &announce_request([1; 20]))
+            .await
+            .unwrap();
+        assert_eq!(response.interval(), 1800);
+        assert_eq!(response.complete(), Some(5));
+        assert_eq!(response.incomplete(), Some(3));
+        let peers: Vec<String> = response
+            .peers()
+            .unwrap()
+            .iter()
+            .map(|peer| peer.to_string())
+            .collect();
+        assert_eq!(peers, vec!["10.0.0.1:6881", "10.0.0.2:6882"]);
+
+        client
+            .announce(&tracker.url, &announce_request([1; 20]))
+            .await
+            .unwrap();
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+        assert_eq!(tracker.announces.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_connection_id_is_renewed() {
+        let tracker = start_tracker(0).await;
+        let client = test_client().with_connection_id_ttl(Duration::ZERO);
+        for _ in 0..2 {
+            client
+                .announce(&tracker.url, &announce_request([1; 20]))
+                .await
+                .unwrap();
+        }
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_stale_connection_id() {
+        let tracker = start_tracker(0).await;
+        let client = test_client();
+        client
+            .announce(&tracker.url, &announce_request([1; 20]))
+            .await
+            .unwrap();
+        // the tracker forgets the connection ID before it expires on our side
+        tracker.generation.fetch_add(1, Ordering::SeqCst);
+        client.scrape(&tracker.url, &[[1; 20]]).await.unwrap();
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 2);
+
+        // errors of fresh connection IDs are not retried
+        match client
+            .announce(&tracker.url, &announce_request([0xff; 20]))
+            .await
+        {
+            Err(Error::Tracker(message)) => assert_eq!(message, "unregistered torrent"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retransmit_lost_requests() {
+        let tracker = start_tracker(2).await;
+        let client = test_client();
+        let started = Instant::now();
+        client
+            .announce(&tracker.url, &announce_request([1; 20]))
+            .await
+            .unwrap();
+        // two lost packets: 50ms + 100ms of timeouts before the third attempt succeeds
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(tracker.connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn announce_over_ipv6() {
+        let tracker = start_tracker_on("[::1]:0", 0).await;
+        let response = test_client()
+            .announce(&tracker.url, &announce_request([1; 20]))
+            .await
+            .unwrap();
+        let peers: Vec<String> = response
+            .peers()
+            .unwrap()
+            .iter()
+            .map(|peer| peer.to_string())
+            .collect();
+        assert_eq!(peers, vec!["[::1]:6881"]);
+    }
+
+    #[tokio::test]
+    async fn give_up_after_max_retransmissions() {
+        let tracker = start_tracker(usize::MAX).await;
+        let client = test_client().with_max_retransmissions(1);
+        assert!(matches!(
+            client
+                .announce(&tracker.url, &announce_request([1; 20]))
+                .await,
+            Err(Error::Timeout(1))
+        ));
+    }
+
+    #[tokio::test]
+    async fn surface_tracker_errors() {
+        let tracker = start_tracker(0).await;
+        let client = test_client();
+        match client
+            .announce(&tracker.url, &announce_request([0xff; 20]))
+            .await
+        {
+            Err(Error::Tracker(message)) => assert_eq!(message, "unregistered torrent"),
+            result => panic!("unexpected result: {result:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_multiple_info_hashes() {
+        let tracker = start_tracker(0).await;
+        let client = test_client();
+        let stats = client
+            .scrape(&tracker.url, &[[1; 20], [2; 20]])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 10,
+                    downloaded: 100,
+                    incomplete: 1
+                },
+                ScrapeStats {
+                    complete: 20,
+                    downloaded: 200,
+                    incomplete: 2
+                },
+            ]
+        );
+        assert!(matches!(
+            client.scrape(&tracker.url, &[[0; 20]; 75]).await,
+            Err(Error::TooManyInfoHashes(75))
+        ));
+    }
+}
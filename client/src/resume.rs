@@ -0,0 +1,173 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::{Sha1HashBytes, TorrentFile};
+use crate::protocol::peer_wire::Bitfield;
+use crate::protocol::tracker::{parse_compact_peers, parse_compact_peers6, PeerAddress};
+use crate::storage::FileStats;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io;
+
+/// Bencoded form of the resume data, saved next to the download.
+#[derive(Serialize, Deserialize)]
+struct RawResumeData {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    pieces: ByteBuf,
+    files: Vec<RawFileStats>,
+    peers: ByteBuf,
+    peers6: ByteBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawFileStats {
+    length: u64,
+    /// Nanoseconds since the UNIX epoch.
+    mtime: u64,
+}
+
+/// State of a download saved regularly and when it stops, so it can continue without verifying all data
+/// after a restart: the pieces verified, the files they were written to, and the peers known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: Sha1HashBytes,
+    /// Bitfield of the verified pieces.
+    pub pieces: Vec<u8>,
+    /// Sizes and modification times of the torrent's files when the data was saved.
+    pub files: Vec<FileStats>,
+    pub peers: Vec<PeerAddress>,
+}
+
+impl ResumeData {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let files = self
+            .files
+            .iter()
+            .map(|file| RawFileStats {
+                length: file.length,
+                mtime: file
+                    .modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_nanos() as u64),
+            })
+            .collect();
+        let compact = |ipv4: bool| {
+            let peers = self.peers.iter().filter(|peer| peer.ip().is_ipv4() == ipv4);
+            ByteBuf::from(peers.flat_map(PeerAddress::to_compact).collect::<Vec<_>>())
+        };
+        let raw = RawResumeData {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            pieces: ByteBuf::from(self.pieces.clone()),
+            files,
+            peers: compact(true),
+            peers6: compact(false),
+        };
+        serde_bencode::to_bytes(&raw).map_err(|_| Error::InvalidResumeData)
+    }
+
+    /// Parses resume data serialized by [`Self::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let raw: RawResumeData =
+            serde_bencode::from_bytes(data).map_err(|_| Error::InvalidResumeData)?;
+        let info_hash = Sha1HashBytes::try_from(raw.info_hash.as_slice())
+            .map_err(|_| Error::InvalidResumeData)?;
+        let mut peers = parse_compact_peers(&raw.peers).map_err(|_| Error::InvalidResumeData)?;
+        peers.extend(parse_compact_peers6(&raw.peers6).map_err(|_| Error::InvalidResumeData)?);
+        Ok(Self {
+            info_hash,
+            pieces: raw.pieces.into_vec(),
+            files: raw
+                .files
+                .iter()
+                .map(|file| FileStats {
+                    length: file.length,
+                    modified: SystemTime::UNIX_EPOCH + Duration::from_nanos(file.mtime),
+                })
+                .collect(),
+            peers,
+        })
+    }
+
+    /// The verified pieces, if the resume data belongs to `torrent` and its `files` did not change
+    /// since it was saved. Otherwise the data must be verified again.
+    pub fn pieces(&self, torrent: &TorrentFile, files: &[FileStats]) -> Option<Bitfield> {
+        let pieces_count = torrent.pieces_count();
+        let valid = self.info_hash == torrent.info_hash
+            && self.pieces.len() == pieces_count.div_ceil(8)
+            && self.files == files;
+        valid.then(|| Bitfield::from_bytes(&self.pieces, pieces_count))
+    }
+}
+
+/// The resume file of a download at `path` (see [`crate::BitTorrentClient::download`]): `path`
+/// with the `.resume` extension appended, next to the downloaded file or directory.
+pub fn resume_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+    file_name.push(".resume");
+    path.with_file_name(file_name)
+}
+
+/// Reads the resume data saved at `path`, `None` if there is none.
+pub async fn load(path: &Path) -> Result<Option<ResumeData>, Error> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(Some(ResumeData::from_bytes(&data)?)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Saves resume data at `path`, replacing the previous one.
+pub async fn save(path: &Path, resume: &ResumeData) -> Result<(), Error> {
+    tokio::fs::write(path, resume.to_bytes()?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::single_file_torrent;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn restore_pieces_of_unchanged_files() {
+        let torrent = single_file_torrent(&[0; 20], 8);
+        let files = vec![FileStats {
+            length: 20,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789),
+        }];
+        let resume = ResumeData {
+            info_hash: torrent.info_hash,
+            pieces: vec![0b1010_0000],
+            files: files.clone(),
+            peers: ["127.0.0.1:6881", "[::1]:6882"]
+                .iter()
+                .map(|address| PeerAddress::from(address.parse::<SocketAddr>().unwrap()))
+                .collect(),
+        };
+        let restored = ResumeData::from_bytes(&resume.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored, resume);
+        let pieces = restored.pieces(&torrent, &files).unwrap();
+        assert_eq!(
+            (pieces.has(0), pieces.has(1), pieces.has(2)),
+            (true, false, true)
+        );
+
+        // resume data is stale once the files changed
+        let mut changed = files.clone();
+        changed[0].modified += Duration::from_nanos(1);
+        assert!(restored.pieces(&torrent, &changed).is_none());
+        assert!(restored.pieces(&torrent, &[]).is_none());
+        assert!(ResumeData::from_bytes(b"de").is_err());
+    }
+
+    #[test]
+    fn save_resume_file_next_to_download() {
+        assert_eq!(
+            resume_path(Path::new("downloads/file.iso")),
+            Path::new("downloads/file.iso.resume")
+        );
+        assert_eq!(resume_path(Path::new("out/")), Path::new("out.resume"));
+    }
+}
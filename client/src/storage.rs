@@ -0,0 +1,383 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::{Sha1HashBytes, TorrentFile};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::fs::OpenOptions;
+use tokio::io;
+
+/// Storage of a torrent's data, blocks are addressed by piece index and offset inside the piece.
+/// The storage is opened for a torrent (see [`StorageBackend`]), so it knows how the pieces map onto files.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// The torrent stored.
+    fn torrent(&self) -> &TorrentFile;
+
+    /// Writes `data` at `offset` of the piece `index`, the block may span multiple files.
+    async fn write_block(&self, index: usize, offset: usize, data: Bytes) -> Result<(), Error>;
+
+    /// Reads `length` bytes at `offset` of the piece `index`.
+    async fn read_block(&self, index: usize, offset: usize, length: usize) -> Result<Bytes, Error>;
+
+    /// Makes sure all written data is persisted.
+    async fn flush(&self) -> Result<(), Error>;
+
+    /// Sizes and modification times of the stored files, they tell whether the data changed since
+    /// resume data was saved. `None` if the storage can't tell (e.g. its data is gone after a restart),
+    /// then no resume data is kept.
+    async fn file_stats(&self) -> Result<Option<Vec<FileStats>>, Error> {
+        Ok(None)
+    }
+
+    /// Checks the stored data of the piece `index` against its SHA-1 hash.
+    async fn verify_piece(&self, index: usize) -> Result<bool, Error> {
+        let torrent = self.torrent();
+        let data = self.read_block(index, 0, torrent.piece_size(index)).await?;
+        Ok(Sha1::digest(&data).as_slice() == torrent.piece_hashes[index])
+    }
+}
+
+/// Size and modification time of a stored file, see [`Storage::file_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    pub length: u64,
+    pub modified: SystemTime,
+}
+
+/// Opens the [`Storage`] of torrents, configured with [`crate::BitTorrentClientConfig::with_storage`].
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Opens (or creates) the storage of `torrent` at `path`, see [`crate::BitTorrentClient::download`]
+    /// for the meaning of `path`.
+    async fn open(&self, torrent: Arc<TorrentFile>, path: &Path)
+        -> Result<Arc<dyn Storage>, Error>;
+}
+
+/// Returns an error if the block at `offset` of the piece `index` is not inside the piece.
+fn check_bounds(
+    torrent: &TorrentFile,
+    index: usize,
+    offset: usize,
+    length: usize,
+) -> Result<usize, Error> {
+    let in_bounds = index < torrent.pieces_count()
+        && offset
+            .checked_add(length)
+            .is_some_and(|end| end <= torrent.piece_size(index));
+    if !in_bounds {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "block {0}+{1} is outside of piece #{2}",
+                offset, length, index
+            ),
+        )
+        .into());
+    }
+    Ok(index * torrent.piece_length as usize + offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, data: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let written = file.seek_write(data, offset)?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        data = &data[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut data: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        let read = file.seek_read(data, offset)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        data = &mut data[read..];
+        offset += read as u64;
+    }
+    Ok(())
+}
+
+/// Stores torrents in files, default [`StorageBackend`] of the client.
+#[derive(Debug, Default)]
+pub struct FileSystemBackend;
+
+#[async_trait]
+impl StorageBackend for FileSystemBackend {
+    /// Creates (or opens existing) files of the torrent under `path` with their final sizes,
+    /// see [`TorrentFile::file_paths`] for the meaning of `path`.
+    async fn open(
+        &self,
+        torrent: Arc<TorrentFile>,
+        path: &Path,
+    ) -> Result<Arc<dyn Storage>, Error> {
+        let mut files = Vec::with_capacity(torrent.files.len());
+        for (path, entry) in torrent.file_paths(path).iter().zip(&torrent.files) {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(path)
+                .await?;
+            // resizing updates the modification time even if the size is unchanged, which would
+            // invalidate the resume data (see [`Storage::file_stats`])
+            if file.metadata().await?.len() != entry.length as u64 {
+                file.set_len(entry.length as u64).await?;
+            }
+            files.push(Arc::new(file.into_std().await));
+        }
+        Ok(Arc::new(FileStorage { torrent, files }))
+    }
+}
+
+/// Files of a torrent, blocks are read and written with positional I/O, so they can be accessed in parallel.
+pub struct FileStorage {
+    torrent: Arc<TorrentFile>,
+    files: Vec<Arc<File>>,
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    fn torrent(&self) -> &TorrentFile {
+        &self.torrent
+    }
+
+    async fn write_block(&self, index: usize, offset: usize, data: Bytes) -> Result<(), Error> {
+        let start = check_bounds(&self.torrent, index, offset, data.len())?;
+        let mut position = 0;
+        let mut writes = vec![];
+        for segment in self.torrent.file_segments(start, data.len()) {
+            let chunk = data.slice(position..position + segment.length);
+            writes.push((
+                self.files[segment.file_index].clone(),
+                segment.offset,
+                chunk,
+            ));
+            position += segment.length;
+        }
+        tokio::task::spawn_blocking(move || {
+            writes
+                .iter()
+                .try_for_each(|(file, offset, chunk)| write_all_at(file, chunk, *offset as u64))
+        })
+        .await??;
+        Ok(())
+    }
+
+    async fn read_block(&self, index: usize, offset: usize, length: usize) -> Result<Bytes, Error> {
+        let start = check_bounds(&self.torrent, index, offset, length)?;
+        let reads: Vec<_> = self
+            .torrent
+            .file_segments(start, length)
+            .into_iter()
+            .map(|segment| (self.files[segment.file_index].clone(), segment))
+            .collect();
+        let data = tokio::task::spawn_blocking(move || {
+            let mut data = BytesMut::zeroed(length);
+            let mut position = 0;
+            for (file, segment) in reads {
+                let chunk = &mut data[position..position + segment.length];
+                read_exact_at(&file, chunk, segment.offset as u64)?;
+                position += segment.length;
+            }
+            Ok::<_, io::Error>(data)
+        })
+        .await??;
+        Ok(data.freeze())
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || files.iter().try_for_each(|file| file.sync_all()))
+            .await??;
+        Ok(())
+    }
+
+    async fn file_stats(&self) -> Result<Option<Vec<FileStats>>, Error> {
+        let files = self.files.clone();
+        let stats = tokio::task::spawn_blocking(move || {
+            files
+                .iter()
+                .map(|file| {
+                    let metadata = file.metadata()?;
+                    Ok(FileStats {
+                        length: metadata.len(),
+                        modified: metadata.modified()?,
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .await??;
+        Ok(Some(stats))
+    }
+}
+
+/// Keeps torrents in memory instead of writing them to disk, e.g. for tests. The data of the torrents
+/// opened can be accessed with [`MemoryBackend::storage`], the path they were opened at is ignored.
+#[derive(Default)]
+pub struct MemoryBackend {
+    torrents: Mutex<HashMap<Sha1HashBytes, Arc<MemoryStorage>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Storage of the torrent with the given info hash, if it was opened.
+    pub fn storage(&self, info_hash: &Sha1HashBytes) -> Option<Arc<MemoryStorage>> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryBackend {
+    /// Opening a torrent again returns the same storage, like reopening its files.
+    async fn open(
+        &self,
+        torrent: Arc<TorrentFile>,
+        _path: &Path,
+    ) -> Result<Arc<dyn Storage>, Error> {
+        let storage = self
+            .torrents
+            .lock()
+            .unwrap()
+            .entry(torrent.info_hash)
+            .or_insert_with(|| Arc::new(MemoryStorage::new(torrent)))
+            .clone();
+        Ok(storage)
+    }
+}
+
+/// Data of a torrent kept in memory, see [`MemoryBackend`].
+pub struct MemoryStorage {
+    torrent: Arc<TorrentFile>,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    /// Constructs a storage of `torrent` filled with zeros.
+    pub fn new(torrent: Arc<TorrentFile>) -> Self {
+        let data = vec![0; torrent.length as usize];
+        Self {
+            torrent,
+            data: Mutex::new(data),
+        }
+    }
+
+    /// The concatenated data of all files of the torrent.
+    pub fn data(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn torrent(&self) -> &TorrentFile {
+        &self.torrent
+    }
+
+    async fn write_block(&self, index: usize, offset: usize, data: Bytes) -> Result<(), Error> {
+        let start = check_bounds(&self.torrent, index, offset, data.len())?;
+        self.data.lock().unwrap()[start..start + data.len()].copy_from_slice(&data);
+        Ok(())
+    }
+
+    async fn read_block(&self, index: usize, offset: usize, length: usize) -> Result<Bytes, Error> {
+        let start = check_bounds(&self.torrent, index, offset, length)?;
+        Ok(Bytes::copy_from_slice(
+            &self.data.lock().unwrap()[start..start + length],
+        ))
+    }
+
+    async fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{multi_file_torrent, TempDir, MULTI_FILE_DATA as DATA};
+
+    #[tokio::test]
+    async fn write_blocks_across_files() {
+        let torrent = Arc::new(multi_file_torrent());
+        let dir = TempDir::new("file-storage");
+        let root = dir.path();
+        let storage = FileSystemBackend.open(torrent, root).await.unwrap();
+        assert!(!storage.verify_piece(0).await.unwrap());
+        storage
+            .write_block(0, 0, Bytes::from_static(&DATA[..10]))
+            .await
+            .unwrap();
+        storage
+            .write_block(1, 5, Bytes::from_static(&DATA[15..20]))
+            .await
+            .unwrap();
+        storage.flush().await.unwrap();
+
+        assert_eq!(tokio::fs::read(root.join("a")).await.unwrap(), b"aaaaa");
+        assert!(tokio::fs::read(root.join("empty"))
+            .await
+            .unwrap()
+            .is_empty());
+        let b = tokio::fs::read(root.join("b")).await.unwrap();
+        assert_eq!(&b[..5], b"bbbbb");
+        assert_eq!(&b[10..15], b"bbbbb");
+        assert!(storage.verify_piece(0).await.unwrap());
+        assert!(!storage.verify_piece(1).await.unwrap());
+        assert_eq!(storage.read_block(0, 3, 4).await.unwrap().as_ref(), b"aabb");
+        assert!(storage.read_block(2, 0, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keep_torrents_in_memory() {
+        let torrent = Arc::new(multi_file_torrent());
+        let backend = MemoryBackend::new();
+        let storage = backend.open(torrent.clone(), Path::new("")).await.unwrap();
+        storage
+            .write_block(2, 0, Bytes::from_static(&DATA[20..]))
+            .await
+            .unwrap();
+        assert!(storage.verify_piece(2).await.unwrap());
+        assert!(storage
+            .write_block(2, 1, Bytes::from_static(&DATA[20..]))
+            .await
+            .is_err());
+
+        let reopened = backend.open(torrent.clone(), Path::new("")).await.unwrap();
+        assert_eq!(
+            reopened.read_block(2, 0, 5).await.unwrap().as_ref(),
+            b"bbbbb"
+        );
+        let data = backend.storage(&torrent.info_hash).unwrap().data();
+        assert_eq!(&data[20..], b"bbbbb");
+    }
+}
@@ -0,0 +1,110 @@
+//! Fixtures shared by the tests of multiple modules.
+use crate::protocol::meta_info_file::TorrentFile;
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Content of [`multi_file_torrent`].
+pub const MULTI_FILE_DATA: &[u8; 25] = b"aaaaabbbbbbbbbbbbbbbbbbbb";
+
+/// Files `a` (5 bytes), `empty` and `b` (20 bytes) of [`MULTI_FILE_DATA`] in pieces of 10 bytes.
+pub fn multi_file_torrent() -> TorrentFile {
+    let mut info = b"d5:filesld6:lengthi5e4:pathl1:aeed6:lengthi0e4:pathl5:emptyeed6:lengthi20e4:pathl1:beee4:name4:test12:piece lengthi10e6:pieces60:".to_vec();
+    for piece in MULTI_FILE_DATA.chunks(10) {
+        info.extend_from_slice(&Sha1::digest(piece));
+    }
+    info.push(b'e');
+    TorrentFile::from_info_bytes(&info, String::new()).unwrap()
+}
+
+/// Single-file torrent `test` of `data` in pieces of `piece_length` bytes.
+pub fn single_file_torrent(data: &[u8], piece_length: usize) -> TorrentFile {
+    let mut info = format!(
+        "d6:lengthi{0}e4:name4:test12:piece lengthi{1}e6:pieces{2}:",
+        data.len(),
+        piece_length,
+        data.len().div_ceil(piece_length) * 20
+    )
+    .into_bytes();
+    for piece in data.chunks(piece_length) {
+        info.extend_from_slice(&Sha1::digest(piece));
+    }
+    info.push(b'e');
+    TorrentFile::from_info_bytes(&info, String::new()).unwrap()
+}
+
+/// Directory under the system's temporary directory, unique to a single test and removed with
+/// all of its content when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "{0}-{1}-{2:016x}",
+            name,
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Query parameters of a request to [`start_http_tracker`], values are still percent-encoded.
+pub type QueryParams = HashMap<String, String>;
+
+/// Local stand-in HTTP tracker answering each request with the bencoded body `respond` returns
+/// for its query parameters. Returns the announce URL and the query parameters of all requests.
+pub async fn start_http_tracker(
+    mut respond: impl FnMut(&QueryParams) -> Vec<u8> + Send + 'static,
+) -> (String, Arc<Mutex<Vec<QueryParams>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{0}/announce", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 4096];
+            let size = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..size]).to_string();
+            let query = request
+                .split_whitespace()
+                .nth(1)
+                .and_then(|path| path.split_once('?'))
+                .map(|(_, query)| query.to_string())
+                .unwrap_or_default();
+            let params: QueryParams = query
+                .split('&')
+                .filter_map(|param| param.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            let body = respond(&params);
+            recorded.lock().unwrap().push(params);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {0}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        }
+    });
+    (url, requests)
+}
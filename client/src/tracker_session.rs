@@ -0,0 +1,727 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::Sha1HashBytes;
+use crate::protocol::tracker;
+use crate::protocol::tracker::{
+    scrape_request_url, scrape_url, AnnounceEvent, AnnounceResponse, PeerAddress, ScrapeResponse,
+    ScrapeStats, TrackerTiers, TrackerUrl,
+};
+use crate::protocol::udp_tracker::{self, UdpAnnounceRequest, UdpTrackerClient};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Scheme prefix of UDP tracker URLs (https://www.bittorrent.org/beps/bep_0015.html).
+const UDP_TRACKER_URL_PREFIX: &str = "udp://";
+
+/// How long to wait before announcing again after all trackers failed.
+const ANNOUNCE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Upper bound of a single HTTP announce or scrape, so an unresponsive tracker can't stall the failover
+/// to the next one. UDP requests are bounded by [`UDP_TRACKER_RETRANSMISSIONS`] instead.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Retransmissions of a UDP tracker request before the next tracker is tried: a dead tracker takes
+/// 15 + 30 + 60 seconds to give up on. The 8 retransmissions the protocol allows would take more than
+/// an hour (https://www.bittorrent.org/beps/bep_0015.html#time-outs).
+const UDP_TRACKER_RETRANSMISSIONS: u32 = 2;
+
+/// Lower bound of the time between regular announces, whatever interval the tracker asks for.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Port reported to trackers unless the client listens on another one.
+const DEFAULT_LISTEN_PORT: u16 = 6881;
+
+/// Public addresses used to find the local address of the default route, no packets are sent to them.
+const PUBLIC_IPV4_PROBE_ADDRESS: &str = "192.0.2.1:6881";
+const PUBLIC_IPV6_PROBE_ADDRESS: &str = "[2001:db8::1]:6881";
+
+/// Returns the local address the OS would use to reach `remote`.
+fn local_address(remote: &str) -> Option<IpAddr> {
+    let bind = if remote.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = std::net::UdpSocket::bind(bind).ok()?;
+    socket.connect(remote).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Whether an IPv4 address is reachable from the internet (not private, loopback, link-local, etc.).
+fn is_global_ipv4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_documentation()
+        // shared address space of carrier-grade NAT (100.64.0.0/10)
+        || (ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64))
+}
+
+/// Whether an IPv6 address is reachable from the internet (not loopback, link-local, unique local, etc.).
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_unicast_link_local()
+        || ip.is_unique_local()
+        || ip.to_ipv4_mapped().is_some())
+}
+
+/// Transfer statistics of a torrent reported to trackers.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn set_left(&self, left: u64) {
+        self.left.store(left, Ordering::Relaxed);
+    }
+
+    /// Records bytes of blocks sent to other peers.
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records verified downloaded bytes, which are not left to download anymore.
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+}
+
+/// Notable tracker responses, reported to the channel set by [`crate::BitTorrentClient::with_tracker_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerEvent {
+    /// The tracker responded with a warning, the response was processed normally.
+    Warning { url: String, message: String },
+    /// The tracker rejected the announce with a failure reason.
+    Failure { url: String, reason: String },
+}
+
+/// Parameters of a single announce.
+#[derive(Debug, Clone, Default)]
+pub struct AnnounceParams {
+    pub info_hash: Sha1HashBytes,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    /// Tracker ID received in a previous announce response.
+    pub tracker_id: Option<String>,
+}
+
+/// Announces to HTTP and UDP trackers.
+#[derive(Clone)]
+pub struct Announcer {
+    http_client: reqwest::Client,
+    udp_tracker: Arc<UdpTrackerClient>,
+    peer_id: String,
+    /// Random key identifying this client across IP changes (https://www.bittorrent.org/beps/bep_0015.html#announce).
+    key: u32,
+    events: Option<mpsc::UnboundedSender<TrackerEvent>>,
+    /// Public addresses of this host reported to HTTP trackers, so peers can reach it over both families.
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    /// Port other peers can connect to.
+    port: u16,
+    /// Upper bound of each HTTP announce and scrape.
+    timeout: Duration,
+}
+
+impl Announcer {
+    pub fn new(peer_id: String) -> Self {
+        Self {
+            http_client: reqwest::Client::new(),
+            udp_tracker: Arc::new(
+                UdpTrackerClient::new().with_max_retransmissions(UDP_TRACKER_RETRANSMISSIONS),
+            ),
+            peer_id,
+            key: rand::random(),
+            events: None,
+            ipv4: match local_address(PUBLIC_IPV4_PROBE_ADDRESS) {
+                Some(IpAddr::V4(ip)) if is_global_ipv4(&ip) => Some(ip),
+                _ => None,
+            },
+            ipv6: match local_address(PUBLIC_IPV6_PROBE_ADDRESS) {
+                Some(IpAddr::V6(ip)) if is_global_ipv6(&ip) => Some(ip),
+                _ => None,
+            },
+            port: DEFAULT_LISTEN_PORT,
+            timeout: TRACKER_TIMEOUT,
+        }
+    }
+
+    pub fn with_events(mut self, events: mpsc::UnboundedSender<TrackerEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets the upper bound of each HTTP announce and scrape.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the client used for `udp://` trackers. UDP requests are not bounded by the timeout of
+    /// [`Self::with_timeout`], as it would cut the retransmissions short, but by the client's retransmissions.
+    pub fn with_udp_tracker(mut self, udp_tracker: UdpTrackerClient) -> Self {
+        self.udp_tracker = Arc::new(udp_tracker);
+        self
+    }
+
+    fn report(&self, event: TrackerEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Fails with [`Error::TrackerTimeout`] if the HTTP `request` takes longer than the tracker timeout.
+    async fn bounded<T>(
+        &self,
+        request: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        tokio::time::timeout(self.timeout, request)
+            .await
+            .map_err(|_| Error::TrackerTimeout(self.timeout))?
+    }
+
+    /// Announces to a single tracker, `udp://` URLs use the UDP tracker protocol, others HTTP.
+    pub async fn announce(
+        &self,
+        announce_url: &str,
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponse, Error> {
+        if announce_url.starts_with(UDP_TRACKER_URL_PREFIX) {
+            let mut peer_id = [0; 20];
+            peer_id.copy_from_slice(self.peer_id.as_bytes());
+            let request = UdpAnnounceRequest {
+                info_hash: params.info_hash,
+                peer_id,
+                downloaded: params.downloaded,
+                left: params.left,
+                uploaded: params.uploaded,
+                event: params.event,
+                key: self.key,
+                num_want: -1,
+                port: self.port,
+            };
+            // error responses of UDP trackers are failure reasons just like in HTTP responses
+            return match self.udp_tracker.announce(announce_url, &request).await {
+                Err(udp_tracker::Error::Tracker(reason)) => {
+                    Err(tracker::Error::TrackerFailure(reason).into())
+                }
+                result => Ok(result?),
+            };
+        }
+        self.bounded(self.announce_http(announce_url, params)).await
+    }
+
+    async fn announce_http(
+        &self,
+        announce_url: &str,
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponse, Error> {
+        let url = TrackerUrl::new(announce_url.to_string(), self.peer_id.clone())
+            .with_compact(true)
+            .with_port(self.port)
+            .with_info_hash(params.info_hash)
+            .with_bytes_uploaded(params.uploaded as usize)
+            .with_bytes_downloaded(params.downloaded as usize)
+            .with_left_bytes(params.left as usize)
+            .with_event(params.event)
+            .with_tracker_id(params.tracker_id.clone())
+            .with_ipv4(self.ipv4)
+            .with_ipv6(self.ipv6)
+            .to_string();
+        debug!("Announce URL: {:?}", url);
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .map_err(Error::HttpClient)?;
+        let response_body = response.bytes().await.map_err(Error::HttpClient)?;
+        let resp = AnnounceResponse::from_bytes(response_body.as_ref())?;
+
+        Ok(resp)
+    }
+
+    /// Scrapes statistics of `info_hashes` from a single tracker. UDP trackers are scraped at their
+    /// announce address in batches of [`udp_tracker::MAX_SCRAPE_INFO_HASHES`], for HTTP trackers
+    /// the scrape URL is derived from the announce URL.
+    pub async fn scrape(
+        &self,
+        announce_url: &str,
+        info_hashes: &[Sha1HashBytes],
+    ) -> Result<HashMap<Sha1HashBytes, ScrapeStats>, Error> {
+        if announce_url.starts_with(UDP_TRACKER_URL_PREFIX) {
+            // a UDP request only fits so many info hashes, larger scrapes are split
+            let mut stats = HashMap::new();
+            for batch in info_hashes.chunks(udp_tracker::MAX_SCRAPE_INFO_HASHES) {
+                let batch_stats = match self.udp_tracker.scrape(announce_url, batch).await {
+                    Err(udp_tracker::Error::Tracker(reason)) => {
+                        return Err(tracker::Error::TrackerFailure(reason).into())
+                    }
+                    result => result?,
+                };
+                stats.extend(batch.iter().copied().zip(batch_stats));
+            }
+            return Ok(stats);
+        }
+        self.bounded(self.scrape_http(announce_url, info_hashes))
+            .await
+    }
+
+    async fn scrape_http(
+        &self,
+        announce_url: &str,
+        info_hashes: &[Sha1HashBytes],
+    ) -> Result<HashMap<Sha1HashBytes, ScrapeStats>, Error> {
+        let url = scrape_request_url(&scrape_url(announce_url)?, info_hashes);
+        debug!("Scrape URL: {:?}", url);
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .map_err(Error::HttpClient)?;
+        let response_body = response.bytes().await.map_err(Error::HttpClient)?;
+        Ok(ScrapeResponse::from_bytes(response_body.as_ref())?.files())
+    }
+
+    /// Announces to the trackers tier by tier until one of them responds (https://www.bittorrent.org/beps/bep_0012.html).
+    /// The responsive tracker is moved to the front of its tier, so it is tried first next time.
+    pub async fn announce_to_trackers(
+        &self,
+        trackers: &mut TrackerTiers,
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponse, Error> {
+        let mut last_error = Error::NoTrackers;
+        for url in trackers.urls() {
+            match self.announce(&url, params).await {
+                Ok(response) => {
+                    if let Some(message) = response.warning_message() {
+                        warn!("Tracker {0} warning: {1}", url, message);
+                        self.report(TrackerEvent::Warning {
+                            url: url.clone(),
+                            message: message.to_string(),
+                        });
+                    }
+                    trackers.promote(&url);
+                    return Ok(response);
+                }
+                Err(Error::Tracker(tracker::Error::TrackerFailure(reason))) => {
+                    warn!("Tracker {0} failure: {1}", url, reason);
+                    self.report(TrackerEvent::Failure {
+                        url: url.clone(),
+                        reason: reason.clone(),
+                    });
+                    last_error = Error::Tracker(tracker::Error::TrackerFailure(reason));
+                }
+                Err(error) => {
+                    debug!("Tracker {0} error: {1:?}", url, error);
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Announces a torrent to its trackers for the whole lifetime of a download
+/// (https://wiki.theory.org/BitTorrentSpecification#Tracker_Request_Parameters).
+/// `started` is sent first, then regular announces follow based on the tracker's `interval`,
+/// `completed` is sent once the download finished and `stopped` when the session is stopped.
+pub struct TrackerSession {
+    events: mpsc::Sender<AnnounceEvent>,
+    handle: JoinHandle<()>,
+}
+
+/// State of the background announce task.
+struct SessionState {
+    announcer: Announcer,
+    trackers: TrackerTiers,
+    info_hash: Sha1HashBytes,
+    stats: Arc<TransferStats>,
+    tracker_id: Option<String>,
+    /// Lower bound of the time between regular announces.
+    min_interval: Duration,
+}
+
+impl SessionState {
+    async fn announce(&mut self, event: AnnounceEvent) -> Result<AnnounceResponse, Error> {
+        let params = AnnounceParams {
+            info_hash: self.info_hash,
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            event,
+            tracker_id: self.tracker_id.clone(),
+        };
+        let response = self
+            .announcer
+            .announce_to_trackers(&mut self.trackers, &params)
+            .await?;
+        if let Some(tracker_id) = response.tracker_id() {
+            self.tracker_id = Some(tracker_id.to_string());
+        }
+        Ok(response)
+    }
+
+    /// Time to wait until the next regular announce, `min interval` is a lower bound of `interval`.
+    /// Trackers asking for shorter intervals than [`Self::min_interval`] are announced to less often.
+    fn next_announce_interval(&self, response: &AnnounceResponse) -> Duration {
+        let interval = response
+            .interval()
+            .max(response.min_interval().unwrap_or(0));
+        Duration::from_secs(interval as u64).max(self.min_interval)
+    }
+}
+
+impl TrackerSession {
+    /// Sends `started` to the first responsive tracker and keeps announcing in the background.
+    /// Returns the session and the peers of the first response, peers of later responses are sent to `peers`.
+    pub async fn start(
+        announcer: Announcer,
+        trackers: TrackerTiers,
+        info_hash: Sha1HashBytes,
+        stats: Arc<TransferStats>,
+        peers: mpsc::Sender<Vec<PeerAddress>>,
+    ) -> Result<(Self, Vec<PeerAddress>), Error> {
+        let state = SessionState {
+            announcer,
+            trackers,
+            info_hash,
+            stats,
+            tracker_id: None,
+            min_interval: MIN_ANNOUNCE_INTERVAL,
+        };
+        Self::start_session(state, peers).await
+    }
+
+    async fn start_session(
+        mut state: SessionState,
+        peers: mpsc::Sender<Vec<PeerAddress>>,
+    ) -> Result<(Self, Vec<PeerAddress>), Error> {
+        let response = state.announce(AnnounceEvent::Started).await?;
+        let initial_peers = response.peers()?;
+        let (events, events_rx) = mpsc::channel(4);
+        let wait = state.next_announce_interval(&response);
+        let handle = tokio::spawn(Self::run(state, wait, events_rx, peers));
+        Ok((Self { events, handle }, initial_peers))
+    }
+
+    async fn run(
+        mut state: SessionState,
+        mut wait: Duration,
+        mut events: mpsc::Receiver<AnnounceEvent>,
+        peers: mpsc::Sender<Vec<PeerAddress>>,
+    ) {
+        loop {
+            let event = tokio::select! {
+                _ = tokio::time::sleep(wait) => AnnounceEvent::None,
+                event = events.recv() => event.unwrap_or(AnnounceEvent::Stopped),
+            };
+            let result = state.announce(event).await;
+            if event == AnnounceEvent::Stopped {
+                if let Err(error) = result {
+                    debug!("Failed to announce stopped event: {:?}", error);
+                }
+                return;
+            }
+            match result.and_then(|response| {
+                Ok((state.next_announce_interval(&response), response.peers()?))
+            }) {
+                Ok((interval, new_peers)) => {
+                    wait = interval;
+                    debug!("{0} peers received from tracker", new_peers.len());
+                    // nobody may be waiting for peers anymore (e.g. download finished), never block on it
+                    let _ = peers.try_send(new_peers);
+                }
+                Err(error) => {
+                    debug!("Announce error: {:?}", error);
+                    wait = ANNOUNCE_RETRY_INTERVAL;
+                }
+            }
+        }
+    }
+
+    /// Announces `completed` to the trackers.
+    pub async fn completed(&self) {
+        let _ = self.events.send(AnnounceEvent::Completed).await;
+    }
+
+    /// Announces `stopped` to the trackers and waits for the session to finish.
+    pub async fn stop(self) {
+        let _ = self.events.send(AnnounceEvent::Stopped).await;
+        let _ = self.handle.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{start_http_tracker, QueryParams};
+    use bytes::{Buf, BufMut, Bytes, BytesMut};
+    use std::sync::Mutex;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    /// Local stand-in HTTP tracker answering all requests with `body`.
+    async fn start_tracker(body: &[u8]) -> (String, Arc<Mutex<Vec<QueryParams>>>) {
+        let body = body.to_vec();
+        start_http_tracker(move |_| body.clone()).await
+    }
+
+    #[tokio::test]
+    async fn announce_lifecycle_events() {
+        let (url, requests) =
+            start_tracker(b"d8:intervali1e5:peers6:\x0a\x00\x00\x01\x1a\xe110:tracker id3:abce")
+                .await;
+        let stats = Arc::new(TransferStats::new(1000));
+        let (peers_tx, mut peers_rx) = mpsc::channel(4);
+        // no lower bound, so the test doesn't wait a minute for the regular announce
+        let state = SessionState {
+            announcer: Announcer::new("-RT0100-abcdefghijkl".to_string()),
+            trackers: TrackerTiers::new(vec![vec!["http://127.0.0.1:1/announce".to_string(), url]]),
+            info_hash: [1; 20],
+            stats: stats.clone(),
+            tracker_id: None,
+            min_interval: Duration::ZERO,
+        };
+        let (session, peers) = TrackerSession::start_session(state, peers_tx)
+            .await
+            .unwrap();
+        assert_eq!(peers.len(), 1);
+
+        // regular announce after the tracker's interval reports the current stats
+        stats.add_downloaded(600);
+        assert_eq!(peers_rx.recv().await.unwrap().len(), 1);
+        stats.add_downloaded(400);
+        session.completed().await;
+        session.stop().await;
+
+        let requests = requests.lock().unwrap();
+        let values = |key: &str| -> Vec<Option<String>> {
+            requests
+                .iter()
+                .map(|params| params.get(key).cloned())
+                .collect()
+        };
+        assert_eq!(
+            values("event"),
+            vec![
+                Some("started".to_string()),
+                None,
+                Some("completed".to_string()),
+                Some("stopped".to_string())
+            ]
+        );
+        assert_eq!(
+            values("left"),
+            vec![
+                Some("1000".to_string()),
+                Some("400".to_string()),
+                Some("0".to_string()),
+                Some("0".to_string())
+            ]
+        );
+        assert_eq!(values("trackerid")[0], None);
+        assert!(values("trackerid")[1..]
+            .iter()
+            .all(|tracker_id| tracker_id.as_deref() == Some("abc")));
+    }
+
+    #[test]
+    fn clamp_short_announce_intervals() {
+        let state = SessionState {
+            announcer: Announcer::new("-RT0100-abcdefghijkl".to_string()),
+            trackers: TrackerTiers::new(vec![]),
+            info_hash: [1; 20],
+            stats: Arc::new(TransferStats::new(0)),
+            tracker_id: None,
+            min_interval: MIN_ANNOUNCE_INTERVAL,
+        };
+        let interval = |body: &[u8]| {
+            state.next_announce_interval(&AnnounceResponse::from_bytes(body).unwrap())
+        };
+        assert_eq!(interval(b"d8:intervali0e5:peers0:e"), MIN_ANNOUNCE_INTERVAL);
+        assert_eq!(interval(b"d8:intervali1e5:peers0:e"), MIN_ANNOUNCE_INTERVAL);
+        assert_eq!(
+            interval(b"d8:intervali900e12:min intervali1800e5:peers0:e"),
+            Duration::from_secs(1800)
+        );
+    }
+
+    #[tokio::test]
+    async fn surface_tracker_failures_and_warnings() {
+        let (failing, _) = start_tracker(b"d14:failure reason17:torrent not founde").await;
+        let (warning, _) =
+            start_tracker(b"d8:intervali900e5:peers0:15:warning message9:slow downe").await;
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let announcer = Announcer::new("-RT0100-abcdefghijkl".to_string()).with_events(events_tx);
+        let params = AnnounceParams::default();
+
+        let mut trackers = TrackerTiers::new(vec![vec![failing.clone()]]);
+        match announcer.announce_to_trackers(&mut trackers, &params).await {
+            Err(Error::Tracker(tracker::Error::TrackerFailure(reason))) => {
+                assert_eq!(reason, "torrent not found")
+            }
+            result => panic!("unexpected result: {result:?}"),
+        }
+        assert_eq!(
+            events_rx.recv().await.unwrap(),
+            TrackerEvent::Failure {
+                url: failing.clone(),
+                reason: "torrent not found".to_string()
+            }
+        );
+
+        let mut trackers = TrackerTiers::new(vec![vec![failing.clone()], vec![warning.clone()]]);
+        let response = announcer
+            .announce_to_trackers(&mut trackers, &params)
+            .await
+            .unwrap();
+        assert_eq!(response.interval(), 900);
+        assert!(matches!(
+            events_rx.recv().await.unwrap(),
+            TrackerEvent::Failure { .. }
+        ));
+        assert_eq!(
+            events_rx.recv().await.unwrap(),
+            TrackerEvent::Warning {
+                url: warning,
+                message: "slow down".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_unresponsive_trackers() {
+        // accepts connections, but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hanging = format!("http://{0}/announce", listener.local_addr().unwrap());
+        let (responsive, _) = start_tracker(b"d8:intervali900e5:peers0:e").await;
+        let announcer = Announcer::new("-RT0100-abcdefghijkl".to_string())
+            .with_timeout(Duration::from_millis(200));
+        let params = AnnounceParams::default();
+
+        assert!(matches!(
+            announcer.announce(&hanging, &params).await,
+            Err(Error::TrackerTimeout(_))
+        ));
+        let mut trackers = TrackerTiers::new(vec![vec![hanging], vec![responsive]]);
+        let response = announcer
+            .announce_to_trackers(&mut trackers, &params)
+            .await
+            .unwrap();
+        assert_eq!(response.interval(), 900);
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn scrape_http_tracker() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[1; 20]);
+        body.extend_from_slice(b"d8:completei3e10:downloadedi7e10:incompletei2eeee");
+        let (url, requests) = start_tracker(&body).await;
+        let announcer = Announcer::new("-RT0100-abcdefghijkl".to_string());
+        let stats = announcer.scrape(&url, &[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                [1; 20],
+                ScrapeStats {
+                    complete: 3,
+                    downloaded: 7,
+                    incomplete: 2
+                }
+            )])
+        );
+        assert!(requests.lock().unwrap()[0].contains_key("info_hash"));
+
+        assert!(matches!(
+            announcer
+                .scrape("http://127.0.0.1:1/tracker", &[[1; 20]])
+                .await,
+            Err(Error::Tracker(tracker::Error::ScrapeNotSupported(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scrape_udp_tracker_in_batches() {
+        // answers each scrape with the first byte of each info hash as its number of seeders
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{0}/announce", socket.local_addr().unwrap());
+        let scrapes = Arc::new(AtomicU64::new(0));
+        let counted = scrapes.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (size, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut request = Bytes::copy_from_slice(&buf[..size]);
+                request.advance(8);
+                let action = request.get_u32();
+                let transaction_id = request.get_u32();
+                let mut response = BytesMut::new();
+                response.put_u32(action);
+                response.put_u32(transaction_id);
+                if action == 0 {
+                    response.put_u64(1);
+                } else {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    while request.remaining() >= 20 {
+                        response.put_u32(request.copy_to_bytes(20)[0] as u32);
+                        response.put_u64(0);
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        let info_hashes: Vec<Sha1HashBytes> = (0..100).map(|index| [index; 20]).collect();
+        let announcer = Announcer::new("-RT0100-abcdefghijkl".to_string());
+        let stats = announcer.scrape(&url, &info_hashes).await.unwrap();
+        assert_eq!(stats.len(), 100);
+        assert!(info_hashes
+            .iter()
+            .all(|info_hash| stats[info_hash].complete == info_hash[0] as usize));
+        assert_eq!(scrapes.load(Ordering::SeqCst), 2);
+    }
+}
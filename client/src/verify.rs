@@ -0,0 +1,215 @@
+use crate::client::Error;
+use crate::protocol::meta_info_file::{Sha1HashBytes, TorrentFile};
+use crate::storage::read_exact_at;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io;
+
+/// Outcome of verifying a single file of a torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// All pieces of the file match their hashes.
+    Valid,
+    /// Some pieces of the file don't match their hashes. Files sharing such a piece with a corrupt
+    /// (or missing) file are corrupt as well, their part of the piece can't be verified on its own.
+    Corrupt,
+    /// The file doesn't exist.
+    Missing,
+    /// The file's size differs from the torrent, the pieces inside it are verified nonetheless.
+    WrongSize(u64),
+}
+
+/// Verification result of a single file, see [`VerificationReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileVerification {
+    /// Where the file was expected.
+    pub path: PathBuf,
+    pub status: FileStatus,
+}
+
+/// Result of [`verify`]: which pieces and files of a torrent match their hashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationReport {
+    /// Whether the piece with each index matches its hash.
+    pub pieces: Vec<bool>,
+    /// The files in the order of [`TorrentFile::files`].
+    pub files: Vec<FileVerification>,
+}
+
+impl VerificationReport {
+    /// Whether all data of the torrent is present and valid.
+    pub fn is_valid(&self) -> bool {
+        self.files
+            .iter()
+            .all(|file| file.status == FileStatus::Valid)
+    }
+
+    /// Number of pieces matching their hashes.
+    pub fn valid_pieces(&self) -> usize {
+        self.pieces.iter().filter(|valid| **valid).count()
+    }
+}
+
+/// Checks the data of `torrent` at `path` against its piece hashes without any networking,
+/// e.g. before seeding or in CI. See [`crate::BitTorrentClient::download`] for the meaning of `path`.
+/// Pieces are read and hashed on all available cores parallel, nothing is written. Blocks the calling
+/// thread until all pieces are verified, use `tokio::task::spawn_blocking` in async code.
+pub fn verify(torrent: &TorrentFile, path: &Path) -> Result<VerificationReport, Error> {
+    let paths = torrent.file_paths(path);
+    let mut files = Vec::with_capacity(paths.len());
+    let mut statuses = Vec::with_capacity(paths.len());
+    for (path, entry) in paths.iter().zip(&torrent.files) {
+        match File::open(path) {
+            Ok(file) => {
+                let length = file.metadata()?.len();
+                statuses.push(if length == entry.length as u64 {
+                    FileStatus::Valid
+                } else {
+                    FileStatus::WrongSize(length)
+                });
+                files.push(Some(file));
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                statuses.push(FileStatus::Missing);
+                files.push(None);
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let pieces: Vec<bool> = hash_pieces(torrent, &files)?
+        .iter()
+        .zip(&torrent.piece_hashes)
+        .map(|(hash, expected)| hash.as_ref() == Some(expected))
+        .collect();
+    for index in (0..pieces.len()).filter(|index| !pieces[*index]) {
+        for segment in torrent.piece_segments(index) {
+            let status = &mut statuses[segment.file_index];
+            if *status == FileStatus::Valid {
+                *status = FileStatus::Corrupt;
+            }
+        }
+    }
+    Ok(VerificationReport {
+        pieces,
+        files: paths
+            .into_iter()
+            .zip(statuses)
+            .map(|(path, status)| FileVerification { path, status })
+            .collect(),
+    })
+}
+
+/// Hashes the pieces of `torrent` stored in `files` on one thread per core, each thread takes the next
+/// piece nobody took yet. Pieces in missing (`None`) or too short files have no hash.
+pub(crate) fn hash_pieces(
+    torrent: &TorrentFile,
+    files: &[Option<File>],
+) -> Result<Vec<Option<Sha1HashBytes>>, Error> {
+    let pieces_count = torrent.pieces_count();
+    let threads = std::thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .clamp(1, pieces_count.max(1));
+    let next = AtomicUsize::new(0);
+    let results = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    let mut data = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= pieces_count {
+                            return Ok::<_, io::Error>(results);
+                        }
+                        let hash = read_piece(torrent, files, index, &mut data)?
+                            .then(|| Sha1::digest(&data).into());
+                        results.push((index, hash));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("piece hashing thread panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    let mut hashes = vec![None; pieces_count];
+    for (index, hash) in results.into_iter().flatten() {
+        hashes[index] = hash;
+    }
+    Ok(hashes)
+}
+
+/// Reads the piece `index` into `data`, returns `false` if some of its files are missing or too short.
+fn read_piece(
+    torrent: &TorrentFile,
+    files: &[Option<File>],
+    index: usize,
+    data: &mut Vec<u8>,
+) -> io::Result<bool> {
+    data.resize(torrent.piece_size(index), 0);
+    let mut position = 0;
+    for segment in torrent.piece_segments(index) {
+        let Some(file) = &files[segment.file_index] else {
+            return Ok(false);
+        };
+        let chunk = &mut data[position..position + segment.length];
+        match read_exact_at(file, chunk, segment.offset as u64) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(error) => return Err(error),
+        }
+        position += segment.length;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{multi_file_torrent, TempDir, MULTI_FILE_DATA as DATA};
+
+    #[test]
+    fn report_pieces_and_files() {
+        let torrent = multi_file_torrent();
+        let dir = TempDir::new("verify");
+        let root = dir.path();
+        std::fs::write(root.join("a"), &DATA[..5]).unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+        std::fs::write(root.join("b"), &DATA[5..]).unwrap();
+        let report = verify(&torrent, root).unwrap();
+        assert!(report.is_valid());
+        assert_eq!(report.valid_pieces(), 3);
+
+        // the first piece spans `a` and `b`, so both are corrupt
+        std::fs::write(root.join("a"), b"aaaa!").unwrap();
+        let report = verify(&torrent, root).unwrap();
+        assert_eq!(report.pieces, vec![false, true, true]);
+        let statuses: Vec<_> = report.files.iter().map(|file| file.status).collect();
+        assert_eq!(
+            statuses,
+            vec![FileStatus::Corrupt, FileStatus::Valid, FileStatus::Corrupt]
+        );
+        assert_eq!(report.files[2].path, root.join("b"));
+
+        std::fs::remove_file(root.join("a")).unwrap();
+        std::fs::write(root.join("b"), &DATA[5..20]).unwrap();
+        let report = verify(&torrent, root).unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.pieces, vec![false, true, false]);
+        let statuses: Vec<_> = report.files.iter().map(|file| file.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                FileStatus::Missing,
+                FileStatus::Valid,
+                FileStatus::WrongSize(15)
+            ]
+        );
+    }
+}
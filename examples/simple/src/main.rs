@@ -1,13 +1,54 @@
-use client::{BitTorrentClient, Error};
+use client::protocol::meta_info_file;
+use client::{verify, BitTorrentClient, Error, FileStatus, PeerListener};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     env_logger::init();
-    let args = std::env::args();
-    if args.len() <= 1 {
-        panic!("Please provide a torrent file as first argument!");
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() <= 2 {
+        panic!(
+            "Please provide a torrent file as first and an output file path as second argument!"
+        );
+    }
+    if args[1] == "verify" {
+        // checks existing data without any networking: `simple verify <torrent> <path>`
+        let Some(data_path) = args.get(3) else {
+            panic!("Please provide a torrent file and the path of its data to verify!");
+        };
+        let torrent = meta_info_file::parse(args[2].as_str()).await?;
+        let report = verify(&torrent, Path::new(data_path))?;
+        for file in &report.files {
+            match file.status {
+                FileStatus::Valid => println!("OK        {0}", file.path.display()),
+                FileStatus::Corrupt => println!("CORRUPT   {0}", file.path.display()),
+                FileStatus::Missing => println!("MISSING   {0}", file.path.display()),
+                FileStatus::WrongSize(length) => {
+                    println!("SIZE {1:<4} {0}", file.path.display(), length)
+                }
+            }
+        }
+        println!(
+            "{0}/{1} pieces valid",
+            report.valid_pieces(),
+            report.pieces.len()
+        );
+        if !report.is_valid() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.get(3).map(String::as_str) == Some("--seed") {
+        // keep uploading after the download until interrupted
+        let listener = PeerListener::bind(SocketAddr::from(([0, 0, 0, 0], 6881))).await?;
+        let client = BitTorrentClient::new().with_listener(Arc::new(listener));
+        let stop = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+        return client.seed(args[1].as_str(), args[2].as_str(), stop).await;
     }
-    let torrent_file = args.last().unwrap();
     let client = BitTorrentClient::new();
-    client.download(torrent_file.as_str()).await
+    client.download(args[1].as_str(), args[2].as_str()).await
 }